@@ -1,43 +1,117 @@
-use crate::signals::{signal_daemon, Message};
-use crate::units::{reload_server, ALL_UNITS};
+use crate::events::{self, Event};
+use crate::jobs::{self, JobInfo};
+use crate::logs::{self, LogEntry, LogFilter};
+use crate::policy::{self, Peer};
+use crate::router::{
+    error_response, json_response, percent_decode, split_path, Params, Router,
+};
+use crate::signals::{signal_daemon, Message, SystemAction};
+use crate::unitfile::parse_signal;
+use crate::units::ALL_UNITS;
 /// Module that includes all handler functions for the HTTP API.
-use hyper::header::CONTENT_TYPE;
-use hyper::StatusCode;
-use hyper::{Body, Method, Request, Response};
-use serde_json;
-
-/// Router service that routes requests to appropriate handler method based on
-/// the regex.
-///
-/// /             -> Returns just a string.
-/// /units        -> Returns a list of units installed.
-/// /units/<unit> -> Return the details of the specific unit.
-// pub fn router_service() -> Result<RouterService, std::io::Error> {
-//     let router = RouterBuilder::new()
-//         .add(Route::get("/").using(root))
-//         .add(Route::post("/shutdown").using(shutdown))
-//         .add(Route::post("/reload").using(reload))
-//         .add(Route::get("/units").using(get_all_units))
-//         .add(Route::post(r"/unit/.*?/start").using(start_service))
-//         .add(Route::post(r"/unit/.*?/stop").using(stop_service))
-//         .add(Route::get(r"/unit/.*?").using(get_a_unit))
-//         .build();
-
-//     Ok(RouterService::new(router))
-// }
-
-pub async fn router(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/") => Ok(root(req)),
-        (&Method::POST, "/shutdown") => Ok(shutdown(req)),
-        (&Method::POST, "/reload") => Ok(reload(req)),
-        (&Method::GET, "/units") => Ok(get_all_units(req)),
-        _ => Ok(root(req)),
+use hyper::body::Bytes;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use nix::sys::signal::Signal;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+/// How often a comment is sent on an idle event stream, which also finds out
+/// when the client went away.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+lazy_static! {
+    /// Routes of the API.
+    ///
+    /// /                        -> Returns just a string.
+    /// /units                   -> Returns a list of units installed.
+    /// /units/<unit>            -> Return the details of the specific unit.
+    /// /units/<unit>/<action>   -> Start, stop, restart, reload or kill the unit,
+    ///                             or set its properties.
+    /// /jobs                    -> Returns the jobs that are still remembered.
+    /// /jobs/<id>               -> Return the state and result of the job.
+    /// /events                  -> Stream of what happens to units and jobs.
+    /// /logs                    -> Output of the units, one JSON entry per line.
+    static ref ROUTER: Router = Router::new()
+        .get("/", root)
+        .post("/shutdown", shutdown)
+        .post("/poweroff", |req, _| system_shutdown(req, SystemAction::PowerOff))
+        .post("/reboot", |req, _| system_shutdown(req, SystemAction::Reboot))
+        .post("/halt", |req, _| system_shutdown(req, SystemAction::Halt))
+        .post("/reload", reload)
+        .get("/units", get_all_units)
+        .get("/units/:name", get_a_unit)
+        .post("/units/:name/start", start_service)
+        .post("/units/:name/stop", stop_service)
+        .post("/units/:name/restart", restart_service)
+        .post("/units/:name/reload", reload_service)
+        .post("/units/:name/kill", kill_service)
+        .post("/units/:name/set-property", set_property)
+        .get("/jobs", get_all_jobs)
+        .get("/jobs/:id", get_a_job)
+        .get("/events", get_events)
+        .get("/logs", get_logs);
+}
+
+/// Handle a request from peer, which is None when the client has no
+/// credentials, like over TCP.
+pub async fn router(
+    req: Request<Body>,
+    peer: Option<Peer>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Some(reason) = forbidden(&req, peer) {
+        return Ok(error_response(StatusCode::FORBIDDEN, &reason));
     }
+    Ok(ROUTER.route(req).await)
+}
+
+/// Why peer may not make the request, if it may not. Anybody may look at the
+/// units and jobs. Reading the output of units, which the log store keeps from
+/// other users, following events and changing anything takes root or a rule
+/// in the policy file allowing it, as the logs or events action.
+fn forbidden(req: &Request<Body>, peer: Option<Peer>) -> Option<String> {
+    let path = split_path(req.uri().path());
+    let logs_unit;
+    let (unit, action) = if req.method() == Method::GET || req.method() == Method::HEAD {
+        match path.as_slice() {
+            [logs] if logs == "logs" => {
+                logs_unit = query_param(req, "unit");
+                (logs_unit.as_deref(), logs)
+            }
+            [events] if events == "events" => (None, events),
+            _ => return None,
+        }
+    } else {
+        match path.as_slice() {
+            [units, unit, action] if units == "units" => (Some(unit.as_str()), action),
+            [action] => (None, action),
+            _ => (None, &path.join("/")),
+        }
+    };
+    if policy::current().allows(peer, unit, action) {
+        return None;
+    }
+    let who = match peer {
+        Some(peer) => format!("UID {}", peer.uid),
+        None => "Clients without credentials".to_string(),
+    };
+    let action = match action.as_str() {
+        "logs" => "read the logs of",
+        "events" => "follow events",
+        action => action,
+    };
+    Some(match unit {
+        Some(unit) => format!("{} may not {} {}", who, action, unit),
+        None if action == "read the logs of" => format!("{} may not read logs", who),
+        None => format!("{} may not {}", who, action),
+    })
 }
 
 /// Handle: /
-fn root(_: Request<Body>) -> Response<Body> {
+async fn root(_: Request<Body>, _: Params) -> Response<Body> {
     let body = "Try GET to /units";
     Response::builder()
         .header(CONTENT_TYPE, "text/plain")
@@ -46,17 +120,17 @@ fn root(_: Request<Body>) -> Response<Body> {
 }
 
 /// Handle: /shutdown
-fn shutdown(_: Request<Body>) -> Response<Body> {
-    signal_daemon(Message::Shutdown);
+async fn shutdown(req: Request<Body>, _: Params) -> Response<Body> {
+    submit(&req, Message::Shutdown).await
+}
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::from(""))
-        .expect("Failed to construct the response")
+/// Handle: /poweroff, /reboot and /halt
+async fn system_shutdown(req: Request<Body>, action: SystemAction) -> Response<Body> {
+    submit(&req, Message::SystemShutdown { action, immediate: false }).await
 }
 
 /// Handle: /units
-fn get_all_units(_: Request<Body>) -> Response<Body> {
+async fn get_all_units(_: Request<Body>, _: Params) -> Response<Body> {
     Response::builder()
         .header(CONTENT_TYPE, "application/json")
         .body(Body::from(ALL_UNITS.lock().unwrap().to_string()))
@@ -64,60 +138,334 @@ fn get_all_units(_: Request<Body>) -> Response<Body> {
 }
 
 /// Handle: /units/example.service
-fn get_a_unit(req: Request<Body>) -> Response<Body> {
-    let mut response = Response::new(Body::empty());
-    let path = req.uri().path();
-    let service = path.split("/").collect::<Vec<&str>>()[2];
+async fn get_a_unit(_: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    match ALL_UNITS.lock().unwrap().get_by_name(name) {
+        Some(unit) => json_response(StatusCode::OK, unit),
+        None => unknown_unit(name),
+    }
+}
 
-    if let Some(unit) = ALL_UNITS.lock().unwrap().get_by_name(service) {
-        *response.body_mut() = Body::from(serde_json::to_string(&unit).unwrap());
-    } else {
-        // Nothing found with that name.
-        *response.status_mut() = StatusCode::NOT_FOUND;
+/// Handle: /reload
+async fn reload(req: Request<Body>, _: Params) -> Response<Body> {
+    submit(&req, Message::ReloadUnits).await
+}
+
+fn unknown_unit(name: &str) -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, &format!("Unknown unit {}", name))
+}
+
+/// Hand the message over to the daemon as a job. With `?wait=true` the job is
+/// sent once it finished, otherwise right away along with where to find out
+/// how it went.
+async fn submit(req: &Request<Body>, message: Message) -> Response<Body> {
+    let (id, finished) = signal_daemon(message);
+    if query_param(req, "wait").as_deref() == Some("true") {
+        let job = match finished.await {
+            Ok(job) => job,
+            // The job was dropped without finishing, report it as it is.
+            Err(_) => match jobs::get(id) {
+                Some(job) => job,
+                None => return unknown_job(id),
+            },
+        };
+        return json_response(StatusCode::OK, &job);
     }
 
+    let job: Option<JobInfo> = jobs::get(id);
+    let mut response = json_response(StatusCode::ACCEPTED, &job);
+    response.headers_mut().insert(LOCATION, format!("/jobs/{}", id).parse().unwrap());
     response
 }
 
-// TODO: Implement this.
-/// Handle: /reload
-fn reload(_: Request<Body>) -> Response<Body> {
-    reload_server();
-    Response::new(Body::empty())
+/// Hand the action over to the daemon if the unit exists.
+async fn queue(req: &Request<Body>, name: &str, message: Message) -> Response<Body> {
+    if ALL_UNITS.lock().unwrap().get_by_name(name).is_none() {
+        return unknown_unit(name);
+    }
+    submit(req, message).await
 }
 
-/// Handle: /unit/example.service/start
-fn start_service(req: Request<Body>) -> Response<Body> {
-    let mut response = Response::new(Body::empty());
-    let path = req.uri().path();
-    let service = path.split("/").collect::<Vec<&str>>()[2];
+/// Handle: /units/example.service/start
+async fn start_service(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    queue(&req, name, Message::Start(name.to_string())).await
+}
 
-    if let Some(_) = ALL_UNITS.lock().unwrap().get_by_name(service) {
-        signal_daemon(Message::Start(service.to_string()));
-        *response.body_mut() = Body::from("OK");
-    } else {
-        // Nothing found with that name.
-        *response.status_mut() = StatusCode::NOT_FOUND;
-        *response.body_mut() = Body::from(format!("Unknown service {}", service));
+/// Handle: /units/example.service/stop
+async fn stop_service(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    queue(&req, name, Message::Stop(name.to_string())).await
+}
+
+/// Handle: /units/example.service/restart
+async fn restart_service(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    queue(&req, name, Message::Restart(name.to_string())).await
+}
+
+/// Handle: /units/example.service/reload
+async fn reload_service(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    let reloadable = ALL_UNITS
+        .lock()
+        .unwrap()
+        .get_by_name(name)
+        .map(|unit| unit.service.lock().unwrap().exec_reload.is_some());
+    if reloadable == Some(false) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("{} does not support reloading, it has no ExecReload=", name),
+        );
     }
+    queue(&req, name, Message::Reload(name.to_string())).await
+}
 
-    response
+/// Handle: /units/example.service/kill?signal=SIGHUP, the signal defaults to
+/// SIGTERM.
+async fn kill_service(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    let signal = match query_param(&req, "signal") {
+        Some(signal) => match parse_signal(&signal) {
+            Some(signal) => signal,
+            None => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Unknown signal {}", signal),
+                )
+            }
+        },
+        None => Signal::SIGTERM,
+    };
+    queue(&req, name, Message::Kill(name.to_string(), signal)).await
 }
 
-/// Handle: /unit/example.service/stop
-fn stop_service(req: Request<Body>) -> Response<Body> {
-    let mut response = Response::new(Body::empty());
-    let path = req.uri().path();
-    let service = path.split("/").collect::<Vec<&str>>()[2];
+/// Handle: /units/example.service/set-property?MemoryMax=1G&CPUQuota=20%,
+/// which changes resource control directives without restarting the unit.
+async fn set_property(req: Request<Body>, params: Params) -> Response<Body> {
+    let name = &params["name"];
+    let service = match ALL_UNITS.lock().unwrap().get_by_name(name) {
+        Some(unit) => unit.service.clone(),
+        None => return unknown_unit(name),
+    };
+    let properties = query_pairs(&req);
+    if properties.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "No properties to set");
+    }
+    let mut service = service.lock().unwrap();
+    match service.set_properties(&properties) {
+        Ok(()) => json_response(StatusCode::OK, &service.resources),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
 
-    if let Some(_) = ALL_UNITS.lock().unwrap().get_by_name(service) {
-        signal_daemon(Message::Stop(service.to_string()));
-        *response.body_mut() = Body::from("OK");
-    } else {
-        // Nothing found with that name.
-        *response.status_mut() = StatusCode::NOT_FOUND;
-        *response.body_mut() = Body::from(format!("Unknown service {}", service));
+#[derive(Serialize)]
+struct AllJobs {
+    jobs: Vec<JobInfo>,
+}
+
+/// Handle: /jobs
+async fn get_all_jobs(_: Request<Body>, _: Params) -> Response<Body> {
+    json_response(StatusCode::OK, &AllJobs { jobs: jobs::list() })
+}
+
+/// Handle: /jobs/42
+async fn get_a_job(_: Request<Body>, params: Params) -> Response<Body> {
+    let id = &params["id"];
+    match id.parse().ok().and_then(jobs::get) {
+        Some(job) => json_response(StatusCode::OK, &job),
+        None => error_response(StatusCode::NOT_FOUND, &format!("Unknown job {}", id)),
     }
+}
 
-    response
+fn unknown_job(id: jobs::JobId) -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, &format!("Unknown job {}", id))
+}
+
+/// Value of a parameter in the query string, decoded.
+fn query_param(req: &Request<Body>, key: &str) -> Option<String> {
+    query_pairs(req).into_iter().find(|(k, _)| k == key).map(|(_, value)| value)
+}
+
+/// All the parameters in the query string, decoded, in order.
+fn query_pairs(req: &Request<Body>) -> Vec<(String, String)> {
+    let query = match req.uri().query() {
+        Some(query) => query,
+        None => return vec![],
+    };
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((
+                    percent_decode(&key.replace('+', " ")),
+                    percent_decode(&value.replace('+', " ")),
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Handle: /events, as server-sent events. A client reconnecting with
+/// Last-Event-ID first gets the events it missed, as long as they are still
+/// in the backlog.
+async fn get_events(req: Request<Body>, _: Params) -> Response<Body> {
+    let after = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok());
+    let (missed, mut receiver) = events::subscribe(after);
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        for event in missed {
+            if sender.send_data(server_sent_event(&event)).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            let chunk = match timeout(KEEP_ALIVE, receiver.recv()).await {
+                Ok(Ok(event)) => server_sent_event(&event),
+                // The client fell behind, ending the stream has it reconnect
+                // and catch up from the backlog.
+                Ok(Err(_)) => return,
+                Err(_) => Bytes::from(": keep-alive\n\n"),
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .expect("Failed to construct the response")
+}
+
+fn server_sent_event(event: &Event) -> Bytes {
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.id,
+        event.kind.name(),
+        serde_json::to_string(event).unwrap()
+    ))
+}
+
+/// The filter for /logs from the query string.
+fn log_filter(req: &Request<Body>) -> Result<LogFilter, String> {
+    let time =
+        |key| query_param(req, key).map(|value| logs::parse_time(&value)).transpose();
+    Ok(LogFilter {
+        unit: query_param(req, "unit")
+            .map(|value| logs::parse_unit(&value))
+            .transpose()?,
+        since: time("since")?,
+        until: time("until")?,
+        priority: query_param(req, "priority")
+            .map(|value| logs::parse_priority(&value))
+            .transpose()?,
+        lines: query_param(req, "lines")
+            .map(|value| value.parse().map_err(|_| format!("invalid lines {:?}", value)))
+            .transpose()?,
+    })
+}
+
+/// Handle: /logs?unit=example.service&since=-1h&priority=err&lines=10, as
+/// JSON lines, oldest first. With follow=true new entries are sent as they
+/// come in, after the stored ones.
+async fn get_logs(req: Request<Body>, _: Params) -> Response<Body> {
+    let filter = match log_filter(&req) {
+        Ok(filter) => filter,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    let follow = query_param(&req, "follow").as_deref() == Some("true");
+    let (entries, mut live) = logs::query(&filter);
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        for entry in entries {
+            if sender.send_data(json_line(&entry)).await.is_err() {
+                return;
+            }
+        }
+        if !follow {
+            return;
+        }
+        loop {
+            let chunk = match timeout(KEEP_ALIVE, live.recv()).await {
+                Ok(Ok(entry)) if filter.matches(&entry) => json_line(&entry),
+                Ok(Ok(_)) => continue,
+                // Entries that were missed are in the store, but following
+                // along goes on with the ones coming in.
+                Ok(Err(broadcast::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::RecvError::Closed)) => return,
+                // An empty line, which also finds out when the client went
+                // away.
+                Err(_) => Bytes::from("\n"),
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(body)
+        .expect("Failed to construct the response")
+}
+
+fn json_line(entry: &LogEntry) -> Bytes {
+    let mut line = serde_json::to_string(entry).unwrap();
+    line.push('\n');
+    Bytes::from(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forbidden() {
+        let root = Some(Peer { uid: 0, gid: 0 });
+        let user = Some(Peer { uid: 1000, gid: 1000 });
+        // The method, the URI and whether root, a user without rules and a
+        // client without credentials may make the request.
+        let cases = [
+            ("GET", "/units", [true, true, true]),
+            ("GET", "/units/web.service", [true, true, true]),
+            ("GET", "/jobs/1", [true, true, true]),
+            ("GET", "/logs", [true, false, false]),
+            ("GET", "/logs?unit=web.service&lines=10", [true, false, false]),
+            ("HEAD", "/logs", [true, false, false]),
+            ("GET", "/events", [true, false, false]),
+            ("POST", "/units/web.service/start", [true, false, false]),
+            ("POST", "/reload", [true, false, false]),
+        ];
+        for (method, uri, allowed) in cases.iter() {
+            for (peer, allowed) in [root, user, None].iter().zip(allowed.iter()) {
+                let req =
+                    Request::builder().method(*method).uri(*uri).body(Body::empty());
+                let forbidden = forbidden(&req.unwrap(), *peer);
+                assert_eq!(
+                    forbidden.is_none(),
+                    *allowed,
+                    "{} {} by {:?}",
+                    method,
+                    uri,
+                    peer
+                );
+            }
+        }
+        let req = Request::get("/logs?unit=web.service").body(Body::empty()).unwrap();
+        assert_eq!(
+            forbidden(&req, user).unwrap(),
+            "UID 1000 may not read the logs of web.service"
+        );
+    }
 }
@@ -1,68 +1,106 @@
-use clap::{App, SubCommand};
-use hyper::{body::Bytes, Body, Client, Request, Uri};
+use clap::{App, Arg, SubCommand};
+use getup::capabilities::ProcessCapabilities;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HOST;
+use hyper::{client::conn, Body, Request, Response, StatusCode};
 use serde_json::Value;
+use std::env;
+use tokio::net::UnixStream;
 
-static BASE_URL: &'static str = "localhost:3000";
+/// Where getupd serves its API, unless GETUP_API_SOCKET says otherwise like it
+/// does for getupd.
+static SOCKET_PATH: &str = "/run/getup/private";
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-/// create a full url with scheme and host appending the Path and returning the
-/// Uri attribute.
-fn get_full_url(path: &str) -> Uri {
-    return Uri::builder()
-        .scheme("http")
-        .authority(BASE_URL)
-        .path_and_query(path)
-        .build()
-        .unwrap();
-}
-
 /// Call the getup API url from the provided url as the path and return the
 /// response as bytes.
 async fn get_request(url: &str) -> Result<Bytes> {
-    Ok(_request(url, "GET").await?)
+    _request(url, "GET").await
 }
 
 async fn _request(url: &str, method: &str) -> Result<Bytes> {
-    let full_url = get_full_url(url);
-    // Build a request object with the full URL.
+    let resp = send(url, method).await?;
+    let body = hyper::body::to_bytes(resp).await?;
+    Ok(body)
+}
+
+/// Send a request to getupd and return the response, whose body may still be
+/// coming in.
+async fn send(url: &str, method: &str) -> Result<Response<Body>> {
+    let path = env::var("GETUP_API_SOCKET").unwrap_or_else(|_| SOCKET_PATH.to_string());
+    let stream = UnixStream::connect(&path)
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", path, e))?;
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(connection);
+
     let req = Request::builder()
         .method(method)
-        .uri(full_url)
+        .uri(url)
+        .header(HOST, "localhost")
         .body(Body::from(""))
         .expect("Failed to build request");
-    let client = Client::new();
-    let resp = client.request(req).await?;
-    let body = hyper::body::to_bytes(resp).await?;
-    Ok(body)
+    Ok(sender.send_request(req).await?)
 }
 
 /// Call getupd API with a POST request.
 async fn post_request(url: &str) -> Result<Bytes> {
-    Ok(_request(url, "POST").await?)
+    _request(url, "POST").await
 }
 
 /// Call the getupd URL and return the JSON response.
 async fn get_json_response(url: &str) -> Result<Value> {
     let body = get_request(url).await?;
-    let json_body: Value = serde_json::from_slice(&body).expect("Failed to parse json body");
+    let json_body: Value =
+        serde_json::from_slice(&body).expect("Failed to parse json body");
     Ok(json_body)
 }
 
-async fn start_unit() {}
-
-async fn stop_unit() {}
-
-async fn get_unit_status() {}
+/// POST to url, printing why the daemon refused if it did.
+async fn post_and_report(url: &str) {
+    let body = match post_request(url).await {
+        Ok(body) => body,
+        Err(e) => return println!("{}", e),
+    };
+    let response: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        println!("{}", error);
+    }
+}
 
 /// Ask the getupd daemon to reload the unit files.
 async fn reload() {
-    let _ = post_request("/reload").await;
+    post_and_report("/reload?wait=true").await;
 }
 
 /// Ask the getupd daemon to gracefully shutdown.
 async fn shutdown() {
-    let _ = post_request("/shutdown").await;
+    post_and_report("/shutdown").await;
+}
+
+/// Ask the getupd daemon to change properties of a unit while it runs, given
+/// as `MemoryMax=1G`.
+async fn set_property(name: &str, properties: Vec<&str>) {
+    let mut query = vec![];
+    for property in properties {
+        match property.split_once('=') {
+            Some((key, value)) => query.push(format!("{}={}", key, url_encode(value))),
+            None => {
+                return println!(
+                    "Expected a property like MemoryMax=1G, got {}",
+                    property
+                )
+            }
+        }
+    }
+    post_and_report(&format!("/units/{}/set-property?{}", name, query.join("&"))).await;
+}
+
+/// Ask the getupd daemon to stop everything and power off, reboot or halt the
+/// machine.
+async fn system_shutdown(action: &str) {
+    post_and_report(&format!("/{}", action)).await;
 }
 
 /// Get all the units currently installed in the getupd daemon.
@@ -80,22 +118,273 @@ async fn get_all_units() -> Result<()> {
         }
     }
 
+    if let Some(Value::Array(failed)) = all_units.get("failed_to_load") {
+        if !failed.is_empty() {
+            println!("----");
+            println!("Failed to load {} unit(s):", failed.len());
+            for error in failed {
+                pretty_print_parse_error(error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the status of a single unit.
+async fn get_unit_status(name: &str) -> Result<()> {
+    let unit = get_json_response(&format!("/units/{}", name)).await?;
+    if let Some(error) = unit.get("error").and_then(Value::as_str) {
+        println!("{}", error);
+        return Ok(());
+    }
+
+    pretty_print_unit(&unit);
+    let service = unit.get("service").unwrap();
+    println!("Type: {}", service.get("service_type").unwrap());
+    println!("MainPID: {}", service.get("main_pid").unwrap());
+    if let Some(errno) = service.get("status_errno").and_then(Value::as_i64) {
+        println!("Errno: {}", errno);
+    }
+    Ok(())
+}
+
+/// Print the properties of a unit as `key=value`, one per line, like those of
+/// its capabilities. The capability sets its main process ended up with are
+/// read from /proc.
+async fn show(name: &str) -> Result<()> {
+    let unit = get_json_response(&format!("/units/{}", name)).await?;
+    if let Some(error) = unit.get("error").and_then(Value::as_str) {
+        println!("{}", error);
+        return Ok(());
+    }
+    let service = unit.get("service").unwrap_or(&Value::Null);
+    print_properties("", service);
+    if let Some(pid) = service.get("main_pid").and_then(Value::as_u64) {
+        match ProcessCapabilities::of(pid as u32) {
+            Ok(capabilities) => {
+                let capabilities = serde_json::to_value(capabilities)?;
+                print_properties("main_process.", &capabilities);
+            }
+            Err(e) => println!("Failed to read the capabilities of {}: {}", pid, e),
+        }
+    }
+    Ok(())
+}
+
+/// Print the fields of an object, those of objects in it prefixed with their
+/// name and lists separated by spaces.
+fn print_properties(prefix: &str, object: &Value) {
+    let fields = match object {
+        Value::Object(fields) => fields,
+        _ => return,
+    };
+    for (key, value) in fields {
+        match value {
+            Value::Object(_) => print_properties(&format!("{}{}.", prefix, key), value),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(plain).collect();
+                println!("{}{}={}", prefix, key, items.join(" "));
+            }
+            _ => println!("{}{}={}", prefix, key, plain(value)),
+        }
+    }
+}
+
+/// A JSON value without the quotes around strings.
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// Ask the getupd daemon to start, stop, restart, reload or kill a unit. Unless
+/// told not to block, this waits for the job to finish.
+async fn unit_action(
+    name: &str,
+    action: &str,
+    mut query: Vec<String>,
+    block: bool,
+) -> Result<()> {
+    if block {
+        query.push("wait=true".to_string());
+    }
+    let query =
+        if query.is_empty() { String::new() } else { format!("?{}", query.join("&")) };
+    let body = post_request(&format!("/units/{}/{}{}", name, action, query)).await?;
+    let response: Value = serde_json::from_slice(&body)?;
+    let result = response.get("result").and_then(Value::as_str);
+    match (response.get("error").and_then(Value::as_str), result) {
+        (Some(error), Some(result)) => {
+            println!("Failed to {} {} ({}): {}", action, name, result, error)
+        }
+        (Some(error), None) => println!("Failed to {} {}: {}", action, name, error),
+        (None, _) if !block => {
+            println!("Queued job {}", response.get("id").unwrap_or(&Value::Null))
+        }
+        (None, _) => {}
+    }
+    Ok(())
+}
+
+/// Print the logs of the units, or of all of them, as they come in when
+/// following them.
+async fn logs(query: Vec<(&str, String)>, json: bool) -> Result<()> {
+    let query: Vec<String> = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, url_encode(value)))
+        .collect();
+    let mut resp = send(&format!("/logs?{}", query.join("&")), "GET").await?;
+    if resp.status() != StatusCode::OK {
+        let body = hyper::body::to_bytes(resp).await?;
+        let error: Value = serde_json::from_slice(&body)?;
+        println!("{}", error.get("error").and_then(Value::as_str).unwrap_or("Failed"));
+        return Ok(());
+    }
+
+    let hostname = hostname();
+    let mut pending: Vec<u8> = vec![];
+    while let Some(chunk) = resp.body_mut().data().await {
+        pending.extend_from_slice(&chunk?);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            // Empty lines only keep the connection alive.
+            if line.is_empty() {
+                continue;
+            }
+            if json {
+                println!("{}", line);
+            } else if let Ok(entry) = serde_json::from_str::<Value>(line) {
+                print_short(&entry, &hostname);
+            }
+        }
+    }
     Ok(())
 }
 
+/// Print a log entry like journalctl does by default:
+/// `Apr 01 13:05:00 host example.service[42]: message`
+fn print_short(entry: &Value, hostname: &str) {
+    let timestamp = entry.get("timestamp").and_then(Value::as_u64).unwrap_or(0);
+    // Messages sent to syslog go by the name their sender gave.
+    let unit = entry
+        .get("identifier")
+        .or_else(|| entry.get("unit"))
+        .and_then(Value::as_str)
+        .unwrap_or("?");
+    let message = entry.get("message").and_then(Value::as_str).unwrap_or("");
+    match entry.get("pid").and_then(Value::as_u64) {
+        Some(pid) => {
+            println!(
+                "{} {} {}[{}]: {}",
+                local_time(timestamp),
+                hostname,
+                unit,
+                pid,
+                message
+            )
+        }
+        None => println!("{} {} {}: {}", local_time(timestamp), hostname, unit, message),
+    }
+}
+
+/// Microseconds since the epoch as local time, like `Apr 01 13:05:00`.
+fn local_time(timestamp: u64) -> String {
+    let months = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+        "Dec",
+    ];
+    let seconds = (timestamp / 1_000_000) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&seconds, &mut tm) };
+    format!(
+        "{} {:02} {:02}:{:02}:{:02}",
+        months[tm.tm_mon as usize % 12],
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    match nix::unistd::gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "localhost".to_string(),
+    }
+}
+
+/// Percent-encode a query string value.
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Pretty print a unit file that failed to load, in the same format getupd
+/// logs it: path:line: [Section] Key= reason
+fn pretty_print_parse_error(error: &Value) {
+    let mut location =
+        error.get("path").and_then(Value::as_str).unwrap_or("?").to_string();
+    if let Some(line) = error.get("line").and_then(Value::as_u64) {
+        location.push_str(&format!(":{}", line));
+    }
+    location.push(':');
+    if let Some(section) = error.get("section").and_then(Value::as_str) {
+        location.push_str(&format!(" [{}]", section));
+    }
+    if let Some(key) = error.get("key").and_then(Value::as_str) {
+        location.push_str(&format!(" {}=", key));
+    }
+    println!(
+        "  {} {}",
+        location,
+        error.get("reason").and_then(Value::as_str).unwrap_or("")
+    );
+}
+
 /// Pretty print a unit object.
 fn pretty_print_unit(unit: &Value) {
     println!("----");
     println!("Description: {}", unit.get("description").unwrap());
     println!("Documentation: {}", unit.get("documentation").unwrap());
-    println!(
-        "State: {}",
-        unit.get("service").unwrap().get("current_state").unwrap()
-    );
+    println!("State: {}", unit.get("service").unwrap().get("current_state").unwrap());
+    if let Some(status) =
+        unit.get("service").unwrap().get("status_text").and_then(Value::as_str)
+    {
+        println!("Status: {}", status);
+    }
     println!(
         "RestartPolicy: {}",
         unit.get("service").unwrap().get("restart_policy").unwrap()
     );
+    if let Some(restarts) =
+        unit.get("service").unwrap().get("restarts").and_then(Value::as_u64)
+    {
+        println!("Restarts: {}", restarts);
+    }
+    if let Some(cgroup) =
+        unit.get("service").unwrap().get("cgroup").and_then(Value::as_str)
+    {
+        println!("CGroup: {}", cgroup);
+    }
+    if let Some(Value::Object(resources)) = unit.get("service").unwrap().get("resources")
+    {
+        for (key, value) in resources.iter().filter(|(_, value)| !value.is_null()) {
+            println!("{}: {}", key, value);
+        }
+    }
 }
 
 #[tokio::main]
@@ -105,24 +394,196 @@ async fn main() -> Result<()> {
         .author("Abhilash Raj")
         .about("an alternate init system for GNU/Linux")
         .subcommand(SubCommand::with_name("units").about("get all units"))
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show the status of a unit")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Show the properties of a unit, like its capabilities")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("start")
+                .about("Start a unit")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("stop")
+                .about("Stop a unit")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("restart")
+                .about("Restart a unit")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("reload-unit")
+                .about("Reload the configuration of a unit")
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("kill")
+                .about("Send a signal to the processes of a unit")
+                .arg(
+                    Arg::with_name("signal")
+                        .short("s")
+                        .long("signal")
+                        .takes_value(true)
+                        .help("Signal to send, SIGTERM by default"),
+                )
+                .arg(Arg::with_name("unit").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("set-property")
+                .about("Change resource control properties of a unit while it runs")
+                .arg(Arg::with_name("unit").required(true))
+                .arg(
+                    Arg::with_name("property")
+                        .required(true)
+                        .multiple(true)
+                        .help("Property to set, like MemoryMax=1G or CPUQuota=20%"),
+                ),
+        )
+        .arg(
+            Arg::with_name("no-block")
+                .long("no-block")
+                .global(true)
+                .help("Don't wait for the job to finish, print its ID instead"),
+        )
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown getup daemon"))
         .subcommand(SubCommand::with_name("reload").about("Reload all the unit files"))
+        .subcommand(SubCommand::with_name("poweroff").about("Shut down and power off"))
+        .subcommand(SubCommand::with_name("reboot").about("Shut down and reboot"))
+        .subcommand(SubCommand::with_name("halt").about("Shut down and halt"))
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Show the output of units, like journalctl")
+                .arg(
+                    Arg::with_name("unit")
+                        .short("u")
+                        .long("unit")
+                        .takes_value(true)
+                        .help("Only show the output of this unit"),
+                )
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Keep showing new output as it comes in"),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .short("n")
+                        .long("lines")
+                        .takes_value(true)
+                        .help(
+                            "Show this many of the most recent lines, 10 when following",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .short("S")
+                        .long("since")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help(
+                            "Show output since, like 2020-04-01 13:05, -1h or yesterday",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .short("U")
+                        .long("until")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("Show output until, like since"),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .short("p")
+                        .long("priority")
+                        .takes_value(true)
+                        .help("Show this priority and more important ones, like err"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .possible_values(&["short", "json"])
+                        .default_value("short")
+                        .help("Output format"),
+                ),
+        )
         .get_matches();
 
-    match matches.subcommand_name() {
-        Some("units") => {
-
+    match matches.subcommand() {
+        ("units", _) => {
             if let Err(some) = get_all_units().await {
                 println!("Failed to get all units {:?}", some);
             }
         }
-        Some("shutdown") => {
+        ("status", Some(args)) => {
+            let name = args.value_of("unit").unwrap();
+            if let Err(some) = get_unit_status(name).await {
+                println!("Failed to get status of {} {:?}", name, some);
+            }
+        }
+        ("show", Some(args)) => {
+            let name = args.value_of("unit").unwrap();
+            if let Err(some) = show(name).await {
+                println!("Failed to show {} {:?}", name, some);
+            }
+        }
+        (action @ "start", Some(args))
+        | (action @ "stop", Some(args))
+        | (action @ "restart", Some(args))
+        | (action @ "reload-unit", Some(args))
+        | (action @ "kill", Some(args)) => {
+            let name = args.value_of("unit").unwrap();
+            let query = match args.value_of("signal") {
+                Some(signal) => vec![format!("signal={}", signal)],
+                None => vec![],
+            };
+            let block = !args.is_present("no-block");
+            let action = action.trim_end_matches("-unit");
+            if let Err(some) = unit_action(name, action, query, block).await {
+                println!("Failed to {} {} {:?}", action, name, some);
+            }
+        }
+        ("set-property", Some(args)) => {
+            let name = args.value_of("unit").unwrap();
+            set_property(name, args.values_of("property").unwrap().collect()).await;
+        }
+        ("shutdown", _) => {
             shutdown().await;
-            ()
         }
-        Some("reload") => {
+        ("reload", _) => {
             reload().await;
-            ()
+        }
+        (action @ "poweroff", _) | (action @ "reboot", _) | (action @ "halt", _) => {
+            system_shutdown(action).await;
+        }
+        ("logs", Some(args)) => {
+            let mut query = vec![];
+            for key in &["unit", "since", "until", "priority", "lines"] {
+                if let Some(value) = args.value_of(key) {
+                    query.push((*key, value.to_string()));
+                }
+            }
+            if args.is_present("follow") {
+                if !args.is_present("lines") {
+                    query.push(("lines", "10".to_string()));
+                }
+                query.push(("follow", "true".to_string()));
+            }
+            let json = args.value_of("output") == Some("json");
+            if let Err(some) = logs(query, json).await {
+                println!("Failed to get the logs {:?}", some);
+            }
         }
         _ => println!("Invalid command."),
     }
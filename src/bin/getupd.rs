@@ -1,18 +1,41 @@
-use std::fs::File;
+use std::fs::{self, File};
 
 use daemonize::Daemonize;
 use futures::channel::oneshot;
+use futures::future::FutureExt;
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 
 use getup::api::router;
+use getup::cgroup;
 use getup::conf::{initialize_config, SETTINGS};
-use getup::core::initialize;
-use getup::signals::{Message, CHANNEL};
-use getup::units::ALL_UNITS;
+use getup::core::{
+    boot, handle_exits, initialize, kill_unit, reload_unit, reload_units, restart_unit,
+    shutdown, start_unit, stop_unit,
+};
+use getup::credentials;
+use getup::environment;
+use getup::init::{self, survive_panic};
+use getup::jobs::JobError;
+use getup::logs;
+use getup::monitor;
+use getup::namespace;
+use getup::notify;
+use getup::policy::{self, Peer};
+use getup::signals::{self, Message, SystemAction, CHANNEL};
+use getup::syslog;
+use nix::sys::reboot::set_cad_enabled;
 use std::env;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process;
 use std::thread;
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
 
 #[macro_use]
 extern crate log;
@@ -26,26 +49,70 @@ dir"
     );
 }
 
+/// Serve the API on the socket, and over TCP on localhost if that's enabled,
+/// until rx fires.
 #[tokio::main]
 async fn run(rx: oneshot::Receiver<()>) {
-    // This is our socket address...
-    let addr = format!("0.0.0.0:{}", SETTINGS.port);
-
-    let service =
-        make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(router)) });
-    info!("API Server running on {}", addr);
-    // This is our server object.
-    let server = Server::bind(&addr.parse().expect("Unable to parse host port"))
-        .serve(service)
-        .with_graceful_shutdown(async move {
-            rx.await.ok();
+    let shutdown = rx.shared();
+
+    let tcp = if SETTINGS.tcp {
+        let addr = format!("127.0.0.1:{}", SETTINGS.port);
+        info!("API Server running on {}", addr);
+        let service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|req| router(req, None)))
         });
+        let server = Server::bind(&addr.parse().expect("Unable to parse host port"))
+            .serve(service)
+            .with_graceful_shutdown(shutdown.clone().map(|_| ()));
+        Some(tokio::spawn(server))
+    } else {
+        None
+    };
+
+    match bind_socket(&SETTINGS.api_socket) {
+        Ok(mut listener) => {
+            info!("API Server running on {}", SETTINGS.api_socket);
+            let service = make_service_fn(|conn: &UnixStream| {
+                let peer = conn
+                    .peer_cred()
+                    .ok()
+                    .map(|cred| Peer { uid: cred.uid, gid: cred.gid });
+                async move { Ok::<_, hyper::Error>(service_fn(move |req| router(req, peer))) }
+            });
+            let server = Server::builder(accept::from_stream(listener.incoming()))
+                .serve(service)
+                .with_graceful_shutdown(shutdown.map(|_| ()));
+            if let Err(e) = server.await {
+                eprintln!("server error: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to bind {}: {}", SETTINGS.api_socket, e),
+    }
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    if let Some(tcp) = tcp {
+        if let Ok(Err(e)) = tcp.await {
+            eprintln!("server error: {}", e);
+        }
     }
 }
 
+/// Bind the API socket. Anybody may connect, what they may do depends on who
+/// they are.
+fn bind_socket(path: &str) -> io::Result<UnixListener> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+            // The daemon's umask doesn't let anybody in.
+            fs::set_permissions(parent, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    // Remove a stale socket left behind by a previous run.
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
 fn main() {
     initialize_config();
 
@@ -62,66 +129,202 @@ fn main() {
 
     initialize(services_path);
 
-    let stdout = File::create(&SETTINGS.stdout).unwrap();
-    let stderr = File::create(&SETTINGS.stderr).unwrap();
+    // As init there is no parent to go to the background from.
+    let pid1 = init::is_pid1();
+    if !pid1 {
+        let stdout = File::create(&SETTINGS.stdout).unwrap();
+        let stderr = File::create(&SETTINGS.stderr).unwrap();
+
+        let daemon = Daemonize::new()
+            .pid_file(&SETTINGS.pidfile)
+            .chown_pid_file(true)
+            .working_directory(&SETTINGS.workdir)
+            .umask(0o777)
+            .stdout(stdout)
+            .stderr(stderr)
+            .exit_action(|| info!("Switching to background..."))
+            .privileged_action(|| info!("Dropping privileges"));
+
+        if let Err(e) = daemon.start() {
+            eprintln!("Error, {}", e);
+            return;
+        }
+    } else {
+        info!("Running as PID 1");
+        // Have the kernel send us SIGINT on Ctrl-Alt-Del rather than reboot
+        // right away, this fails in a PID namespace which is fine.
+        let _ = set_cad_enabled(false);
+    }
+
+    // Run this server for... forever!
+
+    // Before anything else starts a thread, children are reaped and signals
+    // handled through signalfds.
+    if let Err(e) = monitor::block_sigchld() {
+        error!("Failed to block SIGCHLD: {}", e);
+    }
+    if pid1 {
+        if let Err(e) = init::block_signals() {
+            error!("Failed to block signals: {}", e);
+        }
+    } else if let Err(e) = signals::block(&[libc::SIGHUP]) {
+        error!("Failed to block SIGHUP: {}", e);
+    }
+
+    match logs::open(&SETTINGS.log_dir, SETTINGS.log_max_size, SETTINGS.log_max_files) {
+        Ok(()) => info!("Storing the output of services in {}", SETTINGS.log_dir),
+        Err(e) => error!("Failed to open the log store in {}: {}", SETTINGS.log_dir, e),
+    }
 
-    let daemon = Daemonize::new()
-        .pid_file(&SETTINGS.pidfile)
-        .chown_pid_file(true)
-        .working_directory(&SETTINGS.workdir)
-        .umask(0o777)
-        .stdout(stdout)
-        .stderr(stderr)
-        .exit_action(|| info!("Switching to background..."))
-        .privileged_action(|| info!("Dropping privileges"));
+    policy::reload(&SETTINGS.api_policy);
+    credentials::set_dynamic_range(SETTINGS.dynamic_uid_min, SETTINGS.dynamic_uid_max);
+    if let Err(e) = environment::set_default(&SETTINGS.default_environment) {
+        error!("Invalid default environment: {}", e);
+    }
+    namespace::set_directory(&SETTINGS.sandbox_dir);
+
+    match cgroup::init(&SETTINGS.cgroup_root) {
+        Ok(_) => info!("Putting units in cgroups under {}", SETTINGS.cgroup_root),
+        Err(e) => {
+            error!("Not using cgroups, failed to set up {}: {}", SETTINGS.cgroup_root, e)
+        }
+    }
+
+    if SETTINGS.syslog {
+        match syslog::listen(&SETTINGS.syslog_socket) {
+            Ok(_) => {
+                info!("Listening for syslog messages on {}", SETTINGS.syslog_socket)
+            }
+            Err(e) => error!("Failed to bind {}: {}", SETTINGS.syslog_socket, e),
+        }
+    }
+
+    match notify::listen(&SETTINGS.notify_socket) {
+        Ok(_) => info!("Listening for notifications on {}", SETTINGS.notify_socket),
+        Err(e) => error!("Failed to bind {}: {}", SETTINGS.notify_socket, e),
+    }
 
     // Create a channel to signal Hyper to shutdown when we receive the signal
     // from the Web API.
     let (tx, rx) = oneshot::channel::<()>();
 
-    match daemon.start() {
-        Ok(_) => {
-            // Run this server for... forever!
-
-            info!("Starting up API  in a different thread");
-            let api_server = thread::spawn(move || run(rx));
-
-            let rx = &CHANNEL.1;
+    info!("Starting up API  in a different thread");
+    let api_server = thread::spawn(move || run(rx));
 
+    // Units are started from the main thread, concurrently as far as their
+    // ordering allows.
+    let mut runtime = Runtime::new().expect("Failed to create the runtime");
+    // If reaping children stops, zombies pile up for good, so it is started
+    // over even after a panic.
+    runtime.spawn(async {
+        loop {
+            match tokio::spawn(monitor::reap_children()).await {
+                Ok(Err(e)) => error!("Failed to watch for exiting children: {}", e),
+                Ok(Ok(())) => {}
+                Err(e) => error!("Reaping children panicked: {}", e),
+            }
+            delay_for(Duration::from_secs(1)).await;
+        }
+    });
+    runtime.spawn(handle_exits());
+    runtime.spawn(monitor::watch_cgroups());
+    if pid1 {
+        runtime.spawn(async {
             loop {
-                match rx.lock().unwrap().recv().unwrap() {
-                    Message::Shutdown => {
-                        info!("Got: Shutdown signal");
-                        break;
-                    }
-                    Message::Start(unit_name) => {
-                        info!("Got start {:?}", unit_name);
-                        if let Some(unit) =
-                            ALL_UNITS.lock().unwrap().get_by_name(&unit_name)
-                        {
-                            unit.service.lock().unwrap().start();
-                        } else {
-                            error!("Did not find a service named {}", unit_name);
-                        }
-                    }
-                    Message::Stop(unit_name) => {
-                        info!("Got stop {:?}", unit_name);
-                        if let Some(unit) =
-                            ALL_UNITS.lock().unwrap().get_by_name(&unit_name)
-                        {
-                            unit.service.lock().unwrap().stop();
-                        } else {
-                            error!("Did not find a service named {}", unit_name);
-                        }
-                    }
-                    _ => error!("Unable to handle message"),
+                match tokio::spawn(init::handle_signals()).await {
+                    Ok(Err(e)) => error!("Failed to handle signals: {}", e),
+                    Ok(Ok(())) => {}
+                    Err(e) => error!("Handling signals panicked: {}", e),
                 }
+                delay_for(Duration::from_secs(1)).await;
+            }
+        });
+    } else {
+        runtime.spawn(async {
+            if let Err(e) = signals::reload_on_sighup().await {
+                error!("Failed to handle SIGHUP: {}", e);
             }
+        });
+    }
+    survive_panic(|| {
+        if let Err(e) = runtime.block_on(boot()) {
+            error!("Not all units started at boot: {}", e);
+        }
+    });
+
+    let rx = &CHANNEL.1;
 
-            let _ = tx.send(());
-            info!("Waiting for API Server to exit!");
-            api_server.join().expect("Waiting for child process to exit clean");
+    let action = loop {
+        let job = rx.lock().unwrap().recv().unwrap();
+        if let Err(e) = job.start() {
+            info!("Not running job {}: {}", job.id, e);
+            job.finish(Err(e));
+            continue;
+        }
+        let mut action = None;
+        survive_panic(|| match job.message.clone() {
+            Message::Shutdown => {
+                info!("Got: Shutdown signal");
+                // Init can't just go away.
+                action = Some((SystemAction::PowerOff, false));
+            }
+            Message::SystemShutdown { action: system_action, immediate } => {
+                info!("Got: {:?}, immediate: {}", system_action, immediate);
+                action = Some((system_action, immediate));
+            }
+            // In the main loop, so that it happens in order with the other jobs.
+            Message::ReloadUnits => {
+                info!("Got: Reload unit files");
+                reload_units();
+                policy::reload(&SETTINGS.api_policy);
+                job.finish(Ok(()));
+            }
+            Message::Kill(unit_name, signal) => {
+                info!("Got kill {:?} with {}", unit_name, signal);
+                let result = kill_unit(&unit_name, signal);
+                if let Err(e) = &result {
+                    error!("Failed to kill {}: {}", unit_name, e);
+                }
+                job.finish(result);
+            }
+            // Jobs that wait on units run on their own, so that a unit taking
+            // its time doesn't hold up everything else.
+            message => {
+                runtime.spawn(async move {
+                    let (unit_name, what) = message.describe();
+                    let unit_name = unit_name.unwrap_or_default().to_string();
+                    info!("Got {} {:?}", what, unit_name);
+                    let result = match message {
+                        Message::Start(_) => start_unit(&unit_name).await,
+                        Message::Stop(_) => stop_unit(&unit_name).await,
+                        Message::Restart(_) => restart_unit(&unit_name).await,
+                        Message::Reload(_) => reload_unit(&unit_name).await,
+                        _ => unreachable!("only unit jobs are spawned"),
+                    };
+                    if let Err(e) = &result {
+                        error!("Failed to {} {}: {}", what, unit_name, e);
+                    }
+                    job.finish(result);
+                });
+            }
+        });
+        if let Some(action) = action {
+            break action;
         }
-        Err(e) => eprintln!("Error, {}", e),
     };
+
+    // Whatever is still queued up won't get to run.
+    while let Ok(job) = rx.lock().unwrap().try_recv() {
+        job.finish(Err(JobError::canceled("the system is shutting down")));
+    }
+
+    let (action, immediate) = action;
+    survive_panic(|| runtime.block_on(shutdown(pid1, immediate)));
+    if pid1 {
+        init::finish(action);
+    }
+
+    let _ = tx.send(());
+    info!("Waiting for API Server to exit!");
+    api_server.join().expect("Waiting for child process to exit clean");
 }
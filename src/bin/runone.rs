@@ -1,17 +1,15 @@
-use ctrlc;
 use getup::{
-    monitor,
-    units::{self, RestartMethod},
+    core, monitor,
+    units::{self, CurrState},
 };
 /// run one is a script which reads a systems configuration path and spawns off
 /// the service and keeps on monitoring it.
 use std::env;
-use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::time::delay_for;
 
 fn usage(args: &Vec<String>) {
     println!("Expected 1 parameter, got {:?}", args);
@@ -24,63 +22,79 @@ fn main() {
         usage(&args);
         process::exit(1);
     }
-    let unit = units::Unit::from_unitfile(Path::new(&args[1]));
+    let unit = match units::Unit::from_unitfile(Path::new(&args[1])) {
+        Ok(unit) => unit,
+        Err(e) => {
+            println!("Failed to load unit: {}", e);
+            process::exit(1);
+        }
+    };
     println!("Parsed unit file at {:?}", args[1]);
     println!("{:?}", unit);
     println!(
         "Starting up the service using command: {:?}",
         unit.service.lock().unwrap().exec_start
     );
-    unit.service.lock().unwrap().start();
-
-    let shared = Arc::new(AtomicBool::new(false));
-    let shared_clone = shared.clone();
+    if let Err(e) = monitor::block_sigchld() {
+        println!("Failed to block SIGCHLD: {}", e);
+        process::exit(1);
+    }
+    let service = unit.service.clone();
+    let name = unit.name.clone();
+    units::ALL_UNITS.lock().unwrap().add_unit(unit);
 
+    // The handler runs on a thread of its own, the stop itself happens on the
+    // runtime.
+    let (stop_tx, mut stop_rx) = mpsc::unbounded_channel();
     let _ = ctrlc::set_handler(move || {
-        // If the user wants to exit, raise the flag to signal the running
-        // thread to kill the child process.
-        shared_clone.store(true, Ordering::Relaxed);
+        let _ = stop_tx.send(());
     });
 
-    loop {
-        let service_clone = unit.service.clone();
-        let shared_shared_clone = shared.clone();
-
-        let mon_thread = thread::spawn(move || {
-            monitor::monitor_proc(&service_clone, &shared_shared_clone);
-        });
+    let mut runtime = Runtime::new().expect("Failed to create the runtime");
+    runtime.block_on(async {
+        let mut exits = monitor::subscribe();
+        tokio::spawn(monitor::reap_children());
+        service.lock().unwrap().start();
 
-        let _ = mon_thread.join().expect("Failed to join the threads");
+        loop {
+            let exited = tokio::select! {
+                exited = exits.recv() => exited,
+                _ = stop_rx.recv() => {
+                    // If the user wants to exit, stop the service the way its
+                    // unit says.
+                    core::stop_service(&name, &service, false).await;
+                    break;
+                }
+            };
+            let exited = match exited {
+                Ok((exited, _)) => exited,
+                Err(_) => break,
+            };
+            if exited != name {
+                continue;
+            }
+            let delay = {
+                let mut unlocked_service = service.lock().unwrap();
+                // The next command of a oneshot service is running.
+                if unlocked_service.child_pid().is_some() {
+                    continue;
+                }
+                // Restart= decides whether it goes again.
+                if unlocked_service.status() != CurrState::Restarting {
+                    break;
+                }
+                unlocked_service.next_restart_delay()
+            };
+            println!("Restarting in {:?}...", delay);
+            delay_for(delay).await;
 
-        let mut unlocked_service = unit.service.lock().unwrap();
-        match unlocked_service.restart_policy {
-            RestartMethod::Never => break,
-            RestartMethod::Always => {
-                println!("Restart policy is RestartMethod::Always...");
+            let mut unlocked_service = service.lock().unwrap();
+            if unlocked_service.status() == CurrState::Restarting {
                 unlocked_service.start();
             }
-            RestartMethod::OnFailure => {
-                println!("Restart policy is Restart::OnFailure...");
-                match unlocked_service.exit_status.unwrap().code() {
-                    Some(code) => {
-                        if code != 0 {
-                            unlocked_service.start();
-                        } else {
-                            println!(
-                                "Exitted with exit code 0, so not going to restart."
-                            );
-                            break;
-                        }
-                    }
-                    None => {
-                        println!(
-                            "Exitted with signal: {:?}, so not going to restart.",
-                            unlocked_service.exit_status.unwrap().signal().unwrap()
-                        );
-                        break;
-                    }
-                }
+            if unlocked_service.status() == CurrState::Failed {
+                break;
             }
         }
-    }
+    });
 }
@@ -0,0 +1,478 @@
+/// capabilities.rs limits the privileges of services with
+/// `CapabilityBoundingSet=`, `AmbientCapabilities=`, `NoNewPrivileges=` and
+/// `SecureBits=`, see capabilities(7).
+///
+/// The bounding set is dropped before the process switches to the user of the
+/// service, while it still may. Ambient capabilities are the ones a process
+/// that isn't root keeps across exec, like `CAP_NET_BIND_SERVICE` for a web
+/// server running as nobody. Keeping them across the switch of user takes
+/// `SECBIT_KEEP_CAPS`, after which they are made inheritable and raised.
+/// `NoNewPrivileges=` comes last, since nothing after it could gain any.
+use crate::unitfile::parse_bool;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The directives, as they are called in unit files.
+pub const DIRECTIVES: [&str; 4] =
+    ["CapabilityBoundingSet", "AmbientCapabilities", "NoNewPrivileges", "SecureBits"];
+
+/// Capabilities by their number.
+const CAPABILITIES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// The bits of SecureBits=, by their number.
+const SECURE_BITS: [&str; 6] = [
+    "noroot",
+    "noroot-locked",
+    "no-setuid-fixup",
+    "no-setuid-fixup-locked",
+    "keep-caps",
+    "keep-caps-locked",
+];
+
+const SECBIT_KEEP_CAPS: u32 = 1 << 4;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, which has 64 bits in two halves.
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// A set of capabilities, with a bit for each by its number.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CapabilitySet(pub u64);
+
+impl CapabilitySet {
+    /// Every capability there is a name for.
+    pub fn all() -> CapabilitySet {
+        CapabilitySet((1 << CAPABILITIES.len()) - 1)
+    }
+
+    pub fn contains(&self, capability: usize) -> bool {
+        capability < 64 && self.0 & (1 << capability) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The numbers of the capabilities in the set.
+    fn numbers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..64).filter(move |capability| self.contains(*capability))
+    }
+
+    /// Names of the capabilities in the set, those without one by number.
+    pub fn names(&self) -> Vec<String> {
+        self.numbers()
+            .map(|capability| match CAPABILITIES.get(capability) {
+                Some(name) => name.to_string(),
+                None => capability.to_string(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for CapabilitySet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.names().join(" "))
+    }
+}
+
+impl Serialize for CapabilitySet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.names())
+    }
+}
+
+/// The securebits flags, see `SECURE_BITS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SecureBits(pub u32);
+
+impl Serialize for SecureBits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names = SECURE_BITS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| name);
+        serializer.collect_seq(names)
+    }
+}
+
+/// The capability directives of a service.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// Capabilities the processes can ever have, None leaves them all.
+    pub bounding_set: Option<CapabilitySet>,
+    /// Capabilities the processes keep across exec, even if not root.
+    pub ambient_set: CapabilitySet,
+    /// Whether the processes and their children can't gain privileges, like
+    /// through setuid binaries.
+    pub no_new_privileges: bool,
+    pub secure_bits: SecureBits,
+}
+
+impl Capabilities {
+    /// Set the directive key to value as it is written in unit files. The
+    /// sets of more than one line add up, and an empty one resets them.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key {
+            "CapabilityBoundingSet" => {
+                self.bounding_set = match parse_set(value)? {
+                    None => None,
+                    Some(set) => {
+                        let previous = self.bounding_set.unwrap_or_default();
+                        Some(CapabilitySet(previous.0 | set.0))
+                    }
+                }
+            }
+            "AmbientCapabilities" => {
+                let set = parse_set(value)?.unwrap_or_default();
+                self.ambient_set = match value {
+                    "" => set,
+                    _ => CapabilitySet(self.ambient_set.0 | set.0),
+                }
+            }
+            "NoNewPrivileges" => {
+                self.no_new_privileges = parse_bool(value)
+                    .ok_or_else(|| format!("{:?} is not a boolean", value))?
+            }
+            "SecureBits" => {
+                self.secure_bits = SecureBits(0);
+                for name in value.split_whitespace() {
+                    let bit = SECURE_BITS
+                        .iter()
+                        .position(|bit| *bit == name)
+                        .ok_or_else(|| format!("unknown secure bit {:?}", name))?;
+                    self.secure_bits.0 |= 1 << bit;
+                }
+            }
+            _ => return Err(format!("unknown directive {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Ambient capabilities have to be in the bounding set.
+    pub fn validate(&self) -> Result<(), String> {
+        let bounding_set = self.bounding_set.unwrap_or_else(CapabilitySet::all);
+        let outside = CapabilitySet(self.ambient_set.0 & !bounding_set.0);
+        if !outside.is_empty() {
+            return Err(format!("{} not in CapabilityBoundingSet=", outside));
+        }
+        Ok(())
+    }
+
+    /// Have the command drop the bounding set and set the secure bits before
+    /// it executes anything. This comes before switching users, and
+    /// switching_user says whether it will.
+    pub fn prepare(&self, cmd: &mut Command, switching_user: bool) {
+        // The kernel refuses to drop capabilities it doesn't know about.
+        let last = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+            .ok()
+            .and_then(|last| last.trim().parse().ok())
+            .unwrap_or(CAPABILITIES.len() - 1);
+        let drop: Vec<usize> = match self.bounding_set {
+            Some(set) => (0..=last).filter(|cap| !set.contains(*cap)).collect(),
+            None => vec![],
+        };
+        let keep_caps = switching_user && !self.ambient_set.is_empty();
+        let secure_bits = self.secure_bits.0;
+        unsafe {
+            cmd.pre_exec(move || {
+                for capability in &drop {
+                    if libc::prctl(libc::PR_CAPBSET_DROP, *capability, 0, 0, 0) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if secure_bits != 0 {
+                    let bits =
+                        secure_bits | if keep_caps { SECBIT_KEEP_CAPS } else { 0 };
+                    if libc::prctl(
+                        libc::PR_SET_SECUREBITS,
+                        bits as libc::c_ulong,
+                        0,
+                        0,
+                        0,
+                    ) != 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                } else if keep_caps
+                    && libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Have the command raise the ambient capabilities and set no_new_privs
+    /// before it executes anything, after it switched users.
+    pub fn apply(&self, cmd: &mut Command) {
+        let ambient_set = self.ambient_set;
+        let no_new_privileges = self.no_new_privileges;
+        unsafe {
+            cmd.pre_exec(move || {
+                if !ambient_set.is_empty() {
+                    raise_ambient(ambient_set)?;
+                }
+                if no_new_privileges
+                    && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Make the capabilities inheritable, which they have to be for the ambient
+/// set, and raise them. Only what is safe to call after fork.
+unsafe fn raise_ambient(set: CapabilitySet) -> io::Result<()> {
+    let mut header = CapHeader { version: CAPABILITY_VERSION, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    if libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    data[0].inheritable |= set.0 as u32;
+    data[1].inheritable |= (set.0 >> 32) as u32;
+    if libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for capability in set.numbers() {
+        let raise = libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong;
+        if libc::prctl(libc::PR_CAP_AMBIENT, raise, capability, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// A list of capability names, or all but them with a `~` in front. None is
+/// the empty value, which resets the set.
+fn parse_set(value: &str) -> Result<Option<CapabilitySet>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let (names, invert) = match value.strip_prefix('~') {
+        Some(names) => (names, true),
+        None => (value, false),
+    };
+    let mut set = 0;
+    for name in names.split_whitespace() {
+        let upper = name.to_uppercase();
+        let capability = CAPABILITIES
+            .iter()
+            .position(|capability| *capability == upper)
+            .ok_or_else(|| format!("unknown capability {:?}", name))?;
+        set |= 1 << capability;
+    }
+    if invert {
+        set = CapabilitySet::all().0 & !set;
+    }
+    Ok(Some(CapabilitySet(set)))
+}
+
+/// The capabilities a process has, as in /proc/PID/status.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ProcessCapabilities {
+    pub inheritable: CapabilitySet,
+    pub permitted: CapabilitySet,
+    pub effective: CapabilitySet,
+    pub bounding: CapabilitySet,
+    pub ambient: CapabilitySet,
+    pub no_new_privileges: bool,
+}
+
+impl ProcessCapabilities {
+    /// The capabilities of the process pid.
+    pub fn of(pid: u32) -> io::Result<ProcessCapabilities> {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+        Ok(ProcessCapabilities::parse(&status))
+    }
+
+    fn parse(status: &str) -> ProcessCapabilities {
+        let mut capabilities = ProcessCapabilities::default();
+        for line in status.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            let set = || CapabilitySet(u64::from_str_radix(value, 16).unwrap_or(0));
+            match key {
+                "CapInh" => capabilities.inheritable = set(),
+                "CapPrm" => capabilities.permitted = set(),
+                "CapEff" => capabilities.effective = set(),
+                "CapBnd" => capabilities.bounding = set(),
+                "CapAmb" => capabilities.ambient = set(),
+                "NoNewPrivs" => capabilities.no_new_privileges = value == "1",
+                _ => {}
+            }
+        }
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    const NET_BIND_SERVICE: u64 = 1 << 10;
+
+    fn capabilities(directives: &[(&str, &str)]) -> Result<Capabilities, String> {
+        let mut capabilities = Capabilities::default();
+        for (key, value) in directives {
+            capabilities.set(key, value)?;
+        }
+        Ok(capabilities)
+    }
+
+    #[test]
+    fn test_set() {
+        let caps = capabilities(&[
+            ("CapabilityBoundingSet", "CAP_NET_BIND_SERVICE"),
+            ("CapabilityBoundingSet", "cap_chown"),
+            ("AmbientCapabilities", "CAP_NET_BIND_SERVICE"),
+            ("NoNewPrivileges", "yes"),
+            ("SecureBits", "keep-caps noroot-locked"),
+        ])
+        .unwrap();
+        assert_eq!(caps.bounding_set, Some(CapabilitySet(NET_BIND_SERVICE | 1)));
+        assert_eq!(caps.ambient_set.names(), ["CAP_NET_BIND_SERVICE"]);
+        assert!(caps.no_new_privileges);
+        assert_eq!(caps.secure_bits, SecureBits(0b10010));
+        assert!(caps.validate().is_ok());
+
+        let caps = capabilities(&[
+            ("CapabilityBoundingSet", "CAP_CHOWN"),
+            ("CapabilityBoundingSet", ""),
+            ("CapabilityBoundingSet", "~CAP_SYS_ADMIN CAP_SYS_MODULE"),
+        ])
+        .unwrap();
+        let bounding_set = caps.bounding_set.unwrap();
+        assert!(bounding_set.contains(10));
+        assert!(!bounding_set.contains(16) && !bounding_set.contains(21));
+
+        let caps = capabilities(&[
+            ("CapabilityBoundingSet", "CAP_CHOWN"),
+            ("AmbientCapabilities", "CAP_NET_BIND_SERVICE"),
+        ])
+        .unwrap();
+        assert!(caps.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(capabilities(&[("CapabilityBoundingSet", "CAP_FLY")]).is_err());
+        assert!(capabilities(&[("AmbientCapabilities", "~")]).is_ok());
+        assert!(capabilities(&[("NoNewPrivileges", "maybe")]).is_err());
+        assert!(capabilities(&[("SecureBits", "keep-everything")]).is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let status = "Name:\tsh\nCapInh:\t0000000000000000\nCapPrm:\t0000000000000400\n\
+                      CapEff:\t0000000000000400\nCapBnd:\t0000000000000401\n\
+                      CapAmb:\t0000000000000400\nNoNewPrivs:\t1\n";
+        let capabilities = ProcessCapabilities::parse(status);
+        assert_eq!(capabilities.effective, CapabilitySet(NET_BIND_SERVICE));
+        assert_eq!(capabilities.bounding.to_string(), "CAP_CHOWN CAP_NET_BIND_SERVICE");
+        assert!(capabilities.no_new_privileges);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut caps = capabilities(&[("NoNewPrivileges", "yes")]).unwrap();
+        let is_root = unsafe { libc::geteuid() == 0 };
+        if is_root {
+            caps.set(
+                "CapabilityBoundingSet",
+                "CAP_NET_BIND_SERVICE CAP_SETUID CAP_SETGID",
+            )
+            .unwrap();
+            caps.set("AmbientCapabilities", "CAP_NET_BIND_SERVICE").unwrap();
+        }
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "cat /proc/$$/status"]).stdout(Stdio::piped());
+        caps.prepare(&mut cmd, is_root);
+        if is_root {
+            unsafe {
+                cmd.pre_exec(|| match libc::setuid(65534) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                });
+            }
+        }
+        caps.apply(&mut cmd);
+        let output = cmd.output().unwrap();
+        let status =
+            ProcessCapabilities::parse(&String::from_utf8_lossy(&output.stdout));
+        assert!(status.no_new_privileges);
+        if is_root {
+            let kept = CapabilitySet(NET_BIND_SERVICE);
+            assert_eq!(status.effective, kept);
+            assert_eq!(status.ambient, kept);
+            assert_eq!(status.bounding.names().len(), 3);
+        }
+    }
+}
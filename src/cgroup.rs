@@ -0,0 +1,365 @@
+/// cgroup.rs puts every unit in a cgroup of its own, on the cgroup v2
+/// hierarchy under a root set in the config, like
+/// `/sys/fs/cgroup/getup.slice/web.service`.
+///
+/// Processes are moved into the cgroup of their unit before they execute
+/// anything, so whatever they fork stays in there too, even daemons that fork
+/// twice. The cgroup is what the processes of a unit are and what gets killed
+/// when it is stopped. The kernel lets us know when a cgroup runs empty by
+/// changing its `cgroup.events`, which is watched with inotify.
+///
+/// Without cgroup v2, or if the root can't be created, units are tracked by
+/// the ancestry of their processes instead.
+use lazy_static::lazy_static;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use tokio::sync::broadcast;
+
+/// Controllers of the resource control directives, see `resources`.
+const CONTROLLERS: [&str; 5] = ["cpu", "cpuset", "io", "memory", "pids"];
+
+struct Cgroups {
+    root: PathBuf,
+    inotify: Inotify,
+    /// Units by the watch on their `cgroup.events`.
+    watches: HashMap<WatchDescriptor, String>,
+}
+
+lazy_static! {
+    static ref CGROUPS: Mutex<Option<Cgroups>> = Mutex::new(None);
+    /// Units whose cgroup ran empty.
+    static ref EMPTIED: broadcast::Sender<String> = broadcast::channel(64).0;
+}
+
+/// Set up the root cgroup of the units and start watching for cgroups that
+/// run empty in a background thread.
+pub fn init(root: &str) -> io::Result<thread::JoinHandle<()>> {
+    let root = PathBuf::from(root);
+    if !on_cgroup2(&root) {
+        return Err(io::Error::other("not on a cgroup v2 hierarchy"));
+    }
+    fs::create_dir_all(&root)?;
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o755))?;
+    // Down to the root and from there to the cgroups of units.
+    if let Some(parent) = root.parent() {
+        enable_controllers(parent);
+    }
+    enable_controllers(&root);
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(nix_error)?;
+    *CGROUPS.lock().unwrap() = Some(Cgroups { root, inotify, watches: HashMap::new() });
+
+    thread::Builder::new().name("cgroups".to_string()).spawn(move || loop {
+        let events = match inotify.read_events() {
+            Ok(events) => events,
+            Err(e) => {
+                println!("Failed to watch cgroups: {}", e);
+                return;
+            }
+        };
+        for event in events {
+            let unit = CGROUPS
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|cgroups| cgroups.watches.get(&event.wd).cloned());
+            if let Some(unit) = unit {
+                if !is_populated(&unit) {
+                    // Nobody waiting is fine.
+                    let _ = EMPTIED.send(unit);
+                }
+            }
+        }
+    })
+}
+
+/// Whether the root would be on a cgroup v2 hierarchy, going by its parent.
+fn on_cgroup2(root: &Path) -> bool {
+    root.parent().is_some_and(|parent| parent.join("cgroup.controllers").exists())
+}
+
+/// Follow the units whose cgroup runs empty.
+pub fn subscribe() -> broadcast::Receiver<String> {
+    EMPTIED.subscribe()
+}
+
+/// The cgroup of unit, if units get one.
+pub fn path(unit: &str) -> Option<PathBuf> {
+    CGROUPS.lock().unwrap().as_ref().map(|cgroups| cgroups.root.join(unit))
+}
+
+/// Create the cgroup of unit and watch it, returning its path. Nothing is
+/// created if units don't get a cgroup.
+pub fn create(unit: &str) -> io::Result<Option<PathBuf>> {
+    let mut cgroups = CGROUPS.lock().unwrap();
+    let cgroups = match cgroups.as_mut() {
+        Some(cgroups) => cgroups,
+        None => return Ok(None),
+    };
+    let path = cgroups.root.join(unit);
+    fs::create_dir_all(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    let wd = cgroups
+        .inotify
+        .add_watch(&path.join("cgroup.events"), AddWatchFlags::IN_MODIFY)
+        .map_err(nix_error)?;
+    cgroups.watches.insert(wd, unit.to_string());
+    Ok(Some(path))
+}
+
+/// Remove the cgroup of unit, which only works once it is empty.
+pub fn remove(unit: &str) {
+    let mut cgroups = CGROUPS.lock().unwrap();
+    let cgroups = match cgroups.as_mut() {
+        Some(cgroups) => cgroups,
+        None => return,
+    };
+    // Removing the directory removes the watch along with it.
+    cgroups.watches.retain(|_, watched| watched != unit);
+    if let Err(e) = fs::remove_dir(cgroups.root.join(unit)) {
+        if e.kind() != io::ErrorKind::NotFound {
+            println!("Failed to remove the cgroup of {}: {}", unit, e);
+        }
+    }
+}
+
+/// Have the command move itself into the cgroup at path before it executes
+/// anything, failing to spawn if it can't.
+pub fn attach(cmd: &mut Command, path: &Path) {
+    let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+        .expect("cgroup paths have no NUL bytes");
+    unsafe {
+        cmd.pre_exec(move || {
+            // Only what is safe to call after fork, 0 is the writing process.
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+            if written != 1 {
+                return Err(error);
+            }
+            Ok(())
+        });
+    }
+}
+
+/// The processes in the cgroup of unit.
+pub fn procs(unit: &str) -> Vec<u32> {
+    path(unit).map(|path| read_procs(&path)).unwrap_or_default()
+}
+
+/// The processes in the cgroup at path.
+fn read_procs(path: &Path) -> Vec<u32> {
+    fs::read_to_string(path.join("cgroup.procs"))
+        .map(|procs| procs.lines().filter_map(|pid| pid.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether there are processes left in the cgroup of unit, or below it.
+pub fn is_populated(unit: &str) -> bool {
+    path(unit).is_some_and(|path| populated(&path))
+}
+
+/// Whether there are processes left in the cgroup at path, or below it.
+fn populated(path: &Path) -> bool {
+    fs::read_to_string(path.join("cgroup.events"))
+        .is_ok_and(|events| events.lines().any(|line| line == "populated 1"))
+}
+
+/// SIGKILL everything in the cgroup of unit at once with `cgroup.kill`.
+/// Returns false if the kernel is too old to have it.
+pub fn kill(unit: &str) -> io::Result<bool> {
+    let path = match path(unit) {
+        Some(path) => path.join("cgroup.kill"),
+        None => return Ok(false),
+    };
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::write(path, "1")?;
+    Ok(true)
+}
+
+/// The unit whose cgroup the process is in, going by /proc/PID/cgroup. The
+/// path in there is relative to the cgroup namespace, so only the last two
+/// parts are compared.
+pub fn unit_of_pid(pid: u32) -> Option<String> {
+    let root = CGROUPS.lock().unwrap().as_ref()?.root.clone();
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    unit_in(&cgroups, &root)
+}
+
+/// The unit whose cgroup the contents of /proc/PID/cgroup point to, for units
+/// with their cgroups under root.
+fn unit_in(cgroups: &str, root: &Path) -> Option<String> {
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let path = Path::new(path);
+    if path.parent()?.file_name()? != root.file_name()? {
+        return None;
+    }
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Enable the controllers resource control directives need for the children
+/// of the cgroup at path, as far as they are available. The others are left
+/// out, so are all of them if the cgroup has processes of its own.
+fn enable_controllers(path: &Path) {
+    let available =
+        fs::read_to_string(path.join("cgroup.controllers")).unwrap_or_default();
+    for controller in wanted_controllers(&available) {
+        let enable = format!("+{}", controller);
+        if let Err(e) = fs::write(path.join("cgroup.subtree_control"), enable) {
+            println!(
+                "Failed to enable the {} controller in {:?}: {}",
+                controller, path, e
+            );
+        }
+    }
+}
+
+/// The controllers out of the available ones that resource control needs.
+fn wanted_controllers(available: &str) -> Vec<&str> {
+    available.split_whitespace().filter(|c| CONTROLLERS.contains(c)).collect()
+}
+
+fn nix_error(e: nix::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A directory of its own for the test, made up like a cgroup.
+    fn fake_cgroup(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("getup-cgroup-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_on_cgroup2() {
+        let hierarchy =
+            fake_cgroup("hierarchy", &[("cgroup.controllers", "cpu pids\n")]);
+        assert!(on_cgroup2(&hierarchy.join("getup.slice")));
+        assert!(!on_cgroup2(&hierarchy.join("getup.slice").join("deeper")));
+        assert!(!on_cgroup2(&env::temp_dir().join("getup.slice")));
+        assert!(!on_cgroup2(Path::new("/")));
+        // Without cgroup v2 units aren't given a cgroup at all.
+        assert!(
+            init(&hierarchy.join("a").join("getup.slice").to_string_lossy()).is_err()
+        );
+        assert!(!hierarchy.join("a").exists());
+        fs::remove_dir_all(&hierarchy).unwrap();
+    }
+
+    #[test]
+    fn test_unit_in() {
+        let root = Path::new("/sys/fs/cgroup/getup.slice");
+        let cases = [
+            ("0::/getup.slice/web.service\n", Some("web.service")),
+            // In a cgroup namespace the path starts wherever it starts.
+            ("0::/system.slice/getup.slice/web.service\n", Some("web.service")),
+            // Hybrid hierarchies list the v1 controllers too.
+            (
+                "12:pids:/user.slice\n1:name=systemd:/user.slice\n0::/getup.slice/db.service\n",
+                Some("db.service"),
+            ),
+            // getupd itself, or processes of the host.
+            ("0::/getup.slice\n", None),
+            ("0::/\n", None),
+            ("0::/user.slice/user-1000.slice/session-2.scope\n", None),
+            ("0::/getup.slice/web.service/nested\n", None),
+            ("0::/other.slice/web.service\n", None),
+            ("12:pids:/getup.slice/web.service\n", None),
+            ("", None),
+        ];
+        for (cgroups, unit) in cases.iter() {
+            assert_eq!(unit_in(cgroups, root).as_deref(), *unit, "{:?}", cgroups);
+        }
+    }
+
+    #[test]
+    fn test_procs_and_populated() {
+        let populated_cgroup = fake_cgroup(
+            "populated",
+            &[
+                ("cgroup.procs", "12\n345\n"),
+                ("cgroup.events", "populated 1\nfrozen 0\n"),
+            ],
+        );
+        assert_eq!(read_procs(&populated_cgroup), vec![12, 345]);
+        assert!(populated(&populated_cgroup));
+
+        // Processes below it in nested cgroups count as well.
+        let nested = fake_cgroup(
+            "nested",
+            &[("cgroup.procs", ""), ("cgroup.events", "populated 1\nfrozen 0\n")],
+        );
+        assert!(read_procs(&nested).is_empty());
+        assert!(populated(&nested));
+
+        let empty = fake_cgroup(
+            "empty",
+            &[("cgroup.procs", ""), ("cgroup.events", "populated 0\nfrozen 0\n")],
+        );
+        assert!(!populated(&empty));
+
+        // A cgroup that is gone has no processes.
+        let gone = empty.join("gone");
+        assert!(read_procs(&gone).is_empty());
+        assert!(!populated(&gone));
+
+        for dir in &[populated_cgroup, nested, empty] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_wanted_controllers() {
+        let cases: [(&str, &[&str]); 4] = [
+            (
+                "cpuset cpu io memory hugetlb pids rdma misc\n",
+                &["cpuset", "cpu", "io", "memory", "pids"],
+            ),
+            ("memory pids\n", &["memory", "pids"]),
+            ("hugetlb rdma\n", &[]),
+            ("", &[]),
+        ];
+        for (available, wanted) in cases.iter() {
+            assert_eq!(&wanted_controllers(available), wanted, "{:?}", available);
+        }
+
+        // Whatever controllers there are get enabled, one by one.
+        let cgroup = fake_cgroup(
+            "controllers",
+            &[
+                ("cgroup.controllers", "memory hugetlb\n"),
+                ("cgroup.subtree_control", ""),
+            ],
+        );
+        enable_controllers(&cgroup);
+        let enabled = fs::read_to_string(cgroup.join("cgroup.subtree_control")).unwrap();
+        assert_eq!(enabled, "+memory");
+        fs::remove_dir_all(&cgroup).unwrap();
+    }
+}
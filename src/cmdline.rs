@@ -0,0 +1,450 @@
+/// cmdline.rs parses command lines in `Exec*=` directives following the rules
+/// in systemd.service(5).
+///
+/// A command line is split into words on whitespace. Words can be quoted with
+/// single or double quotes, C-style escapes are recognized both inside and
+/// outside of quotes and a lone `;` separates multiple commands. Before
+/// running, `${VAR}` is replaced with the value of VAR and a word that is
+/// exactly `$VAR` is replaced with the value of VAR split on whitespace.
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// A single command parsed from an `Exec*=` line, ready to be spawned.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExecCommand {
+    /// Path to the executable.
+    pub path: String,
+    /// Arguments, including argv[0].
+    pub argv: Vec<String>,
+    /// `-`: A failing exit code of the command is ignored.
+    pub ignore_failure: bool,
+    /// `@`: The second word was passed as argv[0] instead of the path.
+    pub custom_argv0: bool,
+    /// `:`: Environment variable substitution is not applied.
+    pub no_env_expand: bool,
+    /// `+`: Run with full privileges, ignoring User= and sandboxing.
+    pub full_privileges: bool,
+    /// `!`: Run as getupd's user rather than User=, but still sandboxed.
+    pub elevated: bool,
+    /// `!!`: Like `!`, but without AmbientCapabilities=.
+    pub elevated_no_ambient: bool,
+}
+
+impl ExecCommand {
+    /// The arguments after argv[0].
+    pub fn args(&self) -> &[String] {
+        &self.argv[1..]
+    }
+}
+
+/// Parse a command line into one or more commands, expanding variables from
+/// env.
+pub fn parse_command_line(
+    line: &str,
+    env: &HashMap<String, String>,
+) -> Result<Vec<ExecCommand>, String> {
+    let mut commands = vec![];
+    let mut words = vec![];
+
+    for word in split_words(line)? {
+        match word {
+            Word::Separator => {
+                if !words.is_empty() {
+                    commands.push(build_command(&words, env)?);
+                    words.clear();
+                }
+            }
+            Word::Text { raw, value } => words.push((raw, value)),
+        }
+    }
+    if !words.is_empty() {
+        commands.push(build_command(&words, env)?);
+    }
+
+    if commands.is_empty() {
+        return Err("command line is empty".to_string());
+    }
+    Ok(commands)
+}
+
+/// Split a line into words following the same quoting and escaping rules,
+/// like `Environment=` lines are.
+pub fn split(line: &str) -> Result<Vec<String>, String> {
+    let words = split_words(line)?;
+    Ok(words
+        .into_iter()
+        .map(|word| match word {
+            Word::Separator => ";".to_string(),
+            Word::Text { value, .. } => value,
+        })
+        .collect())
+}
+
+enum Word {
+    /// An unquoted, unescaped `;`.
+    Separator,
+    /// A word, with the first word's raw text kept to look for prefixes.
+    Text { raw: String, value: String },
+}
+
+/// Split a line into unquoted and unescaped words.
+fn split_words(line: &str) -> Result<Vec<Word>, String> {
+    let mut words = vec![];
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while let Some(c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut raw = String::new();
+        let mut value = String::new();
+        let mut quote: Option<char> = None;
+        let mut plain = true;
+
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (None, c) if c.is_whitespace() => break,
+                (None, '"') | (None, '\'') => {
+                    quote = Some(c);
+                    plain = false;
+                }
+                (Some(q), c) if c == q => quote = None,
+                (_, '\\') => {
+                    plain = false;
+                    value.push(unescape(&mut chars)?);
+                    continue;
+                }
+                (_, c) => value.push(c),
+            }
+            raw.push(c);
+        }
+
+        if let Some(q) = quote {
+            return Err(format!("unterminated quote {} in command line", q));
+        }
+        if plain && value == ";" {
+            words.push(Word::Separator);
+        } else {
+            words.push(Word::Text { raw, value });
+        }
+    }
+
+    Ok(words)
+}
+
+/// Decode a C-style escape sequence, the leading backslash has already been
+/// consumed.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, String> {
+    let c = chars.next().ok_or("trailing backslash in command line")?;
+    let decoded = match c {
+        'a' => '\x07',
+        'b' => '\x08',
+        'f' => '\x0c',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'v' => '\x0b',
+        's' => ' ',
+        '\\' | '"' | '\'' | ';' => c,
+        'x' => from_digits(chars, 2, 16)?,
+        'u' => from_digits(chars, 4, 16)?,
+        'U' => from_digits(chars, 8, 16)?,
+        '0'..='7' => {
+            let rest = from_digits(chars, 2, 8)? as u32;
+            let code = c.to_digit(8).unwrap() * 64 + rest;
+            std::char::from_u32(code).ok_or("invalid octal escape")?
+        }
+        _ => return Err(format!("invalid escape sequence \\{}", c)),
+    };
+    if decoded == '\0' {
+        return Err("escaped NUL byte in command line".to_string());
+    }
+    Ok(decoded)
+}
+
+/// Read exactly count digits in radix and return the character they encode.
+fn from_digits(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    count: usize,
+    radix: u32,
+) -> Result<char, String> {
+    let mut code: u32 = 0;
+    for _ in 0..count {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(radix))
+            .ok_or("truncated escape sequence in command line")?;
+        code = code * radix + digit;
+    }
+    std::char::from_u32(code).ok_or_else(|| format!("invalid character {:#x}", code))
+}
+
+/// Strip the prefixes off the first word and expand variables in the rest to
+/// build a command.
+fn build_command(
+    words: &[(String, String)],
+    env: &HashMap<String, String>,
+) -> Result<ExecCommand, String> {
+    let mut command = ExecCommand {
+        path: String::new(),
+        argv: vec![],
+        ignore_failure: false,
+        custom_argv0: false,
+        no_env_expand: false,
+        full_privileges: false,
+        elevated: false,
+        elevated_no_ambient: false,
+    };
+
+    // Prefixes are only recognized on the raw, unquoted first word.
+    let (raw, value) = &words[0];
+    let mut prefix_len = 0;
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.peek().cloned() {
+        match c {
+            '-' if !command.ignore_failure => command.ignore_failure = true,
+            '@' if !command.custom_argv0 => command.custom_argv0 = true,
+            ':' if !command.no_env_expand => command.no_env_expand = true,
+            '+' | '!' => {
+                if command.full_privileges
+                    || command.elevated
+                    || command.elevated_no_ambient
+                {
+                    return Err("only one of +, ! and !! may be used".to_string());
+                }
+                if c == '+' {
+                    command.full_privileges = true;
+                } else {
+                    chars.next();
+                    prefix_len += 1;
+                    if chars.peek() == Some(&'!') {
+                        command.elevated_no_ambient = true;
+                    } else {
+                        command.elevated = true;
+                        continue;
+                    }
+                }
+            }
+            _ => break,
+        }
+        chars.next();
+        prefix_len += 1;
+    }
+
+    let mut values: Vec<String> = words.iter().map(|(_, value)| value.clone()).collect();
+    values[0] = value.chars().skip(prefix_len).collect();
+    if values[0].is_empty() {
+        values.remove(0);
+    }
+
+    let values = if command.no_env_expand { values } else { expand_argv(values, env) };
+
+    let mut values = values.into_iter();
+    command.path = values.next().ok_or("command line has no executable")?;
+    if command.custom_argv0 {
+        command.argv.push(values.next().ok_or("@ prefix requires an argv[0] word")?);
+    } else {
+        command.argv.push(command.path.clone());
+    }
+    command.argv.extend(values);
+
+    Ok(command)
+}
+
+/// Expand variables in each word: `$VAR` on its own is split on whitespace,
+/// `${VAR}` anywhere is replaced in place.
+fn expand_argv(words: Vec<String>, env: &HashMap<String, String>) -> Vec<String> {
+    let mut argv = vec![];
+    for word in words {
+        if let Some(name) = word.strip_prefix('$') {
+            if is_valid_name(name) {
+                if let Some(value) = env.get(name) {
+                    argv.extend(value.split_whitespace().map(|s| s.to_string()));
+                }
+                continue;
+            }
+        }
+        argv.push(expand_braces(&word, env));
+    }
+    argv
+}
+
+/// Replace `${VAR}` with the value of VAR and `$$` with `$`.
+fn expand_braces(word: &str, env: &HashMap<String, String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = word;
+
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("$$") {
+            expanded.push('$');
+            rest = &rest[2..];
+        } else if let (true, Some(end)) = (rest.starts_with("${"), rest.find('}')) {
+            if let Some(value) = env.get(&rest[2..end]) {
+                expanded.push_str(value);
+            }
+            rest = &rest[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Whether name can be the name of an environment variable.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn argv(line: &str, vars: &[(&str, &str)]) -> Vec<String> {
+        let commands = parse_command_line(line, &env(vars)).unwrap();
+        assert_eq!(commands.len(), 1);
+        commands[0].argv.clone()
+    }
+
+    #[test]
+    fn test_simple_split() {
+        assert_eq!(
+            argv("/usr/bin/python3 -m http.server", &[]),
+            vec!["/usr/bin/python3", "-m", "http.server"]
+        );
+    }
+
+    #[test]
+    fn test_quoting() {
+        // ExecStart=/bin/sh -c 'dmesg | tac'
+        assert_eq!(
+            argv("/bin/sh -c 'dmesg | tac'", &[]),
+            vec!["/bin/sh", "-c", "dmesg | tac"]
+        );
+        assert_eq!(
+            argv(r#"/bin/echo "two two" a"b c"d"#, &[]),
+            vec!["/bin/echo", "two two", "ab cd"]
+        );
+        assert_eq!(argv(r#"/bin/echo "it's""#, &[]), vec!["/bin/echo", "it's"]);
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            argv(r#"/bin/echo a\sb "\t\x41\101é" \"q\""#, &[]),
+            vec!["/bin/echo", "a b", "\tAAé", "\"q\""]
+        );
+        assert!(parse_command_line(r"/bin/echo \q", &env(&[])).is_err());
+        assert!(parse_command_line(r"/bin/echo \x4", &env(&[])).is_err());
+        assert!(parse_command_line(r"/bin/echo \x00", &env(&[])).is_err());
+        assert!(parse_command_line("/bin/echo 'open", &env(&[])).is_err());
+    }
+
+    #[test]
+    fn test_multiple_commands() {
+        // ExecStart=/bin/echo one ; /bin/echo "two two"
+        let commands =
+            parse_command_line(r#"/bin/echo one ; /bin/echo "two two""#, &env(&[]))
+                .unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].argv, vec!["/bin/echo", "one"]);
+        assert_eq!(commands[1].argv, vec!["/bin/echo", "two two"]);
+    }
+
+    #[test]
+    fn test_escaped_semicolon() {
+        // ExecStart=/bin/echo / >/dev/null & \; \
+        // ls
+        // The unit file parser joins the continuation line with a space.
+        assert_eq!(
+            argv(r"/bin/echo / >/dev/null & \; ls", &[]),
+            vec!["/bin/echo", "/", ">/dev/null", "&", ";", "ls"]
+        );
+        assert_eq!(argv(r#"/bin/echo ";""#, &[]), vec!["/bin/echo", ";"]);
+    }
+
+    #[test]
+    fn test_variable_expansion() {
+        // Environment="ONE=one" 'TWO=two two'
+        // ExecStart=echo $ONE $TWO ${TWO}
+        let vars = [("ONE", "one"), ("TWO", "two two")];
+        assert_eq!(
+            argv("echo $ONE $TWO ${TWO}", &vars),
+            vec!["echo", "one", "two", "two", "two two"]
+        );
+
+        // Environment=ONE='one' "TWO='two two' too" THREE=
+        // ExecStart=/bin/echo ${ONE} ${TWO} ${THREE}
+        // ExecStart=/bin/echo $ONE $TWO $THREE
+        let vars = [("ONE", "one"), ("TWO", "'two two' too"), ("THREE", "")];
+        assert_eq!(
+            argv("/bin/echo ${ONE} ${TWO} ${THREE}", &vars),
+            vec!["/bin/echo", "one", "'two two' too", ""]
+        );
+        assert_eq!(
+            argv("/bin/echo $ONE $TWO $THREE", &vars),
+            vec!["/bin/echo", "one", "'two", "two'", "too"]
+        );
+    }
+
+    #[test]
+    fn test_variable_edge_cases() {
+        let vars = [("FOO", "foo")];
+        assert_eq!(
+            argv("/bin/echo x${FOO}y $$FOO $FOO-bar $UNSET ${UNSET}", &vars),
+            vec!["/bin/echo", "xfooy", "$FOO", "$FOO-bar", ""]
+        );
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let vars = env(&[("FOO", "foo")]);
+        let command = &parse_command_line("-/bin/false", &vars).unwrap()[0];
+        assert!(command.ignore_failure);
+        assert_eq!(command.path, "/bin/false");
+
+        let command = &parse_command_line("@/bin/sleep sleeper 10", &vars).unwrap()[0];
+        assert!(command.custom_argv0);
+        assert_eq!(command.path, "/bin/sleep");
+        assert_eq!(command.argv, vec!["sleeper", "10"]);
+
+        let command = &parse_command_line(":/bin/echo $FOO", &vars).unwrap()[0];
+        assert!(command.no_env_expand);
+        assert_eq!(command.argv, vec!["/bin/echo", "$FOO"]);
+
+        let command = &parse_command_line("+-/bin/true", &vars).unwrap()[0];
+        assert!(command.full_privileges && command.ignore_failure);
+
+        let command = &parse_command_line("!/bin/true", &vars).unwrap()[0];
+        assert!(command.elevated && !command.elevated_no_ambient);
+
+        let command = &parse_command_line("!!/bin/true", &vars).unwrap()[0];
+        assert!(command.elevated_no_ambient && !command.elevated);
+
+        assert!(parse_command_line("+!/bin/true", &vars).is_err());
+        assert!(parse_command_line("@/bin/true", &vars).is_err());
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(parse_command_line("", &env(&[])).is_err());
+        assert!(parse_command_line(" ; ", &env(&[])).is_err());
+    }
+}
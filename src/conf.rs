@@ -9,7 +9,37 @@ pub struct Settings {
     pub pidfile: String,
     pub workdir: String,
     pub services_path: String,
+    /// Path of the socket the API is served on.
+    pub api_socket: String,
+    /// Policy file allowing users other than root to act through the API.
+    pub api_policy: String,
+    /// Also serve the API over TCP, on localhost only.
+    #[serde(default)]
+    pub tcp: bool,
+    /// Port of the TCP API.
     pub port: u32,
+    /// Path of the socket services send sd_notify messages to.
+    pub notify_socket: String,
+    /// Directory the output of services is stored in.
+    pub log_dir: String,
+    /// Size in bytes a log file may grow to before it is rotated.
+    pub log_max_size: u64,
+    /// How many rotated log files are kept for each unit.
+    pub log_max_files: usize,
+    /// Take in what services send to syslog.
+    #[serde(default)]
+    pub syslog: bool,
+    /// Path of the syslog socket.
+    pub syslog_socket: String,
+    /// cgroup v2 directory the cgroups of units are created in.
+    pub cgroup_root: String,
+    /// First and last UID handed out to services with DynamicUser=.
+    pub dynamic_uid_min: u32,
+    pub dynamic_uid_max: u32,
+    /// Environment every service starts out with, as `NAME=value`.
+    pub default_environment: Vec<String>,
+    /// Directory the sandboxes of services are prepared in.
+    pub sandbox_dir: String,
 }
 
 impl Settings {
@@ -1,7 +1,31 @@
-use crate::units::{Unit, ALL_UNITS};
+use crate::cgroup;
+use crate::cmdline::ExecCommand;
+use crate::credentials;
+use crate::deps::DepGraph;
+use crate::events::{self, EventKind};
+use crate::jobs::JobError;
+use crate::monitor;
+use crate::signals::{self, Message};
+use crate::transaction::{Job, JobType, Transaction};
+use crate::unitfile::UnitParseError;
+use crate::units::{CurrState, Service, Unit, ALL_UNITS};
+use futures::future::{join_all, BoxFuture, FutureExt, Shared};
+use lazy_static::lazy_static;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{sync, Pid};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time;
+use tokio::sync::broadcast;
+use tokio::time::delay_for;
+
+lazy_static! {
+    /// Directory the unit files were loaded from.
+    static ref SERVICES_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
 
 pub fn initialize(path: &str) {
     load_all_services(path);
@@ -19,31 +43,1013 @@ pub fn load_all_services(path: &str) {
         process::exit(1);
     }
 
-    let all_services = services_path
-        .read_dir()
-        .expect("read_dir call failed")
-        .filter(|entry| {
-            entry
-                .as_ref()
-                .expect("Failed to check if the path is a file")
-                .path()
-                .is_file()
-        })
-        .filter(|entry| {
-            entry
-                .as_ref()
-                .expect("Failed to check if the path has service extension")
-                .path()
-                .extension()
-                == Some(OsStr::new("service"))
+    let (units, failed) = read_unit_files(services_path);
+    let mut all_units = ALL_UNITS.lock().expect("Failed to lock all units");
+    for unit in units {
+        events::publish(EventKind::UnitLoaded { unit: unit.name.clone() });
+        all_units.add_unit(unit);
+    }
+    for e in failed {
+        all_units.add_failed(e);
+    }
+    *SERVICES_PATH.lock().unwrap() = Some(services_path.to_path_buf());
+}
+
+/// Read the unit files in the directory at path, along with the errors of
+/// those which could not be read.
+fn read_unit_files(services_path: &Path) -> (Vec<Unit>, Vec<UnitParseError>) {
+    let mut units = vec![];
+    let mut failed = vec![];
+    let entries = match services_path.read_dir() {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Failed to read {}: {}", services_path.display(), e);
+            return (units, failed);
+        }
+    };
+    let all_services = entries.flatten().filter(|entry| {
+        let path = entry.path();
+        path.is_file() && path.extension() == Some(OsStr::new("service"))
+    });
+
+    for an_entry in all_services {
+        println!("Loading {:?}...", an_entry);
+
+        // A broken unit file should not take down the whole daemon, so we
+        // skip it and keep the error around to report it through the API.
+        match Unit::from_unitfile(an_entry.path().as_path()) {
+            Ok(unit) => units.push(unit),
+            Err(e) => {
+                println!("Failed to load unit: {}", e);
+                failed.push(e);
+            }
+        }
+    }
+    (units, failed)
+}
+
+/// Read the unit files again, picking up new, changed and removed ones.
+pub fn reload_units() {
+    let path = match SERVICES_PATH.lock().unwrap().clone() {
+        Some(path) => path,
+        None => return,
+    };
+    println!("Reloading unit files from {}", path.display());
+    let (units, failed) = read_unit_files(&path);
+    ALL_UNITS.lock().unwrap().reload(units, failed);
+    events::publish(EventKind::Reloaded);
+}
+
+/// Start all the loaded units, as many of them at the same time as their
+/// ordering dependencies allow. A unit that can't be started doesn't keep the
+/// others from starting.
+pub async fn boot() -> Result<(), JobError> {
+    let transaction = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        let names: Vec<&str> = all_units.iter().map(|unit| unit.name.as_str()).collect();
+        Transaction::start_wanted(&all_units.dep_graph(), &names)
+            .map_err(|e| e.to_string())?
+    };
+    run_transaction(&transaction).await
+}
+
+/// Start the unit along with everything it needs, in dependency order.
+pub async fn start_unit(name: &str) -> Result<(), JobError> {
+    let transaction = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        Transaction::start(&all_units.dep_graph(), &[name]).map_err(|e| e.to_string())?
+    };
+    run_transaction(&transaction).await
+}
+
+/// Stop the unit along with every unit that requires it.
+pub async fn stop_unit(name: &str) -> Result<(), JobError> {
+    let transaction = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        Transaction::stop(&all_units.dep_graph(), &[name])
+    };
+    run_transaction(&transaction).await
+}
+
+/// Stop the unit and everything that requires it, then start them again.
+pub async fn restart_unit(name: &str) -> Result<(), JobError> {
+    let (stop, names) = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        if all_units.get_by_name(name).is_none() {
+            return Err(JobError::failed(&format!("unit {} not found", name)));
+        }
+        let mut stop = Transaction::stop(&all_units.dep_graph(), &[name]);
+        stop.restart = true;
+        let names: Vec<String> = stop.jobs.iter().map(|job| job.unit.clone()).collect();
+        (stop, names)
+    };
+    run_transaction(&stop).await?;
+
+    let start = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        Transaction::start(&all_units.dep_graph(), &names).map_err(|e| e.to_string())?
+    };
+    run_transaction(&start).await
+}
+
+/// Have a running unit reload its configuration with its ExecReload=
+/// commands, which run one after the other.
+pub async fn reload_unit(name: &str) -> Result<(), JobError> {
+    let service = get_service(name)
+        .ok_or_else(|| JobError::failed(&format!("unit {} not found", name)))?;
+    let commands = {
+        let mut unlocked_service = service.lock().unwrap();
+        if unlocked_service.status() != CurrState::Running {
+            return Err(JobError::failed(&format!("{} is not active", name)));
+        }
+        let commands = unlocked_service.reload_commands()?;
+        unlocked_service.set_state(CurrState::Reloading);
+        commands
+    };
+
+    let mut result = Ok(());
+    for exec in commands {
+        if let Err(e) = run_command(&service, &exec, None).await {
+            result = Err(JobError::failed(&e));
+            break;
+        }
+    }
+
+    // The service keeps running even if reloading failed.
+    let mut unlocked_service = service.lock().unwrap();
+    if unlocked_service.status() == CurrState::Reloading {
+        unlocked_service.set_state(CurrState::Running);
+    }
+    result
+}
+
+/// Send a signal to the processes of the unit.
+pub fn kill_unit(name: &str, signal: Signal) -> Result<(), JobError> {
+    let service = get_service(name)
+        .ok_or_else(|| JobError::failed(&format!("unit {} not found", name)))?;
+    let result = service.lock().unwrap().signal(signal);
+    result.map_err(JobError::from)
+}
+
+/// Run one of the commands of a service alongside its main process and wait
+/// for it to exit, killing it if it takes longer than timeout.
+async fn run_command(
+    service: &Arc<Mutex<Service>>,
+    exec: &ExecCommand,
+    timeout: Option<time::Duration>,
+) -> Result<(), String> {
+    let spawned = {
+        let unlocked_service = service.lock().unwrap();
+        unlocked_service.command(exec).and_then(|mut cmd| monitor::spawn(&mut cmd)).map(
+            |(mut child, exited)| {
+                unlocked_service.capture_output(&mut child);
+                (child.id(), exited)
+            },
+        )
+    };
+    let (pid, exited) =
+        spawned.map_err(|e| format!("failed to run {}: {}", exec.path, e))?;
+    let status = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, exited).await {
+            Ok(status) => status,
+            Err(_) => {
+                let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                return Err(format!("{} timed out", exec.path));
+            }
+        },
+        None => exited.await,
+    };
+    match status {
+        Ok(status) if status.success() || exec.ignore_failure => Ok(()),
+        Ok(status) => Err(format!("{} failed with {}", exec.path, status)),
+        Err(_) => Err(format!("lost {}", exec.path)),
+    }
+}
+
+/// Run the commands one after the other, until one fails or the time is up.
+async fn run_commands(
+    name: &str,
+    service: &Arc<Mutex<Service>>,
+    commands: Result<Vec<ExecCommand>, String>,
+    deadline: Option<time::Instant>,
+) {
+    let commands = match commands {
+        Ok(commands) => commands,
+        Err(e) => {
+            println!("Invalid command for {}: {}", name, e);
+            return;
+        }
+    };
+    for exec in commands {
+        let left = deadline
+            .map(|deadline| deadline.saturating_duration_since(time::Instant::now()));
+        if let Err(e) = run_command(service, &exec, left).await {
+            println!("Failed to stop {}: {}", name, e);
+            return;
+        }
+    }
+}
+
+type JobFuture = Shared<BoxFuture<'static, Result<(), JobError>>>;
+
+/// Run the jobs of a transaction concurrently. Each job only waits for the
+/// jobs it is ordered after, so independent chains of units don't hold each
+/// other up. A failing job which is required fails the transaction.
+pub async fn run_transaction(transaction: &Transaction) -> Result<(), JobError> {
+    let graph = ALL_UNITS.lock().unwrap().dep_graph();
+    let mut results: HashMap<String, JobFuture> = HashMap::new();
+
+    // The units left out for lack of a dependency failed, unless they are
+    // already running.
+    for (name, reason) in &transaction.pruned {
+        let active = ALL_UNITS.lock().unwrap().get_by_name(name).map(Unit::is_active);
+        if active == Some(false) {
+            fail_dependency(name, reason);
+        }
+    }
+
+    // Jobs are sorted, so whatever a job waits on has already been set up.
+    for job in &transaction.jobs {
+        let waits: Vec<(String, JobFuture)> = waits_for(&graph, transaction, job)
+            .into_iter()
+            .filter_map(|name| results.get(&name).map(|result| (name, result.clone())))
+            .collect();
+        let required = required_by(&graph, &job.unit);
+        let name = job.unit.clone();
+        let job = job.clone();
+        let restart = transaction.restart;
+        let result = async move {
+            for (other, result) in waits {
+                if let Err(e) = result.await {
+                    if required.contains(&other) {
+                        let reason = format!("dependency {} failed: {}", other, e);
+                        fail_dependency(&job.unit, &reason);
+                        return Err(JobError::dependency(&reason));
+                    }
+                }
+            }
+            run_job(&job, restart).await
+        };
+        results.insert(name, result.boxed().shared());
+    }
+
+    // The outcome is that of the units asked for, a failing unit they pulled
+    // in makes it a dependency failure.
+    let mut outcome = Ok(());
+    let mut dependency = None;
+    let finished =
+        join_all(transaction.jobs.iter().map(|job| results[&job.unit].clone())).await;
+    for (job, result) in transaction.jobs.iter().zip(finished.iter()) {
+        let anchor =
+            transaction.anchors.is_empty() || transaction.anchors.contains(&job.unit);
+        match result {
+            Ok(()) => {}
+            Err(e) if job.required && anchor && outcome.is_ok() => {
+                outcome = Err(e.clone())
+            }
+            Err(e) if job.required && dependency.is_none() => {
+                dependency = Some(JobError::dependency(&format!(
+                    "dependency {} failed: {}",
+                    job.unit, e
+                )));
+            }
+            Err(e) => println!("Job for {} failed: {}", job.unit, e),
+        }
+    }
+    if let (Ok(()), Some(e)) = (&outcome, dependency) {
+        outcome = Err(e);
+    }
+
+    // A unit whose required dependency wasn't ordered before it was started
+    // regardless, it has to go now that the dependency failed.
+    for (job, result) in transaction.jobs.iter().zip(finished.iter()) {
+        if job.job_type != JobType::Start || result.is_err() {
+            continue;
+        }
+        let failed = required_by(&graph, &job.unit).into_iter().find(|other| {
+            transaction
+                .jobs
+                .iter()
+                .zip(finished.iter())
+                .any(|(j, r)| &j.unit == other && r.is_err())
         });
+        if let Some(other) = failed {
+            let reason = format!("dependency {} failed", other);
+            println!("Stopping {}: {}", job.unit, reason);
+            if let Some(service) = get_service(&job.unit) {
+                stop_service(&job.unit, &service, false).await;
+                service.lock().unwrap().fail(&reason);
+            }
+        }
+    }
+
+    outcome
+}
+
+/// Names of the jobs in the transaction that job has to wait for.
+fn waits_for(graph: &DepGraph, transaction: &Transaction, job: &Job) -> Vec<String> {
+    let in_transaction = |name: &str, job_type: JobType| -> bool {
+        transaction.jobs.iter().any(|j| j.unit == name && j.job_type == job_type)
+    };
+    match job.job_type {
+        // Conflicting units are stopped and requisites checked before
+        // anything starts, then units wait for what they are ordered after.
+        JobType::Start => transaction
+            .jobs
+            .iter()
+            .filter(|j| j.job_type != JobType::Start)
+            .map(|j| j.unit.clone())
+            .chain(
+                graph
+                    .after(&job.unit)
+                    .iter()
+                    .filter(|name| in_transaction(name, JobType::Start))
+                    .cloned(),
+            )
+            .collect(),
+        // Units are stopped in reverse, after the ones ordered after them.
+        JobType::Stop => transaction
+            .jobs
+            .iter()
+            .filter(|j| {
+                j.job_type == JobType::Stop && graph.after(&j.unit).contains(&job.unit)
+            })
+            .map(|j| j.unit.clone())
+            .collect(),
+        JobType::VerifyActive => vec![],
+    }
+}
+
+/// Units the unit can't run without, which fail it when they fail.
+fn required_by(graph: &DepGraph, name: &str) -> Vec<String> {
+    match graph.get(name) {
+        Some(deps) => deps
+            .requires
+            .iter()
+            .chain(deps.binds_to.iter())
+            .chain(deps.requisite.iter())
+            .cloned()
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn fail_dependency(name: &str, reason: &str) {
+    println!("Not starting {}: {}", name, reason);
+    if let Some(service) = get_service(name) {
+        service.lock().unwrap().fail(reason);
+    }
+}
+
+fn get_service(name: &str) -> Option<Arc<Mutex<Service>>> {
+    ALL_UNITS.lock().unwrap().get_by_name(name).map(|unit| unit.service.clone())
+}
+
+async fn run_job(job: &Job, restart: bool) -> Result<(), JobError> {
+    let service = match get_service(&job.unit) {
+        Some(service) => service,
+        None => return Err(JobError::failed(&format!("unit {} not found", job.unit))),
+    };
+    match job.job_type {
+        JobType::Start => run_start_job(&job.unit, &service).await,
+        JobType::Stop => {
+            stop_service(&job.unit, &service, restart).await;
+            Ok(())
+        }
+        JobType::VerifyActive => match service.lock().unwrap().status() {
+            CurrState::Running => Ok(()),
+            state => Err(JobError::failed(&format!(
+                "requisite {} is not active ({:?})",
+                job.unit, state
+            ))),
+        },
+    }
+}
+
+/// Start a service and wait for it to finish starting up, failing it if that
+/// takes longer than TimeoutStartSec=.
+async fn run_start_job(
+    name: &str,
+    service: &Arc<Mutex<Service>>,
+) -> Result<(), JobError> {
+    // Everything that changes the state of the service publishes it, so
+    // subscribe before starting it to not miss any of it.
+    let (mut changes, expire) = {
+        let mut unlocked_service = service.lock().unwrap();
+        if let CurrState::Running | CurrState::Starting | CurrState::Reloading =
+            unlocked_service.status()
+        {
+            return Ok(());
+        }
+        let (_, changes) = events::subscribe(None);
+        println!("Starting {}...", name);
+        unlocked_service.start();
+        let expire =
+            unlocked_service.timeout_start.map(|timeout| time::Instant::now() + timeout);
+        (changes, expire)
+    };
+
+    loop {
+        let time_left = {
+            let mut unlocked_service = service.lock().unwrap();
+            match unlocked_service.status() {
+                CurrState::Starting => match expire {
+                    None => None,
+                    Some(expire) => match unlocked_service.time_left(expire) {
+                        Some(left) => Some(left),
+                        None => {
+                            unlocked_service.fail("timeout");
+                            return Err(JobError::timeout(&format!(
+                                "{} timed out starting",
+                                name
+                            )));
+                        }
+                    },
+                },
+                CurrState::Running => return Ok(()),
+                // A oneshot service without RemainAfterExit= is done once it
+                // exited successfully.
+                CurrState::Stopped
+                    if unlocked_service.exit_status.is_some_and(|s| s.success()) =>
+                {
+                    return Ok(())
+                }
+                state => {
+                    let reason = match &unlocked_service.failure_reason {
+                        Some(reason) => format!("{:?}, {}", state, reason),
+                        None => format!("{:?}", state),
+                    };
+                    return Err(JobError::failed(&format!(
+                        "{} failed to start ({})",
+                        name, reason
+                    )));
+                }
+            }
+        };
+        // Any event will do, missed ones included, the state is checked again
+        // either way.
+        match time_left {
+            Some(left) => {
+                let _ = tokio::time::timeout(left, changes.recv()).await;
+            }
+            None => {
+                let _ = changes.recv().await;
+            }
+        }
+    }
+}
+
+/// Stop a service the way its unit says: run ExecStop=, send KillSignal= to
+/// the processes KillMode= says and FinalKillSignal= to whatever is left after
+/// TimeoutStopSec=, then run ExecStopPost=. RestartKillSignal= is sent instead
+/// when the service is to be started again.
+pub async fn stop_service(name: &str, service: &Arc<Mutex<Service>>, restarting: bool) {
+    println!("Stopping {}...", name);
+    let (commands, timeout) = {
+        let mut unlocked_service = service.lock().unwrap();
+        if !unlocked_service.begin_stop() {
+            return;
+        }
+        (unlocked_service.stop_commands(), unlocked_service.timeout_stop)
+    };
+    let deadline = |timeout: Option<time::Duration>| {
+        timeout.map(|timeout| time::Instant::now() + timeout)
+    };
+
+    let stop_deadline = deadline(timeout);
+    run_commands(name, service, commands, stop_deadline).await;
+    service.lock().unwrap().send_kill_signal(restarting);
+    if !wait_stopped(service, stop_deadline).await {
+        println!("{} did not stop in time", name);
+        let sent = service.lock().unwrap().send_final_signal();
+        if sent && !wait_stopped(service, deadline(timeout)).await {
+            println!("{} is still around, giving up on it", name);
+        }
+    }
+
+    let commands = service.lock().unwrap().stop_post_commands();
+    run_commands(name, service, commands, deadline(timeout)).await;
+    service.lock().unwrap().finish_stop();
+}
+
+/// Wait for the processes of a service being stopped to go away, returns
+/// whether they did before the deadline.
+async fn wait_stopped(
+    service: &Arc<Mutex<Service>>,
+    deadline: Option<time::Instant>,
+) -> bool {
+    // A cgroup running empty is the last thing to go, so check right away
+    // rather than at the next poll.
+    let mut emptied = cgroup::subscribe();
+    loop {
+        if service.lock().unwrap().is_stopped() {
+            return true;
+        }
+        if deadline.is_some_and(|deadline| time::Instant::now() > deadline) {
+            return false;
+        }
+        let _ =
+            tokio::time::timeout(time::Duration::from_millis(50), emptied.recv()).await;
+    }
+}
+
+/// Take care of units whose main process exited on its own, for as long as
+/// getupd runs: run their ExecStopPost= and start them again once RestartSec=
+/// passed, if their Restart= says so. They are started through the job queue
+/// like any other unit.
+pub async fn handle_exits() {
+    let mut exits = monitor::subscribe();
+    loop {
+        let names = match exits.recv().await {
+            Ok((name, _)) => vec![name],
+            // Any of the units waiting to restart may be one we missed.
+            Err(broadcast::RecvError::Lagged(_)) => ALL_UNITS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|unit| {
+                    unit.service.lock().unwrap().status() == CurrState::Restarting
+                })
+                .map(|unit| unit.name.clone())
+                .collect(),
+            Err(broadcast::RecvError::Closed) => return,
+        };
+        for name in names {
+            tokio::spawn(after_exit(name));
+        }
+    }
+}
+
+async fn after_exit(name: String) {
+    let service = match get_service(&name) {
+        Some(service) => service,
+        None => return,
+    };
+    let post = {
+        let mut unlocked_service = service.lock().unwrap();
+        if unlocked_service.take_stop_post() {
+            let timeout = unlocked_service.timeout_stop;
+            Some((unlocked_service.stop_post_commands(), timeout))
+        } else {
+            None
+        }
+    };
+    if let Some((commands, timeout)) = post {
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        run_commands(&name, &service, commands, deadline).await;
+        let unlocked_service = service.lock().unwrap();
+        if let CurrState::Stopped | CurrState::Failed = unlocked_service.status() {
+            credentials::release(&name);
+            unlocked_service.sandbox.cleanup(&name);
+        }
+    }
+
+    let delay = {
+        let mut unlocked_service = service.lock().unwrap();
+        if unlocked_service.status() != CurrState::Restarting {
+            return;
+        }
+        unlocked_service.next_restart_delay()
+    };
+    println!("Restarting {} in {:?}", name, delay);
+    delay_for(delay).await;
+    // Unless it was stopped or started in the meantime.
+    if service.lock().unwrap().status() == CurrState::Restarting {
+        let _ = signals::signal_daemon(Message::Start(name));
+    }
+}
+
+/// Stop all the active units, in the reverse of the order they are started.
+pub async fn stop_all() {
+    let transaction = {
+        let all_units = ALL_UNITS.lock().unwrap();
+        let active: Vec<&str> = all_units
+            .iter()
+            .filter(|unit| unit.service.lock().unwrap().status() != CurrState::Stopped)
+            .map(|unit| unit.name.as_str())
+            .collect();
+        Transaction::stop(&all_units.dep_graph(), &active)
+    };
+    if let Err(e) = run_transaction(&transaction).await {
+        println!("Failed to stop all units: {}", e);
+    }
+}
+
+/// Processes of units that are still around.
+fn remaining_processes() -> Vec<Pid> {
+    let all_units = ALL_UNITS.lock().unwrap();
+    all_units
+        .iter()
+        .flat_map(|unit| {
+            let service = unit.service.lock().unwrap();
+            vec![service.child_pid(), service.main_pid]
+        })
+        .flatten()
+        .map(|pid| Pid::from_raw(pid as i32))
+        .filter(|pid| kill(*pid, None).is_ok())
+        .collect()
+}
+
+/// Whether there are processes left other than ourselves. As PID 1 every
+/// other process in our PID namespace descends from one of our children, or
+/// became our child when its parent exited, so they are gone once we have no
+/// children left. /proc may well be that of another namespace. The children
+/// that exited are reaped along the way.
+fn other_processes_left() -> bool {
+    monitor::reap_all()
+}
+
+/// Send SIGTERM to whatever is left, then SIGKILL to whatever is still left
+/// after the timeout. As PID 1 this is every process on the system, otherwise
+/// only the processes of our units.
+pub async fn kill_remaining(everything: bool, timeout: time::Duration) {
+    let send = |signal: Signal| {
+        if everything {
+            // -1 is every process we are allowed to signal, except ourselves.
+            let _ = kill(Pid::from_raw(-1), signal);
+        } else {
+            for pid in remaining_processes() {
+                let _ = kill(pid, signal);
+            }
+        }
+    };
+    let any_left = || {
+        if everything {
+            other_processes_left()
+        } else {
+            !remaining_processes().is_empty()
+        }
+    };
+
+    if !any_left() {
+        return;
+    }
+    println!("Sending SIGTERM to remaining processes");
+    send(Signal::SIGTERM);
+    let started = time::Instant::now();
+    while any_left() {
+        if started.elapsed() > timeout {
+            println!("Sending SIGKILL to remaining processes");
+            send(Signal::SIGKILL);
+            break;
+        }
+        delay_for(time::Duration::from_millis(100)).await;
+    }
+}
+
+/// Bring everything down before exiting or rebooting: stop the units in order,
+/// unless this is an immediate shutdown, then kill whatever is left and sync
+/// the file systems.
+pub async fn shutdown(everything: bool, immediate: bool) {
+    if !immediate {
+        stop_all().await;
+    }
+    kill_remaining(everything, time::Duration::from_secs(10)).await;
+    println!("Syncing file systems");
+    sync();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobResult;
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    lazy_static! {
+        /// The tests share the loaded units, so they take turns.
+        static ref LOADED: Mutex<()> = Mutex::new(());
+    }
+
+    /// Load units from a directory of their own, each of them running
+    /// `sleep` until it is stopped.
+    fn load(test: &str, units: &[(&str, &str)]) {
+        let files: Vec<(&str, String)> = units
+            .iter()
+            .map(|(name, unit)| {
+                (
+                    *name,
+                    format!("[Unit]\n{}\n[Service]\nExecStart=/bin/sleep 30\n", unit),
+                )
+            })
+            .collect();
+        load_files(test, &files);
+    }
+
+    /// Load services with the given [Service] sections.
+    fn load_services(test: &str, services: &[(&str, String)]) {
+        let files: Vec<(&str, String)> = services
+            .iter()
+            .map(|(name, service)| (*name, format!("[Service]\n{}\n", service)))
+            .collect();
+        load_files(test, &files);
+    }
+
+    fn load_files(test: &str, files: &[(&str, String)]) {
+        let dir = std::env::temp_dir().join(format!("getup-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        let (units, failed) = read_unit_files(&dir);
+        assert!(failed.is_empty());
+        ALL_UNITS.lock().unwrap().reload(units, failed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn status(name: &str) -> CurrState {
+        get_service(name).unwrap().lock().unwrap().status()
+    }
+
+    fn reason(name: &str) -> Option<String> {
+        get_service(name).unwrap().lock().unwrap().failure_reason.clone()
+    }
+
+    #[test]
+    fn test_boot_broken_requires() {
+        let _loaded = LOADED.lock().unwrap();
+        load(
+            "boot-requires",
+            &[
+                ("ok.service", "Wants=missing.service"),
+                ("broken.service", "Requires=missing.service"),
+                ("needs-broken.service", "Requires=broken.service"),
+            ],
+        );
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(boot()).unwrap();
+        assert_eq!(status("ok.service"), CurrState::Running);
+        assert_eq!(status("broken.service"), CurrState::Failed);
+        assert_eq!(
+            reason("broken.service").as_deref(),
+            Some("dependency missing.service not found")
+        );
+        assert_eq!(status("needs-broken.service"), CurrState::Failed);
+        assert_eq!(
+            reason("needs-broken.service").as_deref(),
+            Some(
+                "dependency broken.service failed: dependency missing.service not found"
+            )
+        );
+
+        runtime.block_on(stop_all());
+        assert_eq!(status("ok.service"), CurrState::Stopped);
+    }
+
+    #[test]
+    fn test_boot_cycle() {
+        let _loaded = LOADED.lock().unwrap();
+        load(
+            "boot-cycle",
+            &[
+                ("first.service", "Requires=second.service\nAfter=second.service"),
+                ("second.service", "Requires=first.service\nAfter=first.service"),
+                ("after.service", "After=first.service"),
+                ("wanted.service", "Wants=loop.service\nBefore=loop.service"),
+                ("loop.service", "Before=wanted.service"),
+            ],
+        );
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(boot()).unwrap();
+        // Breaking the cycle leaves out one of the units, so the other one,
+        // which requires it, fails.
+        assert_eq!(status("first.service"), CurrState::Stopped);
+        assert_eq!(status("second.service"), CurrState::Failed);
+        assert_eq!(
+            reason("second.service").as_deref(),
+            Some("dependency first.service can't be started")
+        );
+        assert_eq!(status("after.service"), CurrState::Running);
+        let started = [status("wanted.service"), status("loop.service")];
+        assert!(started.contains(&CurrState::Running));
+        assert!(started.contains(&CurrState::Stopped));
+
+        runtime.block_on(stop_all());
+        assert_eq!(status("after.service"), CurrState::Stopped);
+    }
+
+    #[test]
+    fn test_requisite_inactive() {
+        let _loaded = LOADED.lock().unwrap();
+        load(
+            "requisite",
+            &[
+                ("needy.service", "Requisite=base.service\nAfter=base.service"),
+                ("base.service", ""),
+            ],
+        );
+        let mut runtime = Runtime::new().unwrap();
+        // The requisite isn't started along with it.
+        let e = runtime.block_on(start_unit("needy.service")).unwrap_err();
+        assert_eq!(e.result, JobResult::Dependency);
+        assert_eq!(status("needy.service"), CurrState::Failed);
+        assert_eq!(status("base.service"), CurrState::Stopped);
+
+        runtime.block_on(start_unit("base.service")).unwrap();
+        runtime.block_on(start_unit("needy.service")).unwrap();
+        assert_eq!(status("needy.service"), CurrState::Running);
+
+        runtime.block_on(stop_all());
+        assert_eq!(status("needy.service"), CurrState::Stopped);
+        assert_eq!(status("base.service"), CurrState::Stopped);
+    }
+
+    #[test]
+    fn test_start_timeout() {
+        let _loaded = LOADED.lock().unwrap();
+        load_services(
+            "start-timeout",
+            &[(
+                "silent.service",
+                "Type=notify\nExecStart=/bin/sleep 30\nTimeoutStartSec=1".to_string(),
+            )],
+        );
+        let mut runtime = Runtime::new().unwrap();
+        // It never sends READY=1.
+        let started = time::Instant::now();
+        let e = runtime.block_on(start_unit("silent.service")).unwrap_err();
+        assert_eq!(e.result, JobResult::Timeout);
+        assert!(started.elapsed() >= time::Duration::from_secs(1));
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+        assert_eq!(status("silent.service"), CurrState::Failed);
+        assert_eq!(reason("silent.service").as_deref(), Some("timeout"));
+
+        runtime.block_on(stop_all());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let _loaded = LOADED.lock().unwrap();
+        load(
+            "shutdown",
+            &[
+                ("base.service", ""),
+                ("app.service", "Requires=base.service\nAfter=base.service"),
+                ("other.service", ""),
+            ],
+        );
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(boot()).unwrap();
+        let pids: Vec<Pid> = ["base.service", "app.service", "other.service"]
+            .iter()
+            .map(|name| {
+                assert_eq!(status(name), CurrState::Running);
+                let pid = get_service(name).unwrap().lock().unwrap().main_pid.unwrap();
+                Pid::from_raw(pid as i32)
+            })
+            .collect();
+
+        let started = time::Instant::now();
+        runtime.block_on(shutdown(false, false));
+        // Nothing had to be waited out.
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+        for name in &["base.service", "app.service", "other.service"] {
+            assert_eq!(status(name), CurrState::Stopped);
+        }
+        for pid in pids {
+            assert!(kill(pid, None).is_err(), "{} is still around", pid);
+        }
+        assert!(remaining_processes().is_empty());
+    }
+
+    /// A file the services of a test write what happens to.
+    fn log_path(test: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("getup-{}-{}.log", test, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn log_lines(path: &Path) -> Vec<String> {
+        let log = fs::read_to_string(path).unwrap_or_default();
+        log.lines().map(str::to_string).collect()
+    }
+
+    /// Wait for the service to write that it is ready, once it set itself up.
+    fn wait_ready(path: &Path) {
+        let started = time::Instant::now();
+        while !log_lines(path).contains(&"ready".to_string()) {
+            assert!(started.elapsed() < time::Duration::from_secs(5), "not ready");
+            std::thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    /// Whether the process is around and not a zombie.
+    fn alive(pid: u32) -> bool {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| !stat.rsplit_once(')').unwrap().1.starts_with(" Z"))
+    }
+
+    /// Stop the service like a stop job does, reaping what ExecStop= and
+    /// ExecStopPost= run. Returns how long it took.
+    fn stop(runtime: &mut Runtime, name: &str) -> time::Duration {
+        let service = get_service(name).unwrap();
+        let started = time::Instant::now();
+        let reaping = async {
+            loop {
+                monitor::reap_spawned();
+                delay_for(time::Duration::from_millis(10)).await;
+            }
+        };
+        runtime.block_on(futures::future::select(
+            stop_service(name, &service, false).boxed(),
+            reaping.boxed(),
+        ));
+        started.elapsed()
+    }
+
+    #[test]
+    fn test_stop_sequence() {
+        let _loaded = LOADED.lock().unwrap();
+        let log = log_path("stop-sequence");
+        // The service shrugs off SIGTERM, so it takes SIGKILL once
+        // TimeoutStopSec= is up.
+        let service = format!(
+            "ExecStart=/bin/sh -c 'trap \"echo TERM >> {log}\" TERM; echo ready >> {log}; \
+             while :; do sleep 0.1; done'\n\
+             ExecStop=/bin/sh -c 'echo stop $MAINPID >> {log}'\n\
+             ExecStopPost=/bin/sh -c 'echo post >> {log}'\n\
+             TimeoutStopSec=1",
+            log = log.display()
+        );
+        load_services("stop-sequence", &[("stubborn.service", service)]);
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(start_unit("stubborn.service")).unwrap();
+        wait_ready(&log);
+        let main_pid = get_service("stubborn.service").unwrap().lock().unwrap().main_pid;
+        let main_pid = main_pid.unwrap();
+
+        let took = stop(&mut runtime, "stubborn.service");
+        assert!(took >= time::Duration::from_secs(1), "{:?}", took);
+        assert!(took < time::Duration::from_secs(4), "{:?}", took);
+        // ExecStop= ran first, while the main process was still around.
+        let expected = vec![
+            "ready".to_string(),
+            format!("stop {}", main_pid),
+            "TERM".into(),
+            "post".into(),
+        ];
+        assert_eq!(log_lines(&log), expected);
+        assert!(!alive(main_pid));
+        assert_eq!(status("stubborn.service"), CurrState::Stopped);
+        fs::remove_file(&log).unwrap();
+    }
 
-    for entry in all_services {
-        if let Ok(an_entry) = entry {
-            println!("Loading {:?}...", an_entry);
+    #[test]
+    fn test_kill_mode() {
+        let _loaded = LOADED.lock().unwrap();
+        // The config, whether the main process and its child are left after
+        // stopping and whether TimeoutStopSec= was waited out.
+        let cases = [
+            ("KillMode=control-group", false, false, false),
+            ("KillMode=mixed", false, false, true),
+            ("KillMode=process", false, true, false),
+            ("KillMode=none", true, true, true),
+            ("KillMode=mixed\nSendSIGKILL=no", false, true, true),
+            // Background processes of sh ignore SIGINT.
+            ("KillSignal=SIGINT", false, false, true),
+        ];
+        let mut runtime = Runtime::new().unwrap();
+        for (i, (config, main_left, child_left, waited)) in cases.iter().enumerate() {
+            let test = format!("kill-mode-{}", i);
+            let log = log_path(&test);
+            let service = format!(
+                "ExecStart=/bin/sh -c 'sleep 30 & echo ready >> {}; wait'\n\
+                 TimeoutStopSec=1\n{}",
+                log.display(),
+                config
+            );
+            load_services(&test, &[("kill.service", service)]);
+            runtime.block_on(start_unit("kill.service")).unwrap();
+            wait_ready(&log);
+            let (main, processes) = {
+                let service = get_service("kill.service").unwrap();
+                let service = service.lock().unwrap();
+                (service.main_pid.unwrap(), service.processes())
+            };
+            let child = *processes.iter().find(|pid| **pid != main).unwrap();
 
-            let unit = Unit::from_unitfile(&an_entry.path().as_path());
-            ALL_UNITS.lock().expect("Failed to parse unit file").add_unit(unit);
+            let took = stop(&mut runtime, "kill.service");
+            assert_eq!(
+                took >= time::Duration::from_secs(1),
+                *waited,
+                "{}: {:?}",
+                config,
+                took
+            );
+            assert!(took < time::Duration::from_secs(4), "{}: {:?}", config, took);
+            assert_eq!(status("kill.service"), CurrState::Stopped);
+            let left = (alive(main), alive(child));
+            // Clean up whatever was left behind, reaping our own child.
+            let _ = kill(Pid::from_raw(child as i32), Signal::SIGKILL);
+            if left.0 {
+                let _ = kill(Pid::from_raw(main as i32), Signal::SIGKILL);
+                let _ = nix::sys::wait::waitpid(Pid::from_raw(main as i32), None);
+            }
+            assert_eq!(left, (*main_left, *child_left), "{}", config);
+            fs::remove_file(&log).unwrap();
         }
     }
 }
@@ -0,0 +1,406 @@
+/// credentials.rs finds out who a service runs as from `User=`, `Group=`,
+/// `SupplementaryGroups=` and `DynamicUser=`, and switches to them between
+/// fork and exec.
+///
+/// Users and groups are looked up when the service starts, so they don't
+/// have to exist when its unit is loaded. A service with `DynamicUser=` gets a
+/// UID of its own out of the range in the config, which is its GID too. It
+/// isn't in /etc/passwd and only belongs to the service until it stops, then
+/// it goes back to the range. Processes it left behind can still run as it
+/// then, so it isn't handed out again until they are gone.
+use lazy_static::lazy_static;
+use nix::unistd::{getgid, getgrouplist, getuid, Gid, Group, Uid, User};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// The UIDs systemd hands out to dynamic users.
+const DEFAULT_DYNAMIC_UIDS: RangeInclusive<u32> = 61184..=65519;
+
+lazy_static! {
+    static ref DYNAMIC_UIDS: Mutex<RangeInclusive<u32>> = Mutex::new(DEFAULT_DYNAMIC_UIDS);
+    /// UIDs of dynamic users, by the unit they belong to.
+    static ref DYNAMIC_USERS: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+    /// UIDs of dynamic users that were released while processes still ran as
+    /// them.
+    static ref LINGERING: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+}
+
+/// Who the processes of a service run as.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, those of the user in the group database along
+    /// with SupplementaryGroups=.
+    pub groups: Vec<u32>,
+    /// Name of the user, for USER= and LOGNAME=.
+    pub user: Option<String>,
+    /// Home directory and shell of the user, for HOME= and SHELL=.
+    #[serde(skip_serializing)]
+    pub home: Option<String>,
+    #[serde(skip_serializing)]
+    pub shell: Option<String>,
+}
+
+/// Set the range dynamic users get their UID from.
+pub fn set_dynamic_range(first: u32, last: u32) {
+    *DYNAMIC_UIDS.lock().unwrap() = first..=last;
+}
+
+/// Work out the credentials of the service of unit. None means it runs as
+/// whoever getupd runs as.
+pub fn resolve(
+    unit: &str,
+    user: Option<&str>,
+    group: Option<&str>,
+    supplementary_groups: &[String],
+    dynamic_user: bool,
+) -> Result<Option<Credentials>, String> {
+    if user.is_none()
+        && group.is_none()
+        && supplementary_groups.is_empty()
+        && !dynamic_user
+    {
+        return Ok(None);
+    }
+    let mut credentials = if dynamic_user {
+        let uid = allocate(unit)?;
+        let name =
+            user.unwrap_or_else(|| unit.rsplit_once('.').map_or(unit, |(stem, _)| stem));
+        Credentials {
+            uid,
+            gid: uid,
+            groups: vec![],
+            user: Some(name.to_string()),
+            home: Some("/".to_string()),
+            shell: Some("/usr/sbin/nologin".to_string()),
+        }
+    } else {
+        match user {
+            Some(user) => lookup_user(user)?,
+            None => Credentials {
+                uid: getuid().as_raw(),
+                gid: getgid().as_raw(),
+                groups: vec![],
+                user: None,
+                home: None,
+                shell: None,
+            },
+        }
+    };
+    if let Some(group) = group {
+        credentials.gid = lookup_group(group)?;
+    }
+    for group in supplementary_groups {
+        credentials.groups.push(lookup_group(group)?);
+    }
+    Ok(Some(credentials))
+}
+
+/// A user by name or UID. A UID without an entry in the user database is
+/// fine, its GID is the same number.
+fn lookup_user(user: &str) -> Result<Credentials, String> {
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) if user.contains('\0') => return Err(format!("invalid user {:?}", user)),
+        Err(_) => User::from_name(user),
+    };
+    let entry = match entry {
+        Ok(Some(entry)) => entry,
+        Ok(None) => match user.parse::<u32>() {
+            Ok(uid) => {
+                return Ok(Credentials {
+                    uid,
+                    gid: uid,
+                    groups: vec![],
+                    user: None,
+                    home: None,
+                    shell: None,
+                })
+            }
+            Err(_) => return Err(format!("user {} does not exist", user)),
+        },
+        Err(e) => return Err(format!("failed to look up user {}: {}", user, e)),
+    };
+    // The groups the user is a member of, as login would set them up.
+    let name = CString::new(entry.name.as_str()).expect("user names have no NUL bytes");
+    let groups = getgrouplist(&name, entry.gid)
+        .map_err(|e| format!("failed to look up the groups of {}: {}", user, e))?;
+    Ok(Credentials {
+        uid: entry.uid.as_raw(),
+        gid: entry.gid.as_raw(),
+        groups: groups.iter().map(|gid| gid.as_raw()).collect(),
+        user: Some(entry.name),
+        home: Some(entry.dir.to_string_lossy().into_owned()),
+        shell: Some(entry.shell.to_string_lossy().into_owned()),
+    })
+}
+
+/// A group by name or GID.
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    if group.contains('\0') {
+        return Err(format!("invalid group {:?}", group));
+    }
+    match Group::from_name(group) {
+        Ok(Some(entry)) => Ok(entry.gid.as_raw()),
+        Ok(None) => Err(format!("group {} does not exist", group)),
+        Err(e) => Err(format!("failed to look up group {}: {}", group, e)),
+    }
+}
+
+/// The UID of the dynamic user of unit, which it keeps until it is released.
+/// UIDs and GIDs that are in the user or group database are skipped.
+fn allocate(unit: &str) -> Result<u32, String> {
+    let mut users = DYNAMIC_USERS.lock().unwrap();
+    if let Some(uid) = users.get(unit) {
+        return Ok(*uid);
+    }
+    let mut lingering = LINGERING.lock().unwrap();
+    lingering.retain(|uid| in_use(*uid));
+    let range = DYNAMIC_UIDS.lock().unwrap().clone();
+    let uid = range
+        .clone()
+        .filter(|uid| !users.values().any(|taken| taken == uid))
+        .filter(|uid| !lingering.contains(uid))
+        .find(|uid| {
+            matches!(User::from_uid(Uid::from_raw(*uid)), Ok(None))
+                && matches!(Group::from_gid(Gid::from_raw(*uid)), Ok(None))
+        })
+        .ok_or_else(|| {
+            format!("no dynamic UID left between {} and {}", range.start(), range.end())
+        })?;
+    users.insert(unit.to_string(), uid);
+    Ok(uid)
+}
+
+/// Give the UID of the dynamic user of unit back, if it has one. It is only
+/// handed out again once no process runs as it anymore.
+pub fn release(unit: &str) {
+    let uid = match DYNAMIC_USERS.lock().unwrap().remove(unit) {
+        Some(uid) => uid,
+        None => return,
+    };
+    if in_use(uid) {
+        println!("Processes of {} still run as UID {}, keeping it for them", unit, uid);
+        LINGERING.lock().unwrap().insert(uid);
+    }
+}
+
+/// Whether a process that isn't a zombie has the UID as its real, effective,
+/// saved or file system UID, going by /proc/PID/status.
+fn in_use(uid: u32) -> bool {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| {
+        let status = fs::read_to_string(entry.path().join("status")).unwrap_or_default();
+        let zombie = status.lines().any(|line| line.starts_with("State:\tZ"));
+        !zombie
+            && status.lines().any(|line| {
+                line.strip_prefix("Uid:").is_some_and(|uids| {
+                    uids.split_whitespace().any(|id| id.parse() == Ok(uid))
+                })
+            })
+    })
+}
+
+/// Have the command switch to the credentials before it executes anything:
+/// supplementary groups first, then the GID while we still may and the UID
+/// last.
+pub fn switch(cmd: &mut Command, credentials: &Credentials) {
+    let (uid, gid) = (credentials.uid, credentials.gid);
+    let groups: Vec<libc::gid_t> = credentials.groups.clone();
+    unsafe {
+        cmd.pre_exec(move || {
+            // Nothing to switch, and without root nothing we could switch to.
+            if libc::geteuid() == uid && libc::getegid() == gid && groups.is_empty() {
+                return Ok(());
+            }
+            if libc::setgroups(groups.len(), groups.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_user(user: &str) -> Result<Option<Credentials>, String> {
+        resolve("test.service", Some(user), None, &[], false)
+    }
+
+    /// A UID that isn't in the user database.
+    fn unknown_uid() -> u32 {
+        (4242..)
+            .find(|uid| matches!(User::from_uid(Uid::from_raw(*uid)), Ok(None)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_user() {
+        assert_eq!(resolve("test.service", None, None, &[], false), Ok(None));
+
+        let root = resolve_user("root").unwrap().unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(root.user.as_deref(), Some("root"));
+        assert_eq!(root.home.as_deref(), Some("/root"));
+        assert!(root.groups.contains(&0));
+        // By UID it is the same user.
+        assert_eq!(resolve_user("0").unwrap().unwrap(), root);
+
+        let uid = unknown_uid();
+        let unknown = resolve_user(&uid.to_string()).unwrap().unwrap();
+        assert_eq!((unknown.uid, unknown.gid), (uid, uid));
+        assert_eq!(unknown.user, None);
+        assert!(unknown.groups.is_empty());
+
+        let cases = [
+            ("getup-no-such-user", "user getup-no-such-user does not exist"),
+            ("a\0b", "invalid user \"a\\0b\""),
+        ];
+        for (user, error) in cases.iter() {
+            assert_eq!(resolve_user(user), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_resolve_groups() {
+        let resolve_groups = |user, group, supplementary: &[&str]| {
+            let supplementary: Vec<String> =
+                supplementary.iter().map(|group| group.to_string()).collect();
+            resolve("test.service", user, group, &supplementary, false)
+        };
+        // Without User= it is getupd's own user, in another group.
+        let group = resolve_groups(None, Some("tty"), &[]).unwrap().unwrap();
+        assert_eq!((group.uid, group.gid), (getuid().as_raw(), 5));
+        assert_eq!(group.user, None);
+        let group = resolve_groups(None, Some("1234"), &[]).unwrap().unwrap();
+        assert_eq!(group.gid, 1234);
+
+        // Supplementary groups come after those of the user.
+        let root = resolve_user("root").unwrap().unwrap();
+        let with_groups =
+            resolve_groups(Some("root"), Some("tty"), &["tty", "77"]).unwrap().unwrap();
+        assert_eq!(with_groups.uid, 0);
+        assert_eq!(with_groups.gid, 5);
+        let mut expected = root.groups;
+        expected.extend(&[5, 77]);
+        assert_eq!(with_groups.groups, expected);
+        let only_supplementary = resolve_groups(None, None, &["tty"]).unwrap().unwrap();
+        assert_eq!(only_supplementary.groups, vec![5]);
+
+        let cases: [(Option<&str>, &[&str], &str); 3] = [
+            (
+                Some("getup-no-such-group"),
+                &[],
+                "group getup-no-such-group does not exist",
+            ),
+            (
+                None,
+                &["tty", "getup-no-such-group"],
+                "group getup-no-such-group does not exist",
+            ),
+            (Some("a\0b"), &[], "invalid group \"a\\0b\""),
+        ];
+        for (group, supplementary, error) in cases.iter() {
+            assert_eq!(
+                resolve_groups(None, *group, supplementary),
+                Err(error.to_string())
+            );
+        }
+    }
+
+    /// Everything about dynamic users is in one test, as it changes the
+    /// range they come from.
+    #[test]
+    fn test_dynamic_user() {
+        let dynamic = |unit: &str, user, group| {
+            resolve(unit, user, group, &[], true).unwrap().unwrap()
+        };
+        let web = dynamic("web.service", None, None);
+        assert!(DEFAULT_DYNAMIC_UIDS.contains(&web.uid));
+        assert_eq!(web.gid, web.uid);
+        assert_eq!(web.user.as_deref(), Some("web"));
+        assert_eq!(web.home.as_deref(), Some("/"));
+        assert_eq!(web.shell.as_deref(), Some("/usr/sbin/nologin"));
+        assert!(web.groups.is_empty());
+        // The unit keeps its UID until it is released.
+        assert_eq!(dynamic("web.service", None, None), web);
+
+        // User= only names the dynamic user, Group= still applies.
+        let named = dynamic("named.service", Some("root"), Some("tty"));
+        assert_eq!(named.user.as_deref(), Some("root"));
+        assert_ne!(named.uid, 0);
+        assert_ne!(named.uid, web.uid);
+        assert_eq!(named.gid, 5);
+
+        release("web.service");
+        release("named.service");
+        // Releasing twice is fine.
+        release("web.service");
+
+        // UIDs in the user or group database aren't handed out, nor are those
+        // taken by other units.
+        set_dynamic_range(65532, 65535);
+        let taken = |uid: u32| {
+            !matches!(User::from_uid(Uid::from_raw(uid)), Ok(None))
+                || !matches!(Group::from_gid(Gid::from_raw(uid)), Ok(None))
+        };
+        let free: Vec<u32> = (65532..=65535).filter(|uid| !taken(*uid)).collect();
+        let units: Vec<String> =
+            (0..free.len()).map(|i| format!("{}.service", i)).collect();
+        let mut uids: Vec<u32> =
+            units.iter().map(|unit| allocate(unit).unwrap()).collect();
+        uids.sort_unstable();
+        assert_eq!(uids, free);
+        assert_eq!(
+            allocate("one-too-many.service"),
+            Err("no dynamic UID left between 65532 and 65535".to_string())
+        );
+        // Released UIDs can be handed out again.
+        release(&units[0]);
+        assert!(free.contains(&allocate("one-too-many.service").unwrap()));
+
+        for unit in units.iter().chain(&["one-too-many.service".to_string()]) {
+            release(unit);
+        }
+        set_dynamic_range(*DEFAULT_DYNAMIC_UIDS.start(), *DEFAULT_DYNAMIC_UIDS.end());
+    }
+
+    #[test]
+    fn test_release_in_use() {
+        let uid = getuid().as_raw();
+        assert!(in_use(uid));
+        assert!(!in_use(unknown_uid()));
+
+        // We run as the UID, as processes left behind by a service would.
+        DYNAMIC_USERS.lock().unwrap().insert("lingering.service".into(), uid);
+        release("lingering.service");
+        assert!(LINGERING.lock().unwrap().remove(&uid));
+
+        let unused = unknown_uid();
+        DYNAMIC_USERS.lock().unwrap().insert("gone.service".into(), unused);
+        release("gone.service");
+        assert!(!LINGERING.lock().unwrap().contains(&unused));
+    }
+}
@@ -0,0 +1,134 @@
+/// deps.rs includes the dependencies between units and the graph built out of
+/// them.
+///
+/// Requirement dependencies (Requires=, Wants=, ...) decide which units are
+/// pulled in together, while ordering dependencies (After=, Before=) decide in
+/// which order they are started. The two are independent, like in systemd.
+use crate::unitfile::Section;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Dependencies of a unit as written in its `[Unit]` section. Every directive
+/// takes a whitespace separated list of unit names and can be repeated.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Dependencies {
+    /// Units which are started along with this one, and if they fail to start
+    /// this one fails too.
+    pub requires: Vec<String>,
+    /// Units which are started along with this one, failures are ignored.
+    pub wants: Vec<String>,
+    /// Units which must already be active, they are not started.
+    pub requisite: Vec<String>,
+    /// Like requires, but this unit is also stopped when they stop.
+    pub binds_to: Vec<String>,
+    /// Units whose stop and restart is propagated to this one.
+    pub part_of: Vec<String>,
+    /// Units which can't be active at the same time as this one.
+    pub conflicts: Vec<String>,
+    /// Units this one is started after.
+    pub after: Vec<String>,
+    /// Units this one is started before.
+    pub before: Vec<String>,
+}
+
+impl Dependencies {
+    /// Read all the dependency directives from a `[Unit]` section.
+    pub fn from_section(section: &Section) -> Dependencies {
+        let list = |key: &str| -> Vec<String> {
+            section
+                .get_all(key)
+                .iter()
+                .flat_map(|entry| entry.value.split_whitespace())
+                .map(|name| name.to_string())
+                .collect()
+        };
+        Dependencies {
+            requires: list("Requires"),
+            wants: list("Wants"),
+            requisite: list("Requisite"),
+            binds_to: list("BindsTo"),
+            part_of: list("PartOf"),
+            conflicts: list("Conflicts"),
+            after: list("After"),
+            before: list("Before"),
+        }
+    }
+}
+
+/// Graph of the dependencies between all the units, keyed by unit name.
+///
+/// Relations that have an inverse are stored both ways, so that `Before=` on
+/// one unit shows up as `After=` on the other and asking a unit about what it
+/// conflicts with also finds units that declared the conflict themselves.
+#[derive(Debug, Default)]
+pub struct DepGraph {
+    nodes: HashMap<String, Dependencies>,
+    /// Units that were added, as opposed to only being referred to.
+    loaded: HashSet<String>,
+}
+
+impl DepGraph {
+    pub fn new() -> DepGraph {
+        DepGraph { nodes: HashMap::new(), loaded: HashSet::new() }
+    }
+
+    /// Add a unit with its dependencies to the graph.
+    pub fn add(&mut self, name: &str, deps: &Dependencies) {
+        self.loaded.insert(name.to_string());
+        for other in &deps.before {
+            self.node(other).after.push(name.to_string());
+        }
+        for other in &deps.after {
+            self.node(name).after.push(other.clone());
+        }
+        for other in &deps.conflicts {
+            self.node(other).conflicts.push(name.to_string());
+            self.node(name).conflicts.push(other.clone());
+        }
+        let node = self.node(name);
+        node.requires.extend(deps.requires.iter().cloned());
+        node.wants.extend(deps.wants.iter().cloned());
+        node.requisite.extend(deps.requisite.iter().cloned());
+        node.binds_to.extend(deps.binds_to.iter().cloned());
+        node.part_of.extend(deps.part_of.iter().cloned());
+    }
+
+    fn node(&mut self, name: &str) -> &mut Dependencies {
+        self.nodes.entry(name.to_string()).or_default()
+    }
+
+    /// Whether a unit with this name was added to the graph, as opposed to
+    /// only being referred to by other units.
+    pub fn contains(&self, name: &str) -> bool {
+        self.loaded.contains(name)
+    }
+
+    /// Dependencies of the unit, with inverse relations filled in.
+    pub fn get(&self, name: &str) -> Option<&Dependencies> {
+        self.nodes.get(name)
+    }
+
+    /// Units that have to be started before the unit.
+    pub fn after(&self, name: &str) -> &[String] {
+        self.nodes.get(name).map(|deps| deps.after.as_slice()).unwrap_or(&[])
+    }
+
+    /// Units which are stopped when the unit stops, because they require it,
+    /// are bound to it or are part of it.
+    pub fn propagate_stop(&self, name: &str) -> Vec<&str> {
+        let mut units: Vec<&str> = self
+            .nodes
+            .iter()
+            .filter(|(_, deps)| {
+                deps.requires
+                    .iter()
+                    .chain(deps.binds_to.iter())
+                    .chain(deps.part_of.iter())
+                    .any(|other| other == name)
+            })
+            .map(|(other, _)| other.as_str())
+            .collect();
+        units.sort_unstable();
+        units
+    }
+}
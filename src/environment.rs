@@ -0,0 +1,172 @@
+/// environment.rs builds the environment services run with.
+///
+/// Services don't inherit the environment of getupd, they start out with the
+/// default one from the config, which only has the likes of PATH and LANG.
+/// On top of that come the variables named in `PassEnvironment=` that getupd
+/// has, then `Environment=` and then the files of `EnvironmentFile=`, which
+/// are read again every time the service starts. `UnsetEnvironment=` has the
+/// last word. The same environment is used to expand `$VAR` in `Exec*=`.
+use crate::cmdline::{self, is_valid_name};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// What every service starts out with, see `set_default`.
+    static ref DEFAULT: Mutex<Vec<(String, String)>> = Mutex::new(vec![(
+        "PATH".to_string(),
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string(),
+    )]);
+}
+
+/// Set the default environment to the assignments, like `LANG=C.UTF-8`.
+pub fn set_default(assignments: &[String]) -> Result<(), String> {
+    let mut env = vec![];
+    for assignment in assignments {
+        env.push(parse_assignment(assignment)?);
+    }
+    *DEFAULT.lock().unwrap() = env;
+    Ok(())
+}
+
+/// Parse the assignments of an `Environment=` line, which are quoted like
+/// command lines: `Environment="GREETING=hello world" DEBUG=1`.
+pub fn parse_assignments(line: &str) -> Result<Vec<(String, String)>, String> {
+    cmdline::split(line)?.iter().map(|word| parse_assignment(word)).collect()
+}
+
+fn parse_assignment(assignment: &str) -> Result<(String, String), String> {
+    match assignment.split_once('=') {
+        Some((name, value)) if is_valid_name(name) => {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("{:?} is not an assignment like NAME=value", assignment)),
+    }
+}
+
+/// Read the assignments of an environment file, one per line. Empty lines
+/// and lines starting with `#` or `;` are skipped, and values can be quoted.
+pub fn read_file(path: &str) -> io::Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)?;
+    let mut env = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) if is_valid_name(name.trim()) => {
+                (name.trim(), value.trim())
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {} is not an assignment like NAME=value", number + 1),
+                ))
+            }
+        };
+        let unquoted = ['"', '\''].iter().find_map(|quote| {
+            value.strip_prefix(*quote).and_then(|value| value.strip_suffix(*quote))
+        });
+        env.push((name.to_string(), unquoted.unwrap_or(value).to_string()));
+    }
+    Ok(env)
+}
+
+/// Build the environment of a service out of the default one and its
+/// directives. Files prefixed with `-` may be missing.
+pub fn resolve(
+    pass: &[String],
+    environment: &[(String, String)],
+    files: &[String],
+) -> Result<HashMap<String, String>, String> {
+    let mut env: HashMap<String, String> =
+        DEFAULT.lock().unwrap().iter().cloned().collect();
+    for name in pass {
+        if let Ok(value) = std::env::var(name) {
+            env.insert(name.clone(), value);
+        }
+    }
+    env.extend(environment.iter().cloned());
+    for file in files {
+        let (path, optional) = match file.strip_prefix('-') {
+            Some(path) => (path, true),
+            None => (file.as_str(), false),
+        };
+        match read_file(path) {
+            Ok(vars) => env.extend(vars),
+            Err(e) if optional && e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("failed to read {}: {}", path, e)),
+        }
+    }
+    Ok(env)
+}
+
+/// Remove what `UnsetEnvironment=` lists, which are names or assignments that
+/// only go if the variable has exactly that value.
+pub fn unset(env: &mut HashMap<String, String>, unset: &[String]) {
+    for entry in unset {
+        match entry.split_once('=') {
+            Some((name, value)) => {
+                if env.get(name).is_some_and(|current| current == value) {
+                    env.remove(name);
+                }
+            }
+            None => {
+                env.remove(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        assert_eq!(
+            parse_assignments(r#"A=1 "GREETING=hello world" 'Q=it"s'"#).unwrap(),
+            pairs(&[("A", "1"), ("GREETING", "hello world"), ("Q", "it\"s")])
+        );
+        assert_eq!(parse_assignments("EMPTY=").unwrap(), pairs(&[("EMPTY", "")]));
+        assert!(parse_assignments("A=1 oops").is_err());
+        assert!(parse_assignments("1A=1").is_err());
+        assert!(parse_assignments("A=\"open").is_err());
+    }
+
+    #[test]
+    fn test_read_file() {
+        let path =
+            std::env::temp_dir().join(format!("getup-env-{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "# comment\n\nA=1\n B = two \nC=\"quoted value\"\n; more")
+            .unwrap();
+        let env = read_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(env, pairs(&[("A", "1"), ("B", "two"), ("C", "quoted value")]));
+
+        let optional = format!("-{}.missing", path.display());
+        let files = [path.to_string_lossy().into_owned(), optional];
+        let env = resolve(&[], &pairs(&[("A", "0"), ("D", "4")]), &files).unwrap();
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["D"], "4");
+        assert!(resolve(&[], &[], &[format!("{}.missing", path.display())]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unset() {
+        let mut env: HashMap<String, String> =
+            pairs(&[("A", "1"), ("B", "2"), ("C", "3")]).into_iter().collect();
+        unset(&mut env, &["A".to_string(), "B=2".to_string(), "C=4".to_string()]);
+        assert_eq!(env.len(), 1);
+        assert_eq!(env["C"], "3");
+    }
+}
@@ -0,0 +1,110 @@
+/// events.rs publishes what happens to units and jobs, so that clients can
+/// follow along instead of polling.
+///
+/// Every event gets the next sequence number and the time it happened. The
+/// most recent events are kept in a backlog, a client that lost its connection
+/// picks up where it left off by asking for the events after the last one it
+/// saw.
+use crate::jobs::JobInfo;
+use crate::units::CurrState;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// How many events are kept for clients catching up.
+const BACKLOG: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    StateChanged {
+        unit: String,
+        from: CurrState,
+        to: CurrState,
+    },
+    JobStarted {
+        job: JobInfo,
+    },
+    JobFinished {
+        job: JobInfo,
+    },
+    UnitLoaded {
+        unit: String,
+    },
+    UnitUnloaded {
+        unit: String,
+    },
+    /// The unit files were read again.
+    Reloaded,
+}
+
+impl EventKind {
+    /// The name of the event, as in its type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::StateChanged { .. } => "state_changed",
+            EventKind::JobStarted { .. } => "job_started",
+            EventKind::JobFinished { .. } => "job_finished",
+            EventKind::UnitLoaded { .. } => "unit_loaded",
+            EventKind::UnitUnloaded { .. } => "unit_unloaded",
+            EventKind::Reloaded => "reloaded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Sequence number, one more than that of the event before.
+    pub id: u64,
+    /// Microseconds since the epoch.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+struct Events {
+    last_id: u64,
+    backlog: VecDeque<Event>,
+    sender: broadcast::Sender<Event>,
+}
+
+lazy_static! {
+    static ref EVENTS: Mutex<Events> = Mutex::new(Events {
+        last_id: 0,
+        backlog: VecDeque::new(),
+        sender: broadcast::channel(BACKLOG).0,
+    });
+}
+
+/// Let everybody following along know that something happened.
+pub fn publish(kind: EventKind) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or(0);
+    let mut events = EVENTS.lock().unwrap();
+    events.last_id += 1;
+    let event = Event { id: events.last_id, timestamp, kind };
+    events.backlog.push_back(event.clone());
+    if events.backlog.len() > BACKLOG {
+        events.backlog.pop_front();
+    }
+    // Nobody following along is fine.
+    let _ = events.sender.send(event);
+}
+
+/// Follow the events from now on, along with those after the one with ID
+/// `after` which are still in the backlog.
+pub fn subscribe(after: Option<u64>) -> (Vec<Event>, broadcast::Receiver<Event>) {
+    let events = EVENTS.lock().unwrap();
+    let missed = match after {
+        Some(after) => {
+            events.backlog.iter().filter(|event| event.id > after).cloned().collect()
+        }
+        None => vec![],
+    };
+    (missed, events.sender.subscribe())
+}
@@ -0,0 +1,474 @@
+/// execute.rs sets up the environment processes of a service execute in, from
+/// the directives of systemd.exec(5) like `WorkingDirectory=`, `Nice=` or
+/// `LimitNOFILE=`.
+///
+/// Everything is applied between fork and exec, after the process joined its
+/// cgroup and before it switches to the user of the service, so that it still
+/// may lower its nice level or raise its limits. The working directory is
+/// changed to after `RootDirectory=`, so it is a path within the new root.
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The directives, as they are called in unit files.
+pub const DIRECTIVES: [&str; 10] = [
+    "WorkingDirectory",
+    "RootDirectory",
+    "UMask",
+    "Nice",
+    "OOMScoreAdjust",
+    "CPUAffinity",
+    "CPUSchedulingPolicy",
+    "CPUSchedulingPriority",
+    "IOSchedulingClass",
+    "IOSchedulingPriority",
+];
+
+/// The `Limit*=` directives and the resources they limit.
+pub const LIMITS: [(&str, i32); 16] = [
+    ("LimitCPU", libc::RLIMIT_CPU as i32),
+    ("LimitFSIZE", libc::RLIMIT_FSIZE as i32),
+    ("LimitDATA", libc::RLIMIT_DATA as i32),
+    ("LimitSTACK", libc::RLIMIT_STACK as i32),
+    ("LimitCORE", libc::RLIMIT_CORE as i32),
+    ("LimitRSS", libc::RLIMIT_RSS as i32),
+    ("LimitNOFILE", libc::RLIMIT_NOFILE as i32),
+    ("LimitAS", libc::RLIMIT_AS as i32),
+    ("LimitNPROC", libc::RLIMIT_NPROC as i32),
+    ("LimitMEMLOCK", libc::RLIMIT_MEMLOCK as i32),
+    ("LimitLOCKS", libc::RLIMIT_LOCKS as i32),
+    ("LimitSIGPENDING", libc::RLIMIT_SIGPENDING as i32),
+    ("LimitMSGQUEUE", libc::RLIMIT_MSGQUEUE as i32),
+    ("LimitNICE", libc::RLIMIT_NICE as i32),
+    ("LimitRTPRIO", libc::RLIMIT_RTPRIO as i32),
+    ("LimitRTTIME", libc::RLIMIT_RTTIME as i32),
+];
+
+/// `ioprio_set(2)` is about a process, which 0 makes the calling one.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// WorkingDirectory=, which is `~` for the home of the user.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WorkingDirectory {
+    pub path: String,
+    /// The `-` prefix, a missing directory isn't an error.
+    pub ignore_missing: bool,
+}
+
+/// Soft and hard limit of a resource, `infinity` is `u64::MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Rlimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// The execution environment of a service, None for what isn't set, which is
+/// inherited from getupd.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ExecContext {
+    pub working_directory: Option<WorkingDirectory>,
+    /// Directory the processes are chrooted to.
+    pub root_directory: Option<String>,
+    pub umask: Option<u32>,
+    /// From -20, the most favorable, to 19.
+    pub nice: Option<i32>,
+    /// From -1000, never killed for running out of memory, to 1000.
+    pub oom_score_adjust: Option<i32>,
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// other, batch, idle, fifo or rr.
+    pub cpu_scheduling_policy: Option<String>,
+    /// From 1 to 99, for fifo and rr.
+    pub cpu_scheduling_priority: Option<i32>,
+    /// realtime, best-effort or idle.
+    pub io_scheduling_class: Option<String>,
+    /// From 0, the highest, to 7.
+    pub io_scheduling_priority: Option<i32>,
+    /// Limits by their directive, like LimitNOFILE.
+    pub limits: BTreeMap<String, Rlimit>,
+}
+
+impl ExecContext {
+    /// Set the directive key to value as it is written in unit files.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key {
+            "WorkingDirectory" => {
+                let (path, ignore_missing) = match value.strip_prefix('-') {
+                    Some(path) => (path, true),
+                    None => (value, false),
+                };
+                if path != "~" && !path.starts_with('/') {
+                    return Err(format!("{:?} is neither an absolute path nor ~", path));
+                }
+                let path = path.to_string();
+                self.working_directory = Some(WorkingDirectory { path, ignore_missing });
+            }
+            "RootDirectory" => {
+                if !value.starts_with('/') {
+                    return Err(format!("{:?} is not an absolute path", value));
+                }
+                self.root_directory = Some(value.to_string());
+            }
+            "UMask" => match u32::from_str_radix(value, 8) {
+                Ok(umask) if umask <= 0o777 => self.umask = Some(umask),
+                _ => return Err(format!("invalid umask {:?}", value)),
+            },
+            "Nice" => self.nice = Some(parse_in(value, -20, 19)?),
+            "OOMScoreAdjust" => {
+                self.oom_score_adjust = Some(parse_in(value, -1000, 1000)?)
+            }
+            "CPUAffinity" => self.cpu_affinity = Some(parse_cpus(value)?),
+            "CPUSchedulingPolicy" => {
+                sched_policy(value)
+                    .ok_or_else(|| format!("unknown policy {:?}", value))?;
+                self.cpu_scheduling_policy = Some(value.to_string());
+            }
+            "CPUSchedulingPriority" => {
+                self.cpu_scheduling_priority = Some(parse_in(value, 1, 99)?)
+            }
+            "IOSchedulingClass" => {
+                io_class(value).ok_or_else(|| format!("unknown class {:?}", value))?;
+                self.io_scheduling_class = Some(value.to_string());
+            }
+            "IOSchedulingPriority" => {
+                self.io_scheduling_priority = Some(parse_in(value, 0, 7)?)
+            }
+            _ if LIMITS.iter().any(|(name, _)| *name == key) => {
+                self.limits.insert(key.to_string(), parse_rlimit(value)?);
+            }
+            _ => return Err(format!("unknown directive {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Have the command set up its environment before it executes anything.
+    /// `~` is home, the directory of the user of the service.
+    pub fn apply(&self, cmd: &mut Command, home: Option<&str>) -> io::Result<()> {
+        let cstring = |path: &str| {
+            CString::new(path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let root = self.root_directory.as_deref().map(cstring).transpose()?;
+        let (working_directory, ignore_missing) = match &self.working_directory {
+            Some(dir) if dir.path == "~" => {
+                (Some(home.unwrap_or("/")), dir.ignore_missing)
+            }
+            Some(dir) => (Some(dir.path.as_str()), dir.ignore_missing),
+            // Whatever the directory getupd is in, it isn't in the new root.
+            None if root.is_some() => (Some("/"), false),
+            None => (None, false),
+        };
+        let working_directory = working_directory.map(cstring).transpose()?;
+        let oom_score_adjust =
+            self.oom_score_adjust.map(|adjust| format!("{}\n", adjust).into_bytes());
+        let cpu_set = self.cpu_affinity.as_ref().map(|cpus| {
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for cpu in cpus {
+                unsafe { libc::CPU_SET(*cpu, &mut set) };
+            }
+            set
+        });
+        let policy = self.cpu_scheduling_policy.as_deref().and_then(sched_policy);
+        let realtime = matches!(policy, Some(libc::SCHED_FIFO) | Some(libc::SCHED_RR));
+        let sched_priority =
+            if realtime { self.cpu_scheduling_priority.unwrap_or(1) } else { 0 };
+        let ioprio = match (
+            self.io_scheduling_class.as_deref().and_then(io_class),
+            self.io_scheduling_priority,
+        ) {
+            (Some(class), priority) => {
+                Some((class << IOPRIO_CLASS_SHIFT) | priority.unwrap_or(4))
+            }
+            // Without a class, the priority is that of best-effort.
+            (None, Some(priority)) => Some((2 << IOPRIO_CLASS_SHIFT) | priority),
+            (None, None) => None,
+        };
+        let limits: Vec<(i32, libc::rlimit)> = LIMITS
+            .iter()
+            .filter_map(|(name, resource)| {
+                let limit = self.limits.get(*name)?;
+                Some((
+                    *resource,
+                    libc::rlimit { rlim_cur: limit.soft, rlim_max: limit.hard },
+                ))
+            })
+            .collect();
+        let (umask, nice) = (self.umask, self.nice);
+
+        unsafe {
+            cmd.pre_exec(move || {
+                // /proc may not be there after chroot.
+                if let Some(adjust) = &oom_score_adjust {
+                    write_file(b"/proc/self/oom_score_adj\0", adjust)?;
+                }
+                if let Some(nice) = nice {
+                    check(libc::setpriority(libc::PRIO_PROCESS as _, 0, nice))?;
+                }
+                if let Some(policy) = policy {
+                    let param = libc::sched_param { sched_priority };
+                    check(libc::sched_setscheduler(0, policy, &param))?;
+                }
+                if let Some(ioprio) = ioprio {
+                    check(libc::syscall(
+                        libc::SYS_ioprio_set,
+                        IOPRIO_WHO_PROCESS,
+                        0,
+                        ioprio,
+                    ) as libc::c_int)?;
+                }
+                if let Some(set) = &cpu_set {
+                    let size = std::mem::size_of::<libc::cpu_set_t>();
+                    check(libc::sched_setaffinity(0, size, set))?;
+                }
+                for (resource, limit) in &limits {
+                    check(libc::setrlimit(*resource as _, limit))?;
+                }
+                if let Some(umask) = umask {
+                    libc::umask(umask as libc::mode_t);
+                }
+                if let Some(root) = &root {
+                    check(libc::chroot(root.as_ptr()))?;
+                }
+                if let Some(dir) = &working_directory {
+                    if libc::chdir(dir.as_ptr()) != 0 && !ignore_missing {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The error of a call that returned -1.
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write to the file at path, which has to end in a NUL byte, with only what
+/// is safe to call after fork.
+unsafe fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(
+        path.as_ptr() as *const libc::c_char,
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    );
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written =
+        libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len());
+    let error = io::Error::last_os_error();
+    libc::close(fd);
+    if written != contents.len() as isize {
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn parse_in(value: &str, min: i32, max: i32) -> Result<i32, String> {
+    match value.parse() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!("{:?} is not a number from {} to {}", value, min, max)),
+    }
+}
+
+/// CPU numbers and ranges like `0-3`, separated by commas or spaces.
+fn parse_cpus(value: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("invalid CPU list {:?}", value);
+    let mut cpus = vec![];
+    for part in value.split([',', ' ']).filter(|part| !part.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: usize = first.parse().map_err(|_| invalid())?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        if first > last || last >= libc::CPU_SETSIZE as usize {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        return Err(invalid());
+    }
+    Ok(cpus)
+}
+
+/// `SOFT:HARD` or one value for both, each a number with an optional K, M, G
+/// or T suffix, which are powers of 1024, or `infinity`.
+fn parse_rlimit(value: &str) -> Result<Rlimit, String> {
+    let parse = |value: &str| -> Result<u64, String> {
+        if value == "infinity" {
+            return Ok(libc::RLIM_INFINITY);
+        }
+        let (number, multiplier) = match value.char_indices().last() {
+            Some((i, 'K')) => (&value[..i], 1 << 10),
+            Some((i, 'M')) => (&value[..i], 1 << 20),
+            Some((i, 'G')) => (&value[..i], 1 << 30),
+            Some((i, 'T')) => (&value[..i], 1u64 << 40),
+            _ => (value, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .ok_or_else(|| format!("invalid limit {:?}", value))
+    };
+    let (soft, hard) = match value.split_once(':') {
+        Some((soft, hard)) => (parse(soft)?, parse(hard)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    if soft > hard {
+        return Err(format!("soft limit of {:?} is above the hard one", value));
+    }
+    Ok(Rlimit { soft, hard })
+}
+
+fn sched_policy(name: &str) -> Option<libc::c_int> {
+    match name {
+        "other" => Some(libc::SCHED_OTHER),
+        "batch" => Some(libc::SCHED_BATCH),
+        "idle" => Some(libc::SCHED_IDLE),
+        "fifo" => Some(libc::SCHED_FIFO),
+        "rr" => Some(libc::SCHED_RR),
+        _ => None,
+    }
+}
+
+fn io_class(name: &str) -> Option<libc::c_int> {
+    match name {
+        "realtime" => Some(1),
+        "best-effort" => Some(2),
+        "idle" => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    /// Run script with /bin/sh in the environment of context, returning what
+    /// it printed, or None if it failed to spawn.
+    fn dump(directives: &[(&str, &str)], script: &str) -> Option<String> {
+        let mut context = ExecContext::default();
+        for (key, value) in directives {
+            context.set(key, value).unwrap();
+        }
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", script]).stdout(Stdio::piped()).stderr(Stdio::null());
+        context.apply(&mut cmd, Some("/usr")).unwrap();
+        let output = cmd.output().ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    #[test]
+    fn test_working_directory() {
+        assert_eq!(dump(&[("WorkingDirectory", "/tmp")], "pwd").unwrap(), "/tmp");
+        assert_eq!(dump(&[("WorkingDirectory", "~")], "pwd").unwrap(), "/usr");
+        assert!(dump(&[("WorkingDirectory", "/nonexistent")], "pwd").is_none());
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            dump(&[("WorkingDirectory", "-/nonexistent")], "pwd").unwrap(),
+            cwd.to_string_lossy()
+        );
+        assert!(ExecContext::default().set("WorkingDirectory", "tmp").is_err());
+    }
+
+    #[test]
+    fn test_root_directory() {
+        assert!(ExecContext::default().set("RootDirectory", "srv").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_root_directory_chroot() {
+        let directives = [("RootDirectory", "/"), ("WorkingDirectory", "/usr")];
+        assert_eq!(dump(&directives, "pwd").unwrap(), "/usr");
+        assert!(dump(&[("RootDirectory", "/nonexistent")], "pwd").is_none());
+    }
+
+    #[test]
+    fn test_umask() {
+        assert_eq!(dump(&[("UMask", "0027")], "umask").unwrap(), "0027");
+        assert!(ExecContext::default().set("UMask", "0999").is_err());
+    }
+
+    #[test]
+    fn test_nice() {
+        // The 19th field of stat is the nice level.
+        let script = "cut -d' ' -f19 /proc/$$/stat";
+        assert_eq!(dump(&[("Nice", "7")], script).unwrap(), "7");
+        assert!(ExecContext::default().set("Nice", "20").is_err());
+    }
+
+    #[test]
+    fn test_oom_score_adjust() {
+        let script = "cat /proc/$$/oom_score_adj";
+        assert_eq!(dump(&[("OOMScoreAdjust", "500")], script).unwrap(), "500");
+        assert!(ExecContext::default().set("OOMScoreAdjust", "-1001").is_err());
+    }
+
+    #[test]
+    fn test_cpu_affinity() {
+        let script = "grep Cpus_allowed_list /proc/$$/status | cut -f2";
+        assert_eq!(dump(&[("CPUAffinity", "0")], script).unwrap(), "0");
+        assert!(ExecContext::default().set("CPUAffinity", "1-0").is_err());
+    }
+
+    #[test]
+    fn test_cpu_scheduling() {
+        // The 41st field of stat is the policy, 3 is SCHED_BATCH and 5 is
+        // SCHED_IDLE.
+        let script = "cut -d' ' -f41 /proc/$$/stat";
+        assert_eq!(dump(&[("CPUSchedulingPolicy", "batch")], script).unwrap(), "3");
+        assert_eq!(dump(&[("CPUSchedulingPolicy", "idle")], script).unwrap(), "5");
+        assert!(ExecContext::default().set("CPUSchedulingPolicy", "fast").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_cpu_scheduling_realtime() {
+        // The 40th field of stat is the real-time priority, 2 is SCHED_RR.
+        let directives =
+            [("CPUSchedulingPolicy", "rr"), ("CPUSchedulingPriority", "10")];
+        let script = "cut -d' ' -f40,41 /proc/$$/stat";
+        assert_eq!(dump(&directives, script).unwrap(), "10 2");
+    }
+
+    #[test]
+    fn test_io_scheduling_class() {
+        // ionice comes with util-linux.
+        let script = "ionice -p $$";
+        assert_eq!(dump(&[("IOSchedulingClass", "idle")], script).unwrap(), "idle");
+        let directives =
+            [("IOSchedulingClass", "best-effort"), ("IOSchedulingPriority", "6")];
+        assert_eq!(dump(&directives, script).unwrap(), "best-effort: prio 6");
+        assert!(ExecContext::default().set("IOSchedulingClass", "fast").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_io_scheduling_realtime() {
+        let directives =
+            [("IOSchedulingClass", "realtime"), ("IOSchedulingPriority", "3")];
+        assert_eq!(dump(&directives, "ionice -p $$").unwrap(), "realtime: prio 3");
+    }
+
+    #[test]
+    fn test_limits() {
+        let script = "ulimit -Sn; ulimit -Hn";
+        assert_eq!(dump(&[("LimitNOFILE", "100:200")], script).unwrap(), "100\n200");
+        // dash counts the file size in blocks of 512 bytes.
+        assert_eq!(dump(&[("LimitFSIZE", "1M")], "ulimit -f").unwrap(), "2048");
+        assert_eq!(dump(&[("LimitCORE", "0")], "ulimit -c").unwrap(), "0");
+        assert!(ExecContext::default().set("LimitNOFILE", "200:100").is_err());
+        assert!(ExecContext::default().set("LimitNOFILE", "many").is_err());
+    }
+}
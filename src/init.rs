@@ -0,0 +1,201 @@
+/// init.rs has what getupd needs to run as PID 1, which is when it is the init
+/// system for real.
+///
+/// As PID 1 there is nobody to daemonize away from, every orphaned process on
+/// the system becomes our child and exiting, even by panicking, takes the
+/// whole system down. Signals are how the kernel and tools like `kill(1)` talk
+/// to init, they mean the same as they do for systemd, see systemd(1).
+use crate::signals::{self, signal_daemon, Message, SignalReceiver, SystemAction};
+use crate::units::ALL_UNITS;
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::sys::signal::Signal;
+use nix::unistd::getpid;
+use std::convert::TryFrom;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+/// Whether we are the init process, of the system or of a PID namespace.
+pub fn is_pid1() -> bool {
+    getpid().as_raw() == 1
+}
+
+/// What init does about a signal it receives.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Shutdown(SystemAction, bool),
+    Reload,
+    DumpUnits,
+    LogLevel(log::LevelFilter),
+    /// Ignored, along with why.
+    Ignore(&'static str),
+    Unsupported,
+}
+
+/// Signals that mean something to init. The first real-time signals are kept
+/// by the C library for itself, SIGRTMIN is the first one left for us.
+fn init_signals() -> Vec<libc::c_int> {
+    let mut all =
+        vec![libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+    all.extend((0..=29).map(|n| libc::SIGRTMIN() + n));
+    all
+}
+
+/// Block the signals handled by `handle_signals`, before any thread starts.
+pub fn block_signals() -> io::Result<()> {
+    signals::block(&init_signals())
+}
+
+/// Handle signals sent to init, forever. This has to be spawned on a tokio
+/// runtime, after `block_signals`.
+pub async fn handle_signals() -> io::Result<()> {
+    let mut receiver = SignalReceiver::new(&init_signals())?;
+    loop {
+        let signal = receiver.recv().await?;
+        println!("Received signal {}", signal_name(signal));
+        handle_signal(signal);
+    }
+}
+
+fn shutdown(action: SystemAction, immediate: bool) {
+    signal_daemon(Message::SystemShutdown { action, immediate });
+}
+
+fn handle_signal(signal: libc::c_int) {
+    match signal_action(signal) {
+        Action::Shutdown(action, immediate) => shutdown(action, immediate),
+        Action::Reload => {
+            signal_daemon(Message::ReloadUnits);
+        }
+        Action::DumpUnits => println!("{}", ALL_UNITS.lock().unwrap()),
+        Action::LogLevel(level) => log::set_max_level(level),
+        Action::Ignore(why) => println!("{}, ignoring {}", why, signal_name(signal)),
+        Action::Unsupported => {
+            println!("Ignoring unsupported signal {}", signal_name(signal))
+        }
+    }
+}
+
+fn signal_action(signal: libc::c_int) -> Action {
+    match signal {
+        // Ctrl-Alt-Del, which the kernel sends us once it is disabled with
+        // `reboot(RB_DISABLE_CAD)`. ctrl-alt-del.target is a reboot.
+        libc::SIGINT => Action::Shutdown(SystemAction::Reboot, false),
+        libc::SIGHUP => Action::Reload,
+        libc::SIGUSR2 => Action::DumpUnits,
+        libc::SIGTERM => Action::Ignore("Re-executing is not supported"),
+        libc::SIGUSR1 => Action::Ignore("There is no D-Bus to reconnect to"),
+        _ => match signal - libc::SIGRTMIN() {
+            3 => Action::Shutdown(SystemAction::Halt, false),
+            4 => Action::Shutdown(SystemAction::PowerOff, false),
+            5 => Action::Shutdown(SystemAction::Reboot, false),
+            6 => Action::Shutdown(SystemAction::Kexec, false),
+            13 => Action::Shutdown(SystemAction::Halt, true),
+            14 => Action::Shutdown(SystemAction::PowerOff, true),
+            15 => Action::Shutdown(SystemAction::Reboot, true),
+            16 => Action::Shutdown(SystemAction::Kexec, true),
+            22 => Action::LogLevel(log::LevelFilter::Debug),
+            23 => Action::LogLevel(log::LevelFilter::Info),
+            // Targets, the status display and log targets don't exist here.
+            _ => Action::Unsupported,
+        },
+    }
+}
+
+fn signal_name(signal: libc::c_int) -> String {
+    let offset = signal - libc::SIGRTMIN();
+    if offset >= 0 {
+        return format!("SIGRTMIN+{}", offset);
+    }
+    match Signal::try_from(signal) {
+        Ok(signal) => signal.as_str().to_string(),
+        Err(_) => signal.to_string(),
+    }
+}
+
+/// Run f and survive it panicking, which would otherwise take down the whole
+/// system when we are PID 1.
+pub fn survive_panic<F: FnOnce()>(f: F) {
+    if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+        println!("Recovered from a panic, carrying on");
+        // Whatever panicked may have been holding the units.
+        ALL_UNITS.clear_poison();
+        for unit in ALL_UNITS.lock().unwrap().iter() {
+            unit.service.clear_poison();
+        }
+    }
+}
+
+/// Halt, power off or reboot the machine once everything is shut down. In a
+/// PID namespace the kernel ends the namespace instead. If rebooting fails,
+/// all we can do is wait.
+pub fn finish(action: SystemAction) -> ! {
+    let mode = match action {
+        SystemAction::Halt => RebootMode::RB_HALT_SYSTEM,
+        SystemAction::PowerOff => RebootMode::RB_POWER_OFF,
+        SystemAction::Reboot => RebootMode::RB_AUTOBOOT,
+        SystemAction::Kexec => RebootMode::RB_KEXEC,
+    };
+    println!("Calling reboot with {:?}", mode);
+    let Err(e) = reboot(mode);
+    println!("Failed to {:?}: {}", action, e);
+    // Exiting as PID 1 makes the kernel panic.
+    loop {
+        thread::sleep(Duration::from_secs(3600));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtmin(n: libc::c_int) -> libc::c_int {
+        libc::SIGRTMIN() + n
+    }
+
+    #[test]
+    fn test_signal_name() {
+        assert_eq!(signal_name(libc::SIGTERM), "SIGTERM");
+        assert_eq!(signal_name(libc::SIGHUP), "SIGHUP");
+        assert_eq!(signal_name(rtmin(0)), "SIGRTMIN+0");
+        assert_eq!(signal_name(rtmin(4)), "SIGRTMIN+4");
+        // Real-time signals the C library keeps for itself have no name.
+        assert_eq!(
+            signal_name(libc::SIGRTMIN() - 1),
+            (libc::SIGRTMIN() - 1).to_string()
+        );
+    }
+
+    #[test]
+    fn test_signal_action() {
+        use SystemAction::*;
+        let table = [
+            (libc::SIGINT, Action::Shutdown(Reboot, false)),
+            (libc::SIGHUP, Action::Reload),
+            (libc::SIGUSR2, Action::DumpUnits),
+            (libc::SIGTERM, Action::Ignore("Re-executing is not supported")),
+            (libc::SIGUSR1, Action::Ignore("There is no D-Bus to reconnect to")),
+            (rtmin(0), Action::Unsupported),
+            (rtmin(3), Action::Shutdown(Halt, false)),
+            (rtmin(4), Action::Shutdown(PowerOff, false)),
+            (rtmin(5), Action::Shutdown(Reboot, false)),
+            (rtmin(6), Action::Shutdown(Kexec, false)),
+            (rtmin(13), Action::Shutdown(Halt, true)),
+            (rtmin(14), Action::Shutdown(PowerOff, true)),
+            (rtmin(15), Action::Shutdown(Reboot, true)),
+            (rtmin(16), Action::Shutdown(Kexec, true)),
+            (rtmin(22), Action::LogLevel(log::LevelFilter::Debug)),
+            (rtmin(23), Action::LogLevel(log::LevelFilter::Info)),
+            (rtmin(29), Action::Unsupported),
+        ];
+        for (signal, action) in table.iter() {
+            assert_eq!(&signal_action(*signal), action, "{}", signal_name(*signal));
+        }
+        // Every one of them is blocked so that it can be received.
+        let blocked = init_signals();
+        for (signal, _) in table.iter() {
+            assert!(blocked.contains(signal), "{}", signal_name(*signal));
+        }
+    }
+}
@@ -0,0 +1,394 @@
+/// jobs.rs keeps track of what the daemon has been asked to do, so that
+/// whoever asked can wait for the outcome or look it up later by job ID.
+///
+/// Every message sent to the daemon is a job. It waits in the queue until the
+/// main loop picks it up, runs and then finishes with a result, like jobs in
+/// systemd. A job asking for what a job that didn't finish yet is already
+/// doing to the same unit is merged into it and finishes along with it. A
+/// start still waiting in the queue is canceled by a stop of the same unit,
+/// and the other way around.
+use crate::events::{self, EventKind};
+use crate::signals::Message;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+pub type JobId = u64;
+
+/// How many finished jobs are kept around to be looked up.
+const FINISHED_JOBS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Waiting,
+    Running,
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobResult {
+    /// The job did what it was asked to.
+    Done,
+    Failed,
+    /// The unit took too long.
+    Timeout,
+    /// The job was dropped before it got to run.
+    Canceled,
+    /// A unit the job needed failed.
+    Dependency,
+}
+
+/// Why a job didn't finish with `Done`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobError {
+    pub result: JobResult,
+    pub reason: String,
+}
+
+impl JobError {
+    pub fn failed(reason: &str) -> JobError {
+        JobError { result: JobResult::Failed, reason: reason.to_string() }
+    }
+
+    pub fn timeout(reason: &str) -> JobError {
+        JobError { result: JobResult::Timeout, reason: reason.to_string() }
+    }
+
+    pub fn canceled(reason: &str) -> JobError {
+        JobError { result: JobResult::Canceled, reason: reason.to_string() }
+    }
+
+    pub fn dependency(reason: &str) -> JobError {
+        JobError { result: JobResult::Dependency, reason: reason.to_string() }
+    }
+}
+
+impl From<String> for JobError {
+    fn from(reason: String) -> JobError {
+        JobError::failed(&reason)
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// What is known about a job, as reported by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: JobId,
+    /// The unit the job is for, if it is for one.
+    pub unit: Option<String>,
+    pub action: String,
+    pub state: JobState,
+    /// Only there once the job finished.
+    pub result: Option<JobResult>,
+    /// Why the job failed.
+    pub error: Option<String>,
+}
+
+/// Actions on units that jobs can be merged into.
+const MERGEABLE: [&str; 4] = ["start", "stop", "restart", "reload"];
+
+#[derive(Default)]
+struct Jobs {
+    next_id: JobId,
+    jobs: BTreeMap<JobId, JobInfo>,
+    finished: VecDeque<JobId>,
+    /// Jobs that finish along with the job they were merged into.
+    merged: HashMap<JobId, Vec<Job>>,
+    /// Waiting jobs that were canceled, by the job that did it.
+    superseded: HashMap<JobId, JobId>,
+}
+
+impl Jobs {
+    /// Record how the job went and let whoever is waiting know.
+    fn finish(&mut self, job: Job, outcome: Result<(), JobError>) {
+        let info = match self.jobs.get_mut(&job.id) {
+            Some(info) => info,
+            None => return,
+        };
+        info.state = JobState::Finished;
+        match outcome {
+            Ok(()) => info.result = Some(JobResult::Done),
+            Err(e) => {
+                info.result = Some(e.result);
+                info.error = Some(e.reason);
+            }
+        }
+        events::publish(EventKind::JobFinished { job: info.clone() });
+        // Nobody waiting is fine.
+        let _ = job.reply.send(info.clone());
+
+        self.finished.push_back(job.id);
+        if self.finished.len() > FINISHED_JOBS {
+            if let Some(oldest) = self.finished.pop_front() {
+                self.jobs.remove(&oldest);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref JOBS: Mutex<Jobs> = Mutex::new(Jobs { next_id: 1, ..Jobs::default() });
+}
+
+/// A message for the daemon, along with the job tracking it.
+#[derive(Debug)]
+pub struct Job {
+    pub id: JobId,
+    pub message: Message,
+    reply: oneshot::Sender<JobInfo>,
+}
+
+impl Job {
+    /// Queue up a new job for the message. The receiver gets the job once it
+    /// finished.
+    pub fn new(message: Message) -> (Job, oneshot::Receiver<JobInfo>) {
+        let (unit, action) = message.describe();
+        let mut jobs = JOBS.lock().unwrap();
+        let id = jobs.next_id;
+        jobs.next_id += 1;
+        jobs.jobs.insert(
+            id,
+            JobInfo {
+                id,
+                unit: unit.map(str::to_string),
+                action: action.to_string(),
+                state: JobState::Waiting,
+                result: None,
+                error: None,
+            },
+        );
+        let (reply, receiver) = oneshot::channel();
+        (Job { id, message, reply }, receiver)
+    }
+
+    /// Merge the job into a job that is to do the same to the same unit and
+    /// didn't finish yet. Otherwise the job is given back to be queued up,
+    /// canceling a waiting start of the unit if it is a stop and the other way
+    /// around.
+    pub fn merge(self) -> Option<Job> {
+        let mut jobs = JOBS.lock().unwrap();
+        let new = jobs.jobs[&self.id].clone();
+        if new.unit.is_none() || !MERGEABLE.contains(&new.action.as_str()) {
+            return Some(self);
+        }
+        let opposite = match new.action.as_str() {
+            "start" => Some("stop"),
+            "stop" => Some("start"),
+            _ => None,
+        };
+        let mut superseded = vec![];
+        for (id, job) in &jobs.jobs {
+            if *id == self.id || job.unit != new.unit || jobs.superseded.contains_key(id)
+            {
+                continue;
+            }
+            match job.state {
+                JobState::Finished => {}
+                _ if job.action == new.action => {
+                    let into = *id;
+                    jobs.merged.entry(into).or_default().push(self);
+                    return None;
+                }
+                JobState::Waiting if Some(job.action.as_str()) == opposite => {
+                    superseded.push(*id)
+                }
+                _ => {}
+            }
+        }
+        for id in superseded {
+            jobs.superseded.insert(id, self.id);
+        }
+        Some(self)
+    }
+
+    /// The main loop picked up the job. Fails if it was canceled while it
+    /// was waiting.
+    pub fn start(&self) -> Result<(), JobError> {
+        let mut jobs = JOBS.lock().unwrap();
+        if let Some(by) = jobs.superseded.remove(&self.id) {
+            return Err(JobError::canceled(&format!("superseded by job {}", by)));
+        }
+        if let Some(job) = jobs.jobs.get_mut(&self.id) {
+            job.state = JobState::Running;
+            events::publish(EventKind::JobStarted { job: job.clone() });
+        }
+        Ok(())
+    }
+
+    /// Record how the job went and let whoever is waiting know, along with
+    /// the jobs merged into it.
+    pub fn finish(self, outcome: Result<(), JobError>) {
+        let mut jobs = JOBS.lock().unwrap();
+        let merged = jobs.merged.remove(&self.id).unwrap_or_default();
+        jobs.finish(self, outcome.clone());
+        for job in merged {
+            jobs.finish(job, outcome.clone());
+        }
+    }
+}
+
+/// Look up a job, as long as it hasn't been forgotten.
+pub fn get(id: JobId) -> Option<JobInfo> {
+    JOBS.lock().unwrap().jobs.get(&id).cloned()
+}
+
+/// All the jobs that are still known, oldest first.
+pub fn list() -> Vec<JobInfo> {
+    JOBS.lock().unwrap().jobs.values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+
+    /// Queue up a job the way `signal_daemon` does, giving back the job if it
+    /// wasn't merged.
+    fn submit(message: Message) -> (JobId, Option<Job>, oneshot::Receiver<JobInfo>) {
+        let (job, receiver) = Job::new(message);
+        let id = job.id;
+        (id, job.merge(), receiver)
+    }
+
+    fn result(id: JobId) -> Option<JobResult> {
+        get(id).unwrap().result
+    }
+
+    #[test]
+    fn test_merge_start() {
+        let unit = "merge-start.service";
+        let (first, job, mut first_rx) = submit(Message::Start(unit.into()));
+        let job = job.unwrap();
+        let (second, merged, mut second_rx) = submit(Message::Start(unit.into()));
+        assert!(merged.is_none());
+        assert!(second > first);
+
+        // Merging into a running job works too.
+        job.start().unwrap();
+        let (third, merged, _) = submit(Message::Start(unit.into()));
+        assert!(merged.is_none());
+
+        job.finish(Ok(()));
+        assert_eq!(first_rx.try_recv().unwrap().result, Some(JobResult::Done));
+        assert_eq!(second_rx.try_recv().unwrap().id, second);
+        assert_eq!(result(second), Some(JobResult::Done));
+        assert_eq!(result(third), Some(JobResult::Done));
+
+        // The job finished, so the next start is a job of its own.
+        let (_, job, _) = submit(Message::Start(unit.into()));
+        job.unwrap().finish(Ok(()));
+    }
+
+    #[test]
+    fn test_merge_failure() {
+        let unit = "merge-failure.service";
+        let (_, job, _) = submit(Message::Stop(unit.into()));
+        let (second, merged, _) = submit(Message::Stop(unit.into()));
+        assert!(merged.is_none());
+        job.unwrap().finish(Err(JobError::timeout("took too long")));
+        let info = get(second).unwrap();
+        assert_eq!(info.result, Some(JobResult::Timeout));
+        assert_eq!(info.error.as_deref(), Some("took too long"));
+    }
+
+    #[test]
+    fn test_no_merge() {
+        let unit = "no-merge.service";
+        let (_, start, _) = submit(Message::Start(unit.into()));
+        // Another unit, or another action that doesn't cancel the start.
+        let (_, other, _) = submit(Message::Start("no-merge-other.service".into()));
+        let (_, restart, _) = submit(Message::Restart(unit.into()));
+        let (_, kill, _) = submit(Message::Kill(unit.into(), Signal::SIGTERM));
+        let (_, kill_again, _) = submit(Message::Kill(unit.into(), Signal::SIGTERM));
+        for job in [start, other, restart, kill, kill_again] {
+            let job = job.unwrap();
+            job.start().unwrap();
+            job.finish(Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_supersede() {
+        let unit = "supersede.service";
+        let (start, start_job, mut start_rx) = submit(Message::Start(unit.into()));
+        let (merged, _, _) = submit(Message::Start(unit.into()));
+        let (stop, stop_job, _) = submit(Message::Stop(unit.into()));
+        let (start_job, stop_job) = (start_job.unwrap(), stop_job.unwrap());
+
+        // The waiting start gives way to the stop, along with what was merged
+        // into it.
+        let err = start_job.start().unwrap_err();
+        assert_eq!(err.result, JobResult::Canceled);
+        assert_eq!(err.reason, format!("superseded by job {}", stop));
+        start_job.finish(Err(err));
+        assert_eq!(start_rx.try_recv().unwrap().result, Some(JobResult::Canceled));
+        assert_eq!(result(start), Some(JobResult::Canceled));
+        assert_eq!(result(merged), Some(JobResult::Canceled));
+
+        // A start that is running already is left to finish.
+        stop_job.start().unwrap();
+        let (_, start_job, _) = submit(Message::Start(unit.into()));
+        let start_job = start_job.unwrap();
+        stop_job.finish(Ok(()));
+        start_job.start().unwrap();
+        start_job.finish(Ok(()));
+    }
+
+    #[test]
+    fn test_supersede_stop() {
+        let unit = "supersede-stop.service";
+        let (stop, stop_job, _) = submit(Message::Stop(unit.into()));
+        let (start, start_job, _) = submit(Message::Start(unit.into()));
+        // A start coming in after that is merged into the new start, not the
+        // stop that is going to be canceled.
+        let (merged, _, _) = submit(Message::Start(unit.into()));
+
+        let stop_job = stop_job.unwrap();
+        let err = stop_job.start().unwrap_err();
+        stop_job.finish(Err(err));
+        start_job.unwrap().finish(Ok(()));
+        assert_eq!(result(stop), Some(JobResult::Canceled));
+        assert_eq!(result(start), Some(JobResult::Done));
+        assert_eq!(result(merged), Some(JobResult::Done));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let units: Vec<String> =
+            (0..5).map(|i| format!("ordering-{}.service", i)).collect();
+        let jobs: Vec<Job> = units
+            .iter()
+            .map(|unit| submit(Message::Start(unit.clone())).1.unwrap())
+            .collect();
+        let ids: Vec<JobId> = jobs.iter().map(|job| job.id).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Listed oldest first, whatever order they finish in.
+        for job in jobs.into_iter().rev() {
+            job.finish(Ok(()));
+        }
+        let listed: Vec<JobId> = list()
+            .into_iter()
+            .filter(|job| job.unit.as_ref().is_some_and(|unit| units.contains(unit)))
+            .map(|job| job.id)
+            .collect();
+        assert_eq!(listed, ids);
+
+        // The jobs that finished first are forgotten first.
+        let first = ids[ids.len() - 1];
+        for _ in 0..FINISHED_JOBS {
+            submit(Message::ReloadUnits).1.unwrap().finish(Ok(()));
+        }
+        assert!(get(first).is_none());
+    }
+}
@@ -1,6 +1,25 @@
 pub mod api;
+pub mod capabilities;
+pub mod cgroup;
+pub mod cmdline;
 pub mod conf;
 pub mod core;
+pub mod credentials;
+pub mod deps;
+pub mod environment;
+pub mod events;
+pub mod execute;
+pub mod init;
+pub mod jobs;
+pub mod logs;
 pub mod monitor;
+pub mod namespace;
+pub mod notify;
+pub mod policy;
+pub mod resources;
+pub mod router;
 pub mod signals;
+pub mod syslog;
+pub mod transaction;
+pub mod unitfile;
 pub mod units;
@@ -0,0 +1,728 @@
+/// logs.rs stores what services write to their stdout and stderr.
+///
+/// Each line is stored along with when it was written, the unit, the PID of
+/// the process that wrote it and which stream it came from. Messages services
+/// send to syslog are stored the same way, see syslog.rs. Every unit gets
+/// its own file of JSON lines in the log directory, which is rotated once it
+/// grows past the maximum size: `web.service.log` becomes
+/// `web.service.log.1` and so on, dropping the oldest past the number of
+/// files to keep.
+///
+/// Until the store is opened, which getupd does when it starts, output meant
+/// for it goes wherever getupd's own output goes.
+///
+/// Entries are looked up by unit, time and priority like with journalctl,
+/// times are understood the same way too, see `parse_time`.
+use crate::unitfile::parse_timespan;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Longer lines are split, like journald does.
+const LINE_MAX: usize = 48 * 1024;
+
+const LOG_FILE_MODE: u32 = 0o640;
+
+/// Priority of output lines, LOG_INFO.
+const PRIORITY_INFO: u8 = 6;
+
+/// Where a log entry came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    Syslog,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Microseconds since the epoch.
+    pub timestamp: u64,
+    pub unit: String,
+    pub pid: Option<u32>,
+    pub stream: LogStream,
+    /// Syslog priority, from 0 for emergencies to 7 for debug messages.
+    pub priority: u8,
+    /// Syslog facility, for messages sent to syslog.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facility: Option<u8>,
+    /// Name the sender of a syslog message gave itself, like `sshd`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    pub message: String,
+}
+
+impl LogEntry {
+    pub fn new(
+        unit: &str,
+        pid: Option<u32>,
+        stream: LogStream,
+        message: &str,
+    ) -> LogEntry {
+        LogEntry {
+            timestamp: now(),
+            unit: unit.to_string(),
+            pid,
+            stream,
+            priority: PRIORITY_INFO,
+            facility: None,
+            identifier: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+struct LogStore {
+    dir: PathBuf,
+    /// Size a file may grow to before it is rotated.
+    max_size: u64,
+    /// How many rotated files are kept for each unit.
+    max_files: usize,
+    /// Files being written to, by unit.
+    files: HashMap<String, File>,
+}
+
+lazy_static! {
+    static ref STORE: Mutex<Option<LogStore>> = Mutex::new(None);
+    /// Entries as they are stored, for whoever follows along.
+    static ref LIVE: broadcast::Sender<LogEntry> = broadcast::channel(1024).0;
+}
+
+/// Open the log store in dir, creating it if needed.
+pub fn open(dir: &str, max_size: u64, max_files: usize) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    // getupd's umask doesn't let anybody in, not even to read the logs.
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o755))?;
+    *STORE.lock().unwrap() = Some(LogStore {
+        dir: PathBuf::from(dir),
+        max_size,
+        max_files,
+        files: HashMap::new(),
+    });
+    Ok(())
+}
+
+/// Whether output can go to the store.
+pub fn is_open() -> bool {
+    STORE.lock().unwrap().is_some()
+}
+
+/// Store the entry, printing it if the store isn't open.
+pub fn append(entry: &LogEntry) {
+    let mut store = STORE.lock().unwrap();
+    let result = match store.as_mut() {
+        Some(store) => store.append(entry),
+        None => {
+            println!("{}[{}]: {}", entry.unit, entry.pid.unwrap_or(0), entry.message);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        println!("Failed to store the log of {}: {}", entry.unit, e);
+    }
+    // Nobody following along is fine.
+    let _ = LIVE.send(entry.clone());
+}
+
+/// Which entries to look up.
+#[derive(Debug, Default, Clone)]
+pub struct LogFilter {
+    pub unit: Option<String>,
+    /// Microseconds since the epoch, like entry timestamps.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Only entries of this priority or more important ones.
+    pub priority: Option<u8>,
+    /// Only this many of the most recent entries.
+    pub lines: Option<usize>,
+}
+
+impl LogFilter {
+    /// Whether the entry is one to look up, regardless of lines.
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.unit.as_ref().is_none_or(|unit| &entry.unit == unit)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+            && self.priority.is_none_or(|priority| entry.priority <= priority)
+    }
+}
+
+/// The stored entries matching filter, oldest first, along with a receiver
+/// for the entries stored from then on.
+pub fn query(filter: &LogFilter) -> (Vec<LogEntry>, broadcast::Receiver<LogEntry>) {
+    // Nothing gets stored while we read, so that no entry is missed or seen
+    // twice by following along afterwards.
+    let store = STORE.lock().unwrap();
+    let live = LIVE.subscribe();
+    let mut entries = match store.as_ref() {
+        Some(store) => store.read(filter),
+        None => vec![],
+    };
+    if let Some(lines) = filter.lines {
+        entries.drain(..entries.len().saturating_sub(lines));
+    }
+    (entries, live)
+}
+
+impl LogStore {
+    fn path(&self, unit: &str, generation: usize) -> PathBuf {
+        match generation {
+            0 => self.dir.join(format!("{}.log", unit)),
+            n => self.dir.join(format!("{}.log.{}", unit, n)),
+        }
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        if !self.files.contains_key(&entry.unit) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(&entry.unit, 0))?;
+            file.set_permissions(fs::Permissions::from_mode(LOG_FILE_MODE))?;
+            self.files.insert(entry.unit.clone(), file);
+        }
+        let file = self.files.get_mut(&entry.unit).unwrap();
+        file.write_all(line.as_bytes())?;
+        if file.metadata()?.len() >= self.max_size {
+            self.rotate(&entry.unit)?;
+        }
+        Ok(())
+    }
+
+    /// Units that have entries in the store.
+    fn units(&self) -> BTreeSet<String> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return BTreeSet::new(),
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let (unit, generation) = match name.rsplit_once(".log") {
+                    Some(parts) => parts,
+                    None => return None,
+                };
+                match generation {
+                    "" => Some(unit.to_string()),
+                    n if n.starts_with('.') && n[1..].parse::<usize>().is_ok() => {
+                        Some(unit.to_string())
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Read the entries matching filter, oldest first.
+    fn read(&self, filter: &LogFilter) -> Vec<LogEntry> {
+        let units = match &filter.unit {
+            // The name ends up in a path, which mustn't lead out of the store.
+            Some(unit) if parse_unit(unit).is_err() => return vec![],
+            Some(unit) => vec![unit.clone()],
+            None => self.units().into_iter().collect(),
+        };
+        let mut entries = vec![];
+        for unit in units {
+            for generation in (0..=self.max_files).rev() {
+                let file = match File::open(self.path(&unit, generation)) {
+                    Ok(file) => file,
+                    Err(_) => continue,
+                };
+                entries.extend(
+                    BufReader::new(file)
+                        .lines()
+                        .map_while(Result::ok)
+                        .filter_map(|line| serde_json::from_str::<LogEntry>(&line).ok())
+                        .filter(|entry| filter.matches(entry)),
+                );
+            }
+        }
+        // Entries of each unit are in order already, this interleaves them.
+        entries.sort_by_key(|entry| entry.timestamp);
+        entries
+    }
+
+    /// Move the files of unit one generation up, dropping the oldest.
+    fn rotate(&mut self, unit: &str) -> io::Result<()> {
+        self.files.remove(unit);
+        let _ = fs::remove_file(self.path(unit, self.max_files));
+        for generation in (0..self.max_files).rev() {
+            let from = self.path(unit, generation);
+            if from.exists() {
+                fs::rename(from, self.path(unit, generation + 1))?;
+            }
+        }
+        if self.max_files == 0 {
+            let _ = fs::remove_file(self.path(unit, 0));
+        }
+        Ok(())
+    }
+}
+
+/// Store every line read from the output of a process of unit, until it is
+/// closed.
+pub fn capture<R: Read + Send + 'static>(
+    unit: &str,
+    pid: u32,
+    stream: LogStream,
+    output: R,
+) {
+    let unit = unit.to_string();
+    let spawned =
+        thread::Builder::new().name(format!("log {}", unit)).spawn(move || {
+            let mut reader = BufReader::new(output);
+            let mut buf = vec![];
+            loop {
+                buf.clear();
+                match reader.by_ref().take(LINE_MAX as u64).read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if buf.last() == Some(&b'\n') {
+                    buf.pop();
+                }
+                let message = String::from_utf8_lossy(&buf);
+                append(&LogEntry::new(&unit, Some(pid), stream, &message));
+            }
+        });
+    if let Err(e) = spawned {
+        println!("Failed to capture the output of {}: {}", pid, e);
+    }
+}
+
+/// Parse the name of a unit to look up entries of, which has to be a file name.
+pub fn parse_unit(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.starts_with('.') || value.contains('/') {
+        return Err(format!("invalid unit name {:?}", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Parse a priority, as a number from 0 to 7 or its name like `err`.
+pub fn parse_priority(value: &str) -> Result<u8, String> {
+    let names = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+    match value.parse::<u8>() {
+        Ok(priority) if priority <= 7 => Ok(priority),
+        Ok(_) => Err(format!("priority {} is out of range", value)),
+        Err(_) => names
+            .iter()
+            .position(|name| *name == value)
+            .map(|priority| priority as u8)
+            .ok_or_else(|| format!("unknown priority {:?}", value)),
+    }
+}
+
+/// Parse a time the way journalctl does, in microseconds since the epoch:
+/// `now`, `today`, `yesterday`, `tomorrow`, `@` followed by seconds since the
+/// epoch, a time span relative to now like `-1h`, `+5min` or `2 days ago`, or
+/// a local date and time like `2020-04-01 13:05:00`, where either the date or
+/// the time, or the seconds, may be left out.
+pub fn parse_time(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let now = now();
+    let span = |span: &str| -> Result<u64, String> {
+        match parse_timespan(span)? {
+            Some(span) => Ok(span.as_micros() as u64),
+            None => Err(format!("invalid time {:?}", value)),
+        }
+    };
+
+    match value {
+        "now" => return Ok(now),
+        "today" => return midnight(0),
+        "yesterday" => return midnight(-1),
+        "tomorrow" => return midnight(1),
+        _ => {}
+    }
+    if let Some(seconds) = value.strip_prefix('@') {
+        return seconds
+            .parse::<f64>()
+            .map(|seconds| (seconds * 1_000_000.0) as u64)
+            .map_err(|_| format!("invalid time {:?}", value));
+    }
+    if let Some(ago) = value.strip_prefix('-').or_else(|| value.strip_suffix("ago")) {
+        return Ok(now.saturating_sub(span(ago)?));
+    }
+    if let Some(ahead) = value.strip_prefix('+').or_else(|| value.strip_suffix("left")) {
+        return Ok(now + span(ahead)?);
+    }
+
+    let invalid = || format!("invalid time {:?}", value);
+    let (date, time) = match value.split_once(' ') {
+        Some((date, time)) => (Some(date), Some(time)),
+        None if value.contains('-') => (Some(value), None),
+        None => (None, Some(value)),
+    };
+    let (year, month, day) = match date {
+        Some(date) => {
+            let parts = numbers(date, '-').ok_or_else(invalid)?;
+            match parts.as_slice() {
+                [year, month, day] => (*year, *month, *day),
+                _ => return Err(invalid()),
+            }
+        }
+        None => today(),
+    };
+    let (hour, minute, second) = match time {
+        Some(time) => {
+            let parts = numbers(time, ':').ok_or_else(invalid)?;
+            match parts.as_slice() {
+                [hour, minute] => (*hour, *minute, 0),
+                [hour, minute, second] => (*hour, *minute, *second),
+                _ => return Err(invalid()),
+            }
+        }
+        None => (0, 0, 0),
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..=23).contains(&hour)
+        || !(0..=59).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return Err(invalid());
+    }
+    local_time(year, month, day, hour, minute, second).ok_or_else(invalid)
+}
+
+fn numbers(value: &str, separator: char) -> Option<Vec<i32>> {
+    value.split(separator).map(|part| part.parse().ok()).collect()
+}
+
+/// Today's local date as year, month and day.
+fn today() -> (i32, i32, i32) {
+    let seconds = (now() / 1_000_000) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&seconds, &mut tm) };
+    (tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday)
+}
+
+/// Local midnight, days from today.
+fn midnight(days: i32) -> Result<u64, String> {
+    let (year, month, day) = today();
+    local_time(year, month, day + days, 0, 0, 0)
+        .ok_or_else(|| "invalid time".to_string())
+}
+
+/// A local date and time in microseconds since the epoch.
+fn local_time(
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    second: i32,
+) -> Option<u64> {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = year - 1900;
+    tm.tm_mon = month - 1;
+    tm.tm_mday = day;
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    // Let mktime work out whether daylight saving time applies.
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => None,
+        seconds if seconds < 0 => None,
+        seconds => Some(seconds as u64 * 1_000_000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A store in a directory of its own, which is removed again when the
+    /// test is done.
+    struct TestStore(LogStore);
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    fn store(test: &str, max_size: u64, max_files: usize) -> TestStore {
+        let dir = env::temp_dir().join(format!("getup-logs-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestStore(LogStore { dir, max_size, max_files, files: HashMap::new() })
+    }
+
+    fn entry(unit: &str, timestamp: u64, message: &str) -> LogEntry {
+        LogEntry {
+            timestamp,
+            ..LogEntry::new(unit, Some(1), LogStream::Stdout, message)
+        }
+    }
+
+    /// The messages of the entries in the file, if it is there.
+    fn messages(path: PathBuf) -> Option<Vec<String>> {
+        let file = File::open(path).ok()?;
+        let lines = BufReader::new(file).lines().map_while(Result::ok);
+        Some(
+            lines
+                .map(|line| serde_json::from_str::<LogEntry>(&line).unwrap().message)
+                .collect(),
+        )
+    }
+
+    /// Size of the line an entry is stored as.
+    fn entry_size(message: &str) -> u64 {
+        serde_json::to_string(&entry("a.service", 1, message)).unwrap().len() as u64 + 1
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        // Room for two entries before the file is rotated.
+        let mut store = store("rotate", 2 * entry_size("0"), 2);
+        let store = &mut store.0;
+        for i in 0..7 {
+            store.append(&entry("a.service", i + 1, &i.to_string())).unwrap();
+        }
+        let generation = |n| messages(store.path("a.service", n));
+        // 0 and 1 were dropped once the third file came around.
+        assert_eq!(generation(2), Some(vec!["2".to_string(), "3".to_string()]));
+        assert_eq!(generation(1), Some(vec!["4".to_string(), "5".to_string()]));
+        assert_eq!(generation(0), Some(vec!["6".to_string()]));
+        assert_eq!(generation(3), None);
+
+        let read: Vec<String> = store
+            .read(&LogFilter { unit: Some("a.service".into()), ..LogFilter::default() })
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(read, vec!["2", "3", "4", "5", "6"]);
+    }
+
+    #[test]
+    fn test_rotate_per_unit() {
+        let mut store = store("per-unit", 2 * entry_size("0"), 1);
+        let store = &mut store.0;
+        for i in 0..3 {
+            store.append(&entry("a.service", i + 1, &i.to_string())).unwrap();
+        }
+        store.append(&entry("b.service", 10, "b")).unwrap();
+        assert_eq!(messages(store.path("a.service", 1)).unwrap().len(), 2);
+        assert_eq!(messages(store.path("b.service", 0)), Some(vec!["b".to_string()]));
+        assert_eq!(messages(store.path("b.service", 1)), None);
+        let units: Vec<String> = store.units().into_iter().collect();
+        assert_eq!(units, vec!["a.service", "b.service"]);
+
+        // Entries of all units, in the order they were written.
+        let read: Vec<String> = store
+            .read(&LogFilter::default())
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(read, vec!["0", "1", "2", "b"]);
+    }
+
+    #[test]
+    fn test_rotate_no_files_kept() {
+        let mut store = store("no-files", 2 * entry_size("0"), 0);
+        let store = &mut store.0;
+        store.append(&entry("a.service", 1, "0")).unwrap();
+        assert_eq!(messages(store.path("a.service", 0)), Some(vec!["0".to_string()]));
+        store.append(&entry("a.service", 2, "1")).unwrap();
+        assert_eq!(messages(store.path("a.service", 0)), None);
+        store.append(&entry("a.service", 3, "2")).unwrap();
+        assert_eq!(messages(store.path("a.service", 0)), Some(vec!["2".to_string()]));
+    }
+
+    #[test]
+    fn test_long_line_rotates() {
+        // An entry bigger than the maximum size gets a file of its own.
+        let mut store = store("long", 10, 3);
+        let store = &mut store.0;
+        store.append(&entry("a.service", 1, "first")).unwrap();
+        store.append(&entry("a.service", 2, "second")).unwrap();
+        assert_eq!(
+            messages(store.path("a.service", 2)),
+            Some(vec!["first".to_string()])
+        );
+        assert_eq!(
+            messages(store.path("a.service", 1)),
+            Some(vec!["second".to_string()])
+        );
+        assert_eq!(messages(store.path("a.service", 0)), None);
+    }
+
+    #[test]
+    fn test_read_unit_outside_store() {
+        let mut store = store("outside", 1024, 1);
+        let store = &mut store.0;
+        store.append(&entry("a.service", 1, "a")).unwrap();
+        // A log file of another store next to this one.
+        let other =
+            store.dir.with_file_name(format!("getup-logs-other-{}", process::id()));
+        fs::create_dir_all(&other).unwrap();
+        let unit = format!("../{}/secret", other.file_name().unwrap().to_string_lossy());
+        let line = serde_json::to_string(&entry(&unit, 1, "secret")).unwrap();
+        fs::write(other.join("secret.log"), line).unwrap();
+
+        let filter = LogFilter { unit: Some(unit), ..LogFilter::default() };
+        let read = store.read(&filter);
+        fs::remove_dir_all(&other).unwrap();
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        let cases = [
+            ("a.service", true),
+            ("getty@tty1.service", true),
+            ("a..service", true),
+            ("", false),
+            (".", false),
+            ("..", false),
+            ("../a.service", false),
+            ("a/b.service", false),
+            ("/etc/shadow", false),
+            (".hidden", false),
+        ];
+        for (value, valid) in cases.iter() {
+            assert_eq!(parse_unit(value).is_ok(), *valid, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_priority() {
+        let cases = [
+            ("0", Ok(0)),
+            ("7", Ok(7)),
+            ("emerg", Ok(0)),
+            ("err", Ok(3)),
+            ("warning", Ok(4)),
+            ("debug", Ok(7)),
+            ("8", Err("priority 8 is out of range")),
+            ("error", Err("unknown priority \"error\"")),
+            ("ERR", Err("unknown priority \"ERR\"")),
+            ("", Err("unknown priority \"\"")),
+        ];
+        for (value, expected) in cases.iter() {
+            let expected = expected.map_err(str::to_string);
+            assert_eq!(parse_priority(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_time() {
+        const HOUR: u64 = 3_600_000_000;
+        let (year, month, day) = today();
+        let midnight = local_time(year, month, day, 0, 0, 0).unwrap();
+        let cases = [
+            ("@1585746300", 1_585_746_300_000_000),
+            ("@1.5", 1_500_000),
+            ("2020-04-01 13:05:00", local_time(2020, 4, 1, 13, 5, 0).unwrap()),
+            ("2020-04-01 13:05", local_time(2020, 4, 1, 13, 5, 0).unwrap()),
+            ("2020-04-01", local_time(2020, 4, 1, 0, 0, 0).unwrap()),
+            ("13:05", local_time(year, month, day, 13, 5, 0).unwrap()),
+            ("13:05:30", local_time(year, month, day, 13, 5, 30).unwrap()),
+            ("today", midnight),
+            (" today ", midnight),
+        ];
+        for (value, expected) in cases.iter() {
+            assert_eq!(parse_time(value), Ok(*expected), "{:?}", value);
+        }
+
+        // Relative to now, which moves on while the test runs.
+        let relative = [
+            ("now", 0, 0),
+            ("-1h", HOUR, 0),
+            ("1h ago", HOUR, 0),
+            ("2 days ago", 48 * HOUR, 0),
+            ("+5min", 0, 5 * 60_000_000),
+            ("5min left", 0, 5 * 60_000_000),
+        ];
+        for (value, before, after) in relative.iter() {
+            let start = now();
+            let time = parse_time(value).unwrap();
+            let end = now();
+            assert!(time + before >= start + after, "{:?}", value);
+            assert!(time + before <= end + after, "{:?}", value);
+        }
+
+        // A day is an hour off when daylight saving time starts or ends.
+        let yesterday = parse_time("yesterday").unwrap();
+        let tomorrow = parse_time("tomorrow").unwrap();
+        assert!((23 * HOUR..=25 * HOUR).contains(&(midnight - yesterday)));
+        assert!((23 * HOUR..=25 * HOUR).contains(&(tomorrow - midnight)));
+
+        let invalid = [
+            "",
+            "soon",
+            "@",
+            "@soon",
+            "-",
+            "-soon",
+            "+1 fortnight",
+            "2020-13-01",
+            "2020-04-32",
+            "2020-04",
+            "2020-04-01 25:00",
+            "13:60",
+            "13",
+            "13:05:00:00",
+            "2020-04-01 13:05 +1h",
+        ];
+        for value in invalid.iter() {
+            assert!(parse_time(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let mut entry = entry("a.service", 100, "a");
+        entry.priority = 4;
+        let filter = |unit: Option<&str>, since, until, priority| LogFilter {
+            unit: unit.map(str::to_string),
+            since,
+            until,
+            priority,
+            lines: Some(1),
+        };
+        let cases = [
+            (filter(None, None, None, None), true),
+            (filter(Some("a.service"), None, None, None), true),
+            (filter(Some("b.service"), None, None, None), false),
+            (filter(Some("a"), None, None, None), false),
+            (filter(None, Some(100), None, None), true),
+            (filter(None, Some(101), None, None), false),
+            (filter(None, None, Some(100), None), true),
+            (filter(None, None, Some(99), None), false),
+            (filter(None, Some(50), Some(150), None), true),
+            (filter(None, None, None, Some(4)), true),
+            (filter(None, None, None, Some(7)), true),
+            (filter(None, None, None, Some(3)), false),
+            (filter(Some("a.service"), Some(50), Some(150), Some(3)), false),
+            (filter(Some("a.service"), Some(50), Some(150), Some(6)), true),
+        ];
+        for (filter, matches) in cases.iter() {
+            assert_eq!(filter.matches(&entry), *matches, "{:?}", filter);
+        }
+    }
+}
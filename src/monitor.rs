@@ -1,51 +1,232 @@
-use crate::units::{CurrState, Service};
-/// monitor.rs includes methods to monitor a running child process.
-use std::io::{self, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
+/// monitor.rs reaps child processes and hands their exit status over to the
+/// unit they belong to.
+///
+/// SIGCHLD is blocked in every thread and read from a signalfd that is
+/// registered with the tokio runtime, so nothing runs until a child actually
+/// changes state. Signals coalesce, so each wake up reaps every exited child
+/// with `waitpid(-1, WNOHANG)` rather than the one the signal was about.
+use crate::cgroup;
+use crate::signals::{self, SignalReceiver};
+use crate::units::ALL_UNITS;
+use lazy_static::lazy_static;
+use nix::errno::Errno::ECHILD;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::Error::Sys;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
 use std::sync::Mutex;
-use std::{thread, time};
+use tokio::sync::{broadcast, oneshot};
 
-pub fn monitor_proc(service: &Mutex<Service>, shared: &AtomicBool) {
-    let thirty_millis = time::Duration::from_millis(30);
-    let ten_sec = time::Duration::from_millis(10000);
-    let mut unlocked_service = service.lock().unwrap();
+lazy_static! {
+    /// Exit events, as the unit name and the exit status of the process.
+    static ref EXITS: broadcast::Sender<(String, ExitStatus)> = broadcast::channel(64).0;
+    /// Processes that were spawned with `spawn`, by PID.
+    static ref WAITERS: Mutex<HashMap<u32, oneshot::Sender<ExitStatus>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Subscribe to the exits of processes that belong to units.
+pub fn subscribe() -> broadcast::Receiver<(String, ExitStatus)> {
+    EXITS.subscribe()
+}
+
+/// Spawn a process that isn't the main process of a unit, like a reload
+/// command, and get its exit status once it has been reaped.
+pub fn spawn(cmd: &mut Command) -> io::Result<(Child, oneshot::Receiver<ExitStatus>)> {
+    // Holding on to the lock while spawning makes the reaper wait until we
+    // know about the process, in case it exits right away.
+    let mut waiters = WAITERS.lock().unwrap();
+    let child = cmd.spawn()?;
+    let (tx, rx) = oneshot::channel();
+    waiters.insert(child.id(), tx);
+    Ok((child, rx))
+}
+
+/// Block SIGCHLD so that it is only delivered to the reaper. This has to be
+/// called before any other thread is started.
+pub fn block_sigchld() -> io::Result<()> {
+    signals::block(&[libc::SIGCHLD])
+}
+
+/// Reap children as they exit, forever. This has to be spawned on a tokio
+/// runtime, after `block_sigchld`.
+pub async fn reap_children() -> io::Result<()> {
+    let mut sigchld = SignalReceiver::new(&[libc::SIGCHLD])?;
+    loop {
+        // Children that exited before we started listening are reaped on
+        // the first time around.
+        reap_all();
+        sigchld.recv().await?;
+    }
+}
 
+/// Hand the units whose cgroup ran empty over to them, forever. This is how
+/// we find out that processes that aren't our children are gone, like the
+/// daemon of a forking service. This has to be spawned on a tokio runtime.
+pub async fn watch_cgroups() {
+    let mut emptied = cgroup::subscribe();
     loop {
-        if shared.load(Ordering::Relaxed) {
-            // Shared is a flag for parent process to signal this process to
-            // terminate.
-            // service.lock().unwrap().send_term();
-            thread::sleep(ten_sec);
-            unlocked_service.kill();
-
-            // Re-raise the flag just to make sure we don't enter this same
-            // loop next time over the loop.
-            shared.store(true, Ordering::Relaxed);
+        let name = match emptied.recv().await {
+            Ok(name) => name,
+            Err(broadcast::RecvError::Lagged(_)) => continue,
+            Err(broadcast::RecvError::Closed) => return,
+        };
+        let service = match ALL_UNITS.lock().unwrap().get_by_name(&name) {
+            Some(unit) => unit.service.clone(),
+            None => continue,
+        };
+        let status = service.lock().unwrap().on_cgroup_empty();
+        if let Some(status) = status {
+            println!("The cgroup of {} is empty, its processes are gone", name);
+            let _ = EXITS.send((name, status));
         }
+    }
+}
 
-        match unlocked_service.try_wait() {
-            Ok(Some(status)) => {
-                println!(
-                    "Child proc with PID {:?} exitted with status {:?}",
-                    unlocked_service.child_id(),
-                    status
-                );
-                unlocked_service.exit_status = Some(status);
-                unlocked_service.current_state = CurrState::Stopped;
-                break;
-            }
-            Ok(None) => {
-                // This really means that the process hasn't exitted yet. In
-                // which case, we don't do anything except sleeping for a while
-                // and re-checking in some time.
-                thread::sleep(thirty_millis);
-                print!(".");
-                io::stdout().flush().unwrap();
-                continue;
+/// Reap every child that has exited and hand its status to its unit.
+/// Returns whether there are children left.
+pub fn reap_all() -> bool {
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => return true,
+            Err(Sys(ECHILD)) => return false,
+            Ok(status) => {
+                if let Some((pid, status)) = exit_status(status) {
+                    dispatch(pid, status);
+                }
             }
             Err(e) => {
-                println!("Failed to wait for the child process: {:?}", e);
+                println!("Failed to wait for child processes: {}", e);
+                return true;
             }
         }
     }
 }
+
+/// Reap the processes spawned with `spawn` that exited, leaving any other
+/// children alone for the tests running alongside.
+#[cfg(test)]
+pub fn reap_spawned() {
+    let pids: Vec<u32> = WAITERS.lock().unwrap().keys().copied().collect();
+    for pid in pids {
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        if let Ok(status) = waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            if let Some((pid, status)) = exit_status(status) {
+                dispatch(pid, status);
+            }
+        }
+    }
+}
+
+/// The PID and exit status of a child that exited. Stopped and continued
+/// children are still around, so there is nothing for those.
+pub fn exit_status(status: WaitStatus) -> Option<(u32, ExitStatus)> {
+    match status {
+        WaitStatus::Exited(pid, code) => {
+            Some((pid.as_raw() as u32, ExitStatus::from_raw((code & 0xff) << 8)))
+        }
+        WaitStatus::Signaled(pid, signal, core_dumped) => {
+            let core = if core_dumped { 0x80 } else { 0 };
+            Some((pid.as_raw() as u32, ExitStatus::from_raw(signal as i32 | core)))
+        }
+        _ => None,
+    }
+}
+
+/// Hand the exit status over to the service that owns the process.
+fn dispatch(pid: u32, status: ExitStatus) {
+    if let Some(waiter) = WAITERS.lock().unwrap().remove(&pid) {
+        // Whoever spawned it may have stopped waiting.
+        let _ = waiter.send(status);
+        return;
+    }
+
+    // Services can be locked for a while, don't hold up everything else
+    // waiting on them.
+    let services: Vec<_> = ALL_UNITS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|unit| (unit.name.clone(), unit.service.clone()))
+        .collect();
+
+    for (name, service) in services {
+        let mut service = service.lock().unwrap();
+        if service.owns(pid) {
+            println!("Child proc with PID {} of {} exitted with {}", pid, name, status);
+            // This can spawn the next command of a oneshot service, which we
+            // find out about the same way.
+            service.on_exit(status);
+            // Nobody listening is fine.
+            let _ = EXITS.send((name, status));
+            return;
+        }
+    }
+    println!("Reaped PID {} which doesn't belong to any unit, {}", pid, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+
+    #[test]
+    fn test_exit_status() {
+        let pid = Pid::from_raw(42);
+        let (found, status) = exit_status(WaitStatus::Exited(pid, 0)).unwrap();
+        assert_eq!(found, 42);
+        assert!(status.success());
+        let (_, status) = exit_status(WaitStatus::Exited(pid, 3)).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(status.signal(), None);
+        let (_, status) = exit_status(WaitStatus::Exited(pid, 255)).unwrap();
+        assert_eq!(status.code(), Some(255));
+
+        let (_, status) =
+            exit_status(WaitStatus::Signaled(pid, Signal::SIGTERM, false)).unwrap();
+        assert_eq!(status.code(), None);
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert!(!status.core_dumped());
+        let (_, status) =
+            exit_status(WaitStatus::Signaled(pid, Signal::SIGSEGV, true)).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSEGV));
+        assert!(status.core_dumped());
+
+        // Still around.
+        assert!(exit_status(WaitStatus::Stopped(pid, Signal::SIGSTOP)).is_none());
+        assert!(exit_status(WaitStatus::Continued(pid)).is_none());
+        assert!(exit_status(WaitStatus::StillAlive).is_none());
+    }
+
+    /// Reap the child the way the reaper does, only without taking the
+    /// children of other tests along.
+    fn reap(pid: u32) {
+        let status = waitpid(Pid::from_raw(pid as i32), None).unwrap();
+        let (reaped, status) = exit_status(status).unwrap();
+        assert_eq!(reaped, pid);
+        dispatch(reaped, status);
+    }
+
+    #[test]
+    fn test_spawned_exit() {
+        let (child, mut exited) =
+            spawn(Command::new("/bin/sh").args(["-c", "exit 7"])).unwrap();
+        reap(child.id());
+        let status = exited.try_recv().unwrap();
+        assert_eq!(status.code(), Some(7));
+        assert!(!WAITERS.lock().unwrap().contains_key(&child.id()));
+
+        let (child, mut exited) =
+            spawn(Command::new("/bin/sh").args(["-c", "kill -KILL $$"])).unwrap();
+        reap(child.id());
+        assert_eq!(exited.try_recv().unwrap().signal(), Some(libc::SIGKILL));
+
+        // Nobody waiting anymore is fine.
+        let (child, exited) = spawn(&mut Command::new("/bin/true")).unwrap();
+        drop(exited);
+        reap(child.id());
+    }
+}
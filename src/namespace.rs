@@ -0,0 +1,1040 @@
+/// namespace.rs sandboxes the file system of services in a mount namespace
+/// of their own, with the directives of systemd.exec(5) like `PrivateTmp=`,
+/// `ProtectSystem=` or `ReadOnlyPaths=`.
+///
+/// Between fork and exec, after the process joined its cgroup and before
+/// `RootDirectory=` and the switch of user, it unshares the mount namespace of
+/// getupd and mounts over its copy. Mounts on the host still propagate into
+/// it, but not the other way around. All of it is worked out before fork as a
+/// list of steps the child only has to go through. Paths that are made
+/// read-only or writable get a bind mount of their own first unless they are
+/// a mount point already, and the mounts below them are remounted as well,
+/// except for the ones the sandbox brought, like the private /tmp.
+///
+/// The private /tmp and /var/tmp of a service are directories on the host,
+/// so that all of its processes share them. They are made when it starts and
+/// removed when it stops.
+use crate::unitfile::parse_bool;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// The directives, as they are called in unit files.
+pub const DIRECTIVES: [&str; 9] = [
+    "PrivateTmp",
+    "PrivateDevices",
+    "ProtectSystem",
+    "ProtectHome",
+    "ReadOnlyPaths",
+    "ReadWritePaths",
+    "InaccessiblePaths",
+    "BindPaths",
+    "TemporaryFileSystem",
+];
+
+/// What the /dev of PrivateDevices= has from the one of the host. The syslog
+/// socket isn't a device, but services would lose it along with /dev.
+const DEVICES: [&str; 7] = ["null", "zero", "full", "random", "urandom", "tty", "log"];
+
+/// Directories ProtectHome= is about.
+const HOMES: [&str; 3] = ["/home", "/root", "/run/user"];
+
+/// API file systems, which ProtectSystem=strict leaves writable.
+const API_FILE_SYSTEMS: [&str; 3] = ["/dev", "/proc", "/sys"];
+
+lazy_static! {
+    /// Where the staging directories of units and the nodes that make paths
+    /// inaccessible are, see `set_directory`.
+    static ref DIRECTORY: Mutex<PathBuf> = Mutex::new(PathBuf::from("/run/getup/sandbox"));
+}
+
+/// Set the directory the sandboxes of units are prepared in.
+pub fn set_directory(path: &str) {
+    *DIRECTORY.lock().unwrap() = PathBuf::from(path);
+}
+
+/// ProtectSystem=, which makes more of the system read-only the higher it is.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ProtectSystem {
+    No,
+    /// /usr and the boot loader.
+    Yes,
+    /// /etc as well.
+    Full,
+    /// Everything but the API file systems.
+    Strict,
+}
+
+/// ProtectHome=, what becomes of the home directories.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ProtectHome {
+    No,
+    /// Inaccessible.
+    Yes,
+    ReadOnly,
+    /// Empty and read-only.
+    Tmpfs,
+}
+
+/// A path of ReadOnlyPaths= and the like.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MountPath {
+    pub path: String,
+    /// The `-` prefix, a missing path is skipped.
+    pub ignore_missing: bool,
+}
+
+/// A bind mount of BindPaths=, `SOURCE[:DESTINATION[:OPTIONS]]`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Bind {
+    pub source: String,
+    pub destination: String,
+    /// The `-` prefix, a missing source is skipped.
+    pub ignore_missing: bool,
+    /// Whether the mounts below the source come along, unless `norbind`.
+    pub recursive: bool,
+}
+
+/// A tmpfs of TemporaryFileSystem=, `PATH[:OPTIONS]`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TemporaryFileSystem {
+    pub path: String,
+    pub options: String,
+}
+
+/// The sandboxing directives of a service. Unless one of them is set, the
+/// service stays in the mount namespace of getupd.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sandbox {
+    pub private_tmp: bool,
+    /// Only pseudo devices like /dev/null in /dev.
+    pub private_devices: bool,
+    pub protect_system: ProtectSystem,
+    pub protect_home: ProtectHome,
+    pub read_only_paths: Vec<MountPath>,
+    /// Paths to keep writable within read-only ones.
+    pub read_write_paths: Vec<MountPath>,
+    pub inaccessible_paths: Vec<MountPath>,
+    pub bind_paths: Vec<Bind>,
+    pub temporary_file_system: Vec<TemporaryFileSystem>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            private_tmp: false,
+            private_devices: false,
+            protect_system: ProtectSystem::No,
+            protect_home: ProtectHome::No,
+            read_only_paths: vec![],
+            read_write_paths: vec![],
+            inaccessible_paths: vec![],
+            bind_paths: vec![],
+            temporary_file_system: vec![],
+        }
+    }
+}
+
+impl Sandbox {
+    /// Set the directive key to value as it is written in unit files. Paths
+    /// of more than one line add up.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let boolean =
+            || parse_bool(value).ok_or_else(|| format!("{:?} is not a boolean", value));
+        match key {
+            "PrivateTmp" => self.private_tmp = boolean()?,
+            "PrivateDevices" => self.private_devices = boolean()?,
+            "ProtectSystem" => {
+                self.protect_system = match (value, parse_bool(value)) {
+                    (_, Some(false)) => ProtectSystem::No,
+                    (_, Some(true)) => ProtectSystem::Yes,
+                    ("full", None) => ProtectSystem::Full,
+                    ("strict", None) => ProtectSystem::Strict,
+                    _ => return Err(format!("invalid ProtectSystem= {:?}", value)),
+                }
+            }
+            "ProtectHome" => {
+                self.protect_home = match (value, parse_bool(value)) {
+                    (_, Some(false)) => ProtectHome::No,
+                    (_, Some(true)) => ProtectHome::Yes,
+                    ("read-only", None) => ProtectHome::ReadOnly,
+                    ("tmpfs", None) => ProtectHome::Tmpfs,
+                    _ => return Err(format!("invalid ProtectHome= {:?}", value)),
+                }
+            }
+            "ReadOnlyPaths" => self.read_only_paths.extend(parse_paths(value)?),
+            "ReadWritePaths" => self.read_write_paths.extend(parse_paths(value)?),
+            "InaccessiblePaths" => self.inaccessible_paths.extend(parse_paths(value)?),
+            "BindPaths" => {
+                for bind in value.split_whitespace() {
+                    self.bind_paths.push(parse_bind(bind)?);
+                }
+            }
+            "TemporaryFileSystem" => {
+                for tmpfs in value.split_whitespace() {
+                    let (path, options) = tmpfs.split_once(':').unwrap_or((tmpfs, ""));
+                    absolute(path)?;
+                    self.temporary_file_system.push(TemporaryFileSystem {
+                        path: path.to_string(),
+                        options: options.to_string(),
+                    });
+                }
+            }
+            _ => return Err(format!("unknown directive {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Whether the service gets a mount namespace of its own.
+    pub fn is_enabled(&self) -> bool {
+        *self != Sandbox::default()
+    }
+
+    /// Make what the sandbox of unit needs on the host before its processes
+    /// are spawned. A private /tmp left over from before starts out empty.
+    pub fn prepare(&self, unit: &str) -> io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let directory = DIRECTORY.lock().unwrap().clone();
+        let mut builder = DirBuilder::new();
+        builder.recursive(true).mode(0o755).create(&directory)?;
+        // Nodes nobody may do anything with, to mount over paths.
+        let inaccessible = directory.join("inaccessible");
+        builder.create(&inaccessible)?;
+        builder.create(inaccessible.join("dir"))?;
+        if !inaccessible.join("reg").exists() {
+            fs::File::create(inaccessible.join("reg"))?;
+        }
+        for node in &["dir", "reg"] {
+            fs::set_permissions(
+                inaccessible.join(node),
+                fs::Permissions::from_mode(0o000),
+            )?;
+        }
+        if self.private_devices {
+            builder.mode(0o700).create(directory.join(unit).join("dev"))?;
+        }
+        // Sources of BindPaths= are bound to a node here first, while nothing
+        // hides them yet.
+        let staging = directory.join(unit).join("bind");
+        remove_all(&staging)?;
+        for (i, bind) in self.bind_paths.iter().enumerate() {
+            let stage = staging.join(i.to_string());
+            match fs::metadata(&bind.source) {
+                Ok(metadata) if metadata.is_dir() => {
+                    builder.mode(0o700).create(stage)?
+                }
+                Ok(_) => {
+                    builder.mode(0o700).create(&staging)?;
+                    fs::File::create(stage)?;
+                }
+                Err(e) if bind.ignore_missing && e.kind() == io::ErrorKind::NotFound => {
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("{}: {}", bind.source, e),
+                    ))
+                }
+            }
+        }
+        if self.private_tmp {
+            for base in private_tmp_bases() {
+                let private = private_tmp(base, unit);
+                remove_all(&private)?;
+                DirBuilder::new().mode(0o700).create(&private)?;
+                DirBuilder::new().mode(0o1777).create(private.join("tmp"))?;
+                fs::set_permissions(
+                    private.join("tmp"),
+                    fs::Permissions::from_mode(0o1777),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove what `prepare` made for unit that isn't shared with others.
+    pub fn cleanup(&self, unit: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut paths = vec![DIRECTORY.lock().unwrap().join(unit)];
+        paths.extend(private_tmp_bases().map(|base| private_tmp(base, unit)));
+        for path in paths {
+            if let Err(e) = remove_all(&path) {
+                println!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Have the command set up its mount namespace before it executes
+    /// anything.
+    pub fn apply(&self, cmd: &mut Command, unit: &str) -> io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let steps = self.plan(unit)?.steps;
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::unshare(libc::CLONE_NEWNS) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Keep our mounts from propagating to the host.
+                let root = b"/\0".as_ptr() as *const libc::c_char;
+                let flags = libc::MS_REC | libc::MS_SLAVE;
+                if libc::mount(
+                    std::ptr::null(),
+                    root,
+                    std::ptr::null(),
+                    flags,
+                    std::ptr::null(),
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                for (step, ignore_missing) in &steps {
+                    match step.run() {
+                        Err(e)
+                            if *ignore_missing
+                                && e.raw_os_error() == Some(libc::ENOENT) => {}
+                        result => result?,
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Work out the steps, in order. Mounts that are more specific come
+    /// later, so what ReadWritePaths= makes writable stays so.
+    fn plan(&self, unit: &str) -> io::Result<Plan> {
+        let mut plan = Plan { steps: vec![], own: vec![], mounts: mounts()? };
+        let directory = DIRECTORY.lock().unwrap().clone();
+
+        let staging = directory.join(unit).join("bind");
+        let mut binds = vec![];
+        for (i, bind) in self.bind_paths.iter().enumerate() {
+            // Missing sources that may be missing have no node.
+            let stage = staging.join(i.to_string());
+            if stage.exists() {
+                plan.bind(Path::new(&bind.source), &stage, bind.recursive, false)?;
+                binds.push((stage, bind));
+            }
+        }
+        if self.private_devices {
+            plan.private_devices(&directory.join(unit).join("dev"))?;
+        }
+        if self.private_tmp {
+            for base in private_tmp_bases() {
+                let private = private_tmp(base, unit).join("tmp");
+                plan.bind(&private, Path::new(base), false, false)?;
+                plan.own.push(base.to_string());
+            }
+        }
+        // Those closer to / first, so they don't hide the others.
+        let mut layers: Vec<Layer> = self
+            .temporary_file_system
+            .iter()
+            .map(Layer::Tmpfs)
+            .chain(binds.into_iter().map(|(stage, bind)| Layer::Bind(stage, bind)))
+            .collect();
+        layers.sort_by_key(|layer| layer.path().matches('/').count());
+        for layer in layers {
+            match layer {
+                Layer::Tmpfs(tmpfs) => {
+                    plan.mount_point(Path::new(&tmpfs.path), true)?;
+                    let (flags, data) = tmpfs_options(&tmpfs.options);
+                    plan.tmpfs(&tmpfs.path, flags, &data, false)?;
+                }
+                Layer::Bind(stage, bind) => {
+                    let destination = Path::new(&bind.destination);
+                    plan.mount_point(destination, stage.is_dir())?;
+                    plan.bind(&stage, Path::new(&bind.destination), true, false)?;
+                    plan.own.push(bind.destination.clone());
+                }
+            }
+        }
+
+        let mut read_only: Vec<(&str, bool)> = match self.protect_system {
+            ProtectSystem::No => vec![],
+            ProtectSystem::Yes => vec![("/usr", false), ("/boot", true), ("/efi", true)],
+            ProtectSystem::Full => {
+                vec![("/usr", false), ("/boot", true), ("/efi", true), ("/etc", false)]
+            }
+            ProtectSystem::Strict => vec![("/", false)],
+        };
+        let mut inaccessible = vec![];
+        match self.protect_home {
+            ProtectHome::No => {}
+            ProtectHome::Yes => {
+                inaccessible.extend(HOMES.iter().map(|home| (*home, true)))
+            }
+            ProtectHome::ReadOnly => {
+                read_only.extend(HOMES.iter().map(|home| (*home, true)))
+            }
+            ProtectHome::Tmpfs => {
+                let flags =
+                    libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+                for home in &HOMES {
+                    plan.tmpfs(home, flags, "mode=0755", true)?;
+                }
+            }
+        }
+        let paths = |paths: &'_ [MountPath]| -> Vec<(String, bool)> {
+            paths.iter().map(|path| (path.path.clone(), path.ignore_missing)).collect()
+        };
+        let mut read_only: Vec<(String, bool)> = read_only
+            .into_iter()
+            .map(|(path, optional)| (path.to_string(), optional))
+            .collect();
+        read_only.extend(paths(&self.read_only_paths));
+        let mut inaccessible: Vec<(String, bool)> = inaccessible
+            .into_iter()
+            .map(|(path, optional)| (path.to_string(), optional))
+            .collect();
+        inaccessible.extend(paths(&self.inaccessible_paths));
+
+        for (path, ignore_missing) in &read_only {
+            plan.remount(path, true, *ignore_missing)?;
+        }
+        for (path, ignore_missing) in &inaccessible {
+            plan.inaccessible(&directory, path, *ignore_missing)?;
+        }
+        for (path, ignore_missing) in paths(&self.read_write_paths) {
+            plan.remount(&path, false, ignore_missing)?;
+        }
+        Ok(plan)
+    }
+}
+
+/// What TemporaryFileSystem= and BindPaths= mount, the latter from where its
+/// source was staged.
+enum Layer<'a> {
+    Tmpfs(&'a TemporaryFileSystem),
+    Bind(PathBuf, &'a Bind),
+}
+
+impl Layer<'_> {
+    fn path(&self) -> &str {
+        match self {
+            Layer::Tmpfs(tmpfs) => &tmpfs.path,
+            Layer::Bind(_, bind) => &bind.destination,
+        }
+    }
+}
+
+/// A step of setting up a mount namespace, which is run after fork and so
+/// only makes system calls.
+enum Step {
+    Mount {
+        source: Option<CString>,
+        target: CString,
+        fstype: Option<CString>,
+        flags: libc::c_ulong,
+        data: Option<CString>,
+    },
+    /// Remount the bind mount at target with flags, keeping the likes of
+    /// nosuid it has.
+    Remount {
+        target: CString,
+        flags: libc::c_ulong,
+    },
+    Mkdir {
+        path: CString,
+        mode: libc::mode_t,
+    },
+    CreateFile {
+        path: CString,
+    },
+    /// Create a directory, or a file, to mount on unless there is something.
+    MountPoint {
+        path: CString,
+        directory: bool,
+    },
+    Symlink {
+        target: CString,
+        path: CString,
+    },
+}
+
+impl Step {
+    fn run(&self) -> io::Result<()> {
+        let ptr = |string: &Option<CString>| {
+            string.as_ref().map_or(std::ptr::null(), |string| string.as_ptr())
+        };
+        let result = unsafe {
+            match self {
+                Step::Mount { source, target, fstype, flags, data } => libc::mount(
+                    ptr(source),
+                    target.as_ptr(),
+                    ptr(fstype),
+                    *flags,
+                    ptr(data) as *const libc::c_void,
+                ),
+                Step::Remount { target, flags } => {
+                    let mut stat: libc::statvfs = std::mem::zeroed();
+                    if libc::statvfs(target.as_ptr(), &mut stat) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let flags = flags
+                        | libc::MS_BIND
+                        | libc::MS_REMOUNT
+                        | kept_flags(stat.f_flag);
+                    libc::mount(
+                        std::ptr::null(),
+                        target.as_ptr(),
+                        std::ptr::null(),
+                        flags,
+                        std::ptr::null(),
+                    )
+                }
+                Step::Mkdir { path, mode } => {
+                    // The umask doesn't get a say.
+                    match libc::mkdir(path.as_ptr(), *mode) {
+                        0 => libc::chmod(path.as_ptr(), *mode),
+                        error => error,
+                    }
+                }
+                Step::CreateFile { path } => {
+                    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC;
+                    match libc::open(path.as_ptr(), flags, 0o666) {
+                        -1 => -1,
+                        fd => libc::close(fd),
+                    }
+                }
+                Step::MountPoint { path, directory } => {
+                    let created = if *directory {
+                        libc::mkdir(path.as_ptr(), 0o755)
+                    } else {
+                        let flags = libc::O_WRONLY
+                            | libc::O_CREAT
+                            | libc::O_EXCL
+                            | libc::O_CLOEXEC;
+                        match libc::open(path.as_ptr(), flags, 0o644) {
+                            -1 => -1,
+                            fd => libc::close(fd),
+                        }
+                    };
+                    let exists =
+                        io::Error::last_os_error().raw_os_error() == Some(libc::EEXIST);
+                    if created != 0 && exists {
+                        0
+                    } else {
+                        created
+                    }
+                }
+                Step::Symlink { target, path } => {
+                    libc::symlink(target.as_ptr(), path.as_ptr())
+                }
+            }
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The mount flags to keep from the `statvfs` flags of a mount.
+fn kept_flags(flags: libc::c_ulong) -> libc::c_ulong {
+    [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .iter()
+    .filter(|(st, _)| flags & st != 0)
+    .fold(0, |kept, (_, ms)| kept | ms)
+}
+
+/// A mount point of getupd and whether it is read-only.
+struct Mount {
+    path: String,
+    read_only: bool,
+}
+
+/// The steps for a sandbox, along with what they are worked out from.
+struct Plan {
+    /// Steps and whether they may fail because a path is missing.
+    steps: Vec<(Step, bool)>,
+    /// Where the sandbox mounts things of its own, which paths made read-only
+    /// or writable don't affect.
+    own: Vec<String>,
+    /// The mounts of getupd, which the namespace starts out as a copy of.
+    mounts: Vec<Mount>,
+}
+
+impl Plan {
+    fn mount(
+        &mut self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: libc::c_ulong,
+        data: Option<&str>,
+        ignore_missing: bool,
+    ) -> io::Result<()> {
+        let step = Step::Mount {
+            source: source.map(path_cstring).transpose()?,
+            target: path_cstring(target)?,
+            fstype: fstype.map(cstring).transpose()?,
+            flags,
+            data: data.map(cstring).transpose()?,
+        };
+        self.steps.push((step, ignore_missing));
+        Ok(())
+    }
+
+    fn mount_point(&mut self, path: &Path, directory: bool) -> io::Result<()> {
+        let path = path_cstring(path)?;
+        self.steps.push((Step::MountPoint { path, directory }, false));
+        Ok(())
+    }
+
+    fn bind(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        recursive: bool,
+        ignore_missing: bool,
+    ) -> io::Result<()> {
+        let flags = libc::MS_BIND | if recursive { libc::MS_REC } else { 0 };
+        self.mount(Some(source), target, None, flags, None, ignore_missing)
+    }
+
+    fn tmpfs(
+        &mut self,
+        path: &str,
+        flags: libc::c_ulong,
+        data: &str,
+        ignore_missing: bool,
+    ) -> io::Result<()> {
+        let tmpfs = Some(Path::new("tmpfs"));
+        self.mount(
+            tmpfs,
+            Path::new(path),
+            Some("tmpfs"),
+            flags,
+            Some(data),
+            ignore_missing,
+        )?;
+        self.own.push(path.to_string());
+        Ok(())
+    }
+
+    /// A /dev with only pseudo devices, put together in staging and moved
+    /// over the one of the host.
+    fn private_devices(&mut self, staging: &Path) -> io::Result<()> {
+        let tmpfs = Some(Path::new("tmpfs"));
+        let flags = libc::MS_NOSUID | libc::MS_NOEXEC;
+        self.mount(tmpfs, staging, Some("tmpfs"), flags, Some("mode=0755"), false)?;
+        for device in &DEVICES {
+            let path = path_cstring(&staging.join(device))?;
+            self.steps.push((Step::CreateFile { path }, false));
+            self.bind(
+                &Path::new("/dev").join(device),
+                &staging.join(device),
+                false,
+                true,
+            )?;
+        }
+        for (dir, mode) in &[("shm", 0o1777), ("pts", 0o755)] {
+            let path = path_cstring(&staging.join(dir))?;
+            self.steps.push((Step::Mkdir { path, mode: *mode }, false));
+        }
+        let devpts = Some(Path::new("devpts"));
+        let options = "newinstance,ptmxmode=0666,mode=0620";
+        self.mount(
+            devpts,
+            &staging.join("pts"),
+            Some("devpts"),
+            0,
+            Some(options),
+            true,
+        )?;
+        for (link, target) in &[
+            ("ptmx", "pts/ptmx"),
+            ("fd", "/proc/self/fd"),
+            ("stdin", "/proc/self/fd/0"),
+            ("stdout", "/proc/self/fd/1"),
+            ("stderr", "/proc/self/fd/2"),
+        ] {
+            let step = Step::Symlink {
+                target: cstring(target)?,
+                path: path_cstring(&staging.join(link))?,
+            };
+            self.steps.push((step, false));
+        }
+        self.mount(Some(staging), Path::new("/dev"), None, libc::MS_MOVE, None, false)?;
+        self.own.push("/dev".to_string());
+        Ok(())
+    }
+
+    /// Make path read-only, or writable, along with the mounts below it.
+    fn remount(
+        &mut self,
+        path: &str,
+        read_only: bool,
+        ignore_missing: bool,
+    ) -> io::Result<()> {
+        let mount = self.mounts.iter().find(|mount| mount.path == path);
+        // What is read-only on the host stays so.
+        if !read_only && mount.is_some_and(|mount| mount.read_only) {
+            return Ok(());
+        }
+        let is_mount_point = mount.is_some() || self.own.iter().any(|own| own == path);
+        if !is_mount_point {
+            let path = Path::new(path);
+            self.bind(path, path, true, ignore_missing)?;
+        }
+        let flags = if read_only { libc::MS_RDONLY } else { 0 };
+        let mut targets = vec![path.to_string()];
+        // Strict leaves the API file systems alone.
+        let except: &[&str] = if path == "/" { &API_FILE_SYSTEMS } else { &[] };
+        targets.extend(
+            self.mounts
+                .iter()
+                .filter(|mount| is_below(&mount.path, path) && mount.path != path)
+                .filter(|mount| read_only || !mount.read_only)
+                .filter(|mount| !self.own.iter().any(|own| is_below(&mount.path, own)))
+                .filter(|mount| {
+                    !except.iter().any(|except| is_below(&mount.path, except))
+                })
+                .map(|mount| mount.path.clone()),
+        );
+        for target in targets {
+            let target = cstring(&target)?;
+            self.steps.push((Step::Remount { target, flags }, ignore_missing));
+        }
+        Ok(())
+    }
+
+    /// Mount a node nobody may do anything with over path.
+    fn inaccessible(
+        &mut self,
+        directory: &Path,
+        path: &str,
+        ignore_missing: bool,
+    ) -> io::Result<()> {
+        let node = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => "dir",
+            Ok(_) => "reg",
+            Err(e) if ignore_missing && e.kind() == io::ErrorKind::NotFound => {
+                return Ok(())
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        };
+        let source = directory.join("inaccessible").join(node);
+        self.bind(&source, Path::new(path), false, false)?;
+        let target = cstring(path)?;
+        let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+        self.steps.push((Step::Remount { target, flags }, false));
+        self.own.push(path.to_string());
+        Ok(())
+    }
+}
+
+/// Whether path is at or below directory.
+fn is_below(path: &str, directory: &str) -> bool {
+    directory == "/"
+        || path == directory
+        || path.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The mounts of getupd, from /proc/self/mountinfo.
+fn mounts() -> io::Result<Vec<Mount>> {
+    Ok(parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo")?))
+}
+
+/// The mount point is the fifth field and its options the sixth, with spaces
+/// and the like escaped in octal.
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').skip(4);
+            let path = unescape(fields.next()?);
+            let read_only = fields.next()?.split(',').any(|option| option == "ro");
+            Some(Mount { path, read_only })
+        })
+        .collect()
+}
+
+fn unescape(field: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let octal = tail.get(..3).and_then(|digits| {
+            u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok()
+        });
+        match (byte, octal) {
+            (b'\\', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The mount flags and data of the options of TemporaryFileSystem=, which is
+/// mode 0755 unless they say otherwise.
+fn tmpfs_options(options: &str) -> (libc::c_ulong, String) {
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    let mut data = vec![];
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option {
+            "ro" => flags |= libc::MS_RDONLY,
+            "rw" => flags &= !libc::MS_RDONLY,
+            "nosuid" => flags |= libc::MS_NOSUID,
+            "suid" => flags &= !libc::MS_NOSUID,
+            "nodev" => flags |= libc::MS_NODEV,
+            "dev" => flags &= !libc::MS_NODEV,
+            "noexec" => flags |= libc::MS_NOEXEC,
+            "exec" => flags &= !libc::MS_NOEXEC,
+            option => data.push(option),
+        }
+    }
+    if !data.iter().any(|option| option.starts_with("mode=")) {
+        data.push("mode=0755");
+    }
+    (flags, data.join(","))
+}
+
+/// Where the private /tmp of unit is made, for each of /tmp and /var/tmp.
+fn private_tmp(base: &str, unit: &str) -> PathBuf {
+    Path::new(base).join(format!("getup-private-{}", unit))
+}
+
+/// The /tmp directories to make private, /var/tmp only if there is one.
+fn private_tmp_bases() -> impl Iterator<Item = &'static str> {
+    ["/tmp", "/var/tmp"].iter().copied().filter(|base| Path::new(base).is_dir())
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Paths, absolute and optional with `-`, separated by spaces.
+fn parse_paths(value: &str) -> Result<Vec<MountPath>, String> {
+    value
+        .split_whitespace()
+        .map(|path| {
+            let (path, ignore_missing) = match path.strip_prefix('-') {
+                Some(path) => (path, true),
+                None => (path, false),
+            };
+            absolute(path)?;
+            Ok(MountPath { path: path.to_string(), ignore_missing })
+        })
+        .collect()
+}
+
+fn parse_bind(bind: &str) -> Result<Bind, String> {
+    let (source, ignore_missing) = match bind.strip_prefix('-') {
+        Some(bind) => (bind, true),
+        None => (bind, false),
+    };
+    let mut parts = source.splitn(3, ':');
+    let source = parts.next().unwrap_or_default();
+    let destination = parts.next().unwrap_or(source);
+    let recursive = match parts.next() {
+        None | Some("rbind") => true,
+        Some("norbind") => false,
+        Some(option) => return Err(format!("unknown bind option {:?}", option)),
+    };
+    absolute(source)?;
+    absolute(destination)?;
+    Ok(Bind {
+        source: source.to_string(),
+        destination: destination.to_string(),
+        ignore_missing,
+        recursive,
+    })
+}
+
+fn absolute(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(format!("{:?} is not an absolute path", path));
+    }
+    Ok(())
+}
+
+fn cstring(string: &str) -> io::Result<CString> {
+    CString::new(string).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    fn sandbox(directives: &[(&str, &str)]) -> Result<Sandbox, String> {
+        let mut sandbox = Sandbox::default();
+        for (key, value) in directives {
+            sandbox.set(key, value)?;
+        }
+        Ok(sandbox)
+    }
+
+    /// Run script with /bin/sh in the sandbox of unit, returning what it
+    /// printed, or None if it failed to spawn.
+    fn dump(unit: &str, sandbox: &Sandbox, script: &str) -> Option<String> {
+        sandbox.prepare(unit).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", script]).stdout(Stdio::piped()).stderr(Stdio::null());
+        sandbox.apply(&mut cmd, unit).unwrap();
+        let output = cmd.output().ok();
+        sandbox.cleanup(unit);
+        Some(String::from_utf8_lossy(&output?.stdout).trim().to_string())
+    }
+
+    #[test]
+    fn test_set() {
+        let sandbox = sandbox(&[
+            ("PrivateTmp", "yes"),
+            ("ProtectSystem", "strict"),
+            ("ProtectHome", "read-only"),
+            ("ReadWritePaths", "/var/lib/web -/var/cache/web"),
+            ("BindPaths", "/srv/data:/data:norbind -/srv/optional"),
+            ("TemporaryFileSystem", "/var:ro,size=10M"),
+        ])
+        .unwrap();
+        assert!(sandbox.is_enabled());
+        assert_eq!(sandbox.protect_system, ProtectSystem::Strict);
+        assert_eq!(sandbox.protect_home, ProtectHome::ReadOnly);
+        assert_eq!(
+            sandbox.read_write_paths[1],
+            MountPath { path: "/var/cache/web".into(), ignore_missing: true }
+        );
+        assert_eq!(
+            sandbox.bind_paths[0],
+            Bind {
+                source: "/srv/data".into(),
+                destination: "/data".into(),
+                ignore_missing: false,
+                recursive: false,
+            }
+        );
+        assert_eq!(sandbox.bind_paths[1].destination, "/srv/optional");
+        assert_eq!(
+            tmpfs_options(&sandbox.temporary_file_system[0].options),
+            (
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                "size=10M,mode=0755".into()
+            )
+        );
+        assert!(!Sandbox::default().is_enabled());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(sandbox(&[("PrivateTmp", "maybe")]).is_err());
+        assert!(sandbox(&[("ProtectSystem", "everything")]).is_err());
+        assert!(sandbox(&[("ReadOnlyPaths", "relative/path")]).is_err());
+        assert!(sandbox(&[("BindPaths", "/a:/b:bind")]).is_err());
+        assert!(sandbox(&[("TemporaryFileSystem", "tmp")]).is_err());
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "28 1 254:0 / / rw,relatime - ext4 /dev/vda rw\n\
+                         29 28 254:16 / /mnt/with\\040space ro,nosuid - ext4 /dev/vdb ro\n";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts[0].path, "/");
+        assert!(!mounts[0].read_only);
+        assert_eq!(mounts[1].path, "/mnt/with space");
+        assert!(mounts[1].read_only);
+        assert!(is_below("/var/tmp", "/var"));
+        assert!(!is_below("/variable", "/var"));
+        assert!(is_below("/usr", "/"));
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_private_tmp() {
+        // In /tmp itself rather than TMPDIR, as that is what is made private.
+        let marker = format!("/tmp/getup-test-{}", std::process::id());
+        fs::write(&marker, "").unwrap();
+        let sandbox = sandbox(&[("PrivateTmp", "yes")]).unwrap();
+        let script =
+            format!("test -e {} && echo host; touch /tmp/mine; ls /tmp", marker);
+        assert_eq!(dump("tmp-test.service", &sandbox, &script).unwrap(), "mine");
+        assert!(!Path::new("/tmp/mine").exists());
+        assert!(!private_tmp("/tmp", "tmp-test.service").exists());
+        fs::remove_file(marker).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_protect_system() {
+        let dir =
+            std::env::temp_dir().join(format!("getup-strict-{}", std::process::id()));
+        let writable = dir.join("rw");
+        fs::create_dir_all(&writable).unwrap();
+        let marker = dir.join("bind");
+        fs::write(&marker, "bound").unwrap();
+        let bind = format!("{}:/mnt/bound -/nonexistent", marker.display());
+        let sandbox = sandbox(&[
+            ("ProtectSystem", "strict"),
+            ("ReadWritePaths", writable.to_str().unwrap()),
+            ("InaccessiblePaths", "/etc/hostname -/nonexistent"),
+            ("TemporaryFileSystem", "/mnt"),
+            ("BindPaths", &bind),
+        ])
+        .unwrap();
+        let script = format!(
+            "touch /usr/getup-test || echo ro; touch {}/a || echo ro; \
+             touch {}/a && echo rw; test -s /etc/hostname || echo hidden; \
+             touch /mnt/a && echo tmpfs; touch /proc/self/comm && echo proc; \
+             cat /mnt/bound",
+            dir.display(),
+            writable.display()
+        );
+        assert_eq!(
+            dump("strict-test.service", &sandbox, &script).unwrap(),
+            "ro\nro\nrw\nhidden\ntmpfs\nproc\nbound"
+        );
+        assert!(writable.join("a").exists());
+        assert!(!dir.join("a").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_private_devices() {
+        let sandbox = sandbox(&[("PrivateDevices", "yes")]).unwrap();
+        let script = "echo discarded > /dev/null && ls /dev | tr '\\n' ' '";
+        let devices = dump("dev-test.service", &sandbox, script).unwrap();
+        assert!(devices.contains("null") && devices.contains("urandom"));
+        assert!(!devices.contains("vda") && !devices.contains("kmsg"));
+    }
+}
@@ -0,0 +1,114 @@
+/// notify.rs implements the receiving end of the sd_notify protocol.
+///
+/// getupd binds a single datagram socket and passes its path to services in
+/// `NOTIFY_SOCKET`. Services send newline separated `KEY=VALUE` messages on it,
+/// we use the credentials the kernel attaches to each datagram to find out
+/// which service sent it.
+use crate::units::ALL_UNITS;
+use lazy_static::lazy_static;
+use nix::sys::socket::{
+    recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, UnixCredentials,
+};
+use nix::sys::uio::IoVec;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+lazy_static! {
+    /// Path of the notification socket, once it has been bound.
+    static ref SOCKET_PATH: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Path to pass to services as `NOTIFY_SOCKET`, if we are listening.
+pub fn socket_path() -> Option<String> {
+    SOCKET_PATH.lock().unwrap().clone()
+}
+
+/// Bind the notification socket at path and start handling messages on it in
+/// a background thread.
+pub fn listen(path: &str) -> io::Result<thread::JoinHandle<()>> {
+    // Only root gets to put anything next to it, so nobody can swap in a
+    // socket of their own.
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o755))?;
+    }
+    // Remove a stale socket left behind by a previous run.
+    let _ = fs::remove_file(path);
+    let socket = UnixDatagram::bind(path)?;
+    // Services may run as any user and they all need to be able to write.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    setsockopt(socket.as_raw_fd(), sockopt::PassCred, &true)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    // Wake up every now and then to check on watchdogs.
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    *SOCKET_PATH.lock().unwrap() = Some(path.to_string());
+    Ok(thread::spawn(move || loop {
+        if let Some((pid, message)) = receive(&socket) {
+            dispatch(pid, &message);
+        }
+        check_watchdogs();
+    }))
+}
+
+/// Receive one datagram, returning the PID of the sender and the message.
+fn receive(socket: &UnixDatagram) -> Option<(i32, String)> {
+    let mut buf = [0u8; 4096];
+    let mut cmsg = nix::cmsg_space!(UnixCredentials);
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+
+    let msg = match recvmsg(socket.as_raw_fd(), &iov, Some(&mut cmsg), MsgFlags::empty())
+    {
+        Ok(msg) => msg,
+        // Timeouts are expected, anything else is worth mentioning.
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => return None,
+        Err(e) => {
+            println!("Failed to receive notification: {}", e);
+            return None;
+        }
+    };
+
+    let mut sender = None;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmCredentials(creds) = cmsg {
+            sender = Some(creds.pid());
+        }
+    }
+    let bytes = msg.bytes;
+    match sender {
+        Some(pid) => Some((pid, String::from_utf8_lossy(&buf[..bytes]).into_owned())),
+        None => {
+            println!("Ignoring notification without credentials");
+            None
+        }
+    }
+}
+
+/// Hand the message over to the service whose main process sent it.
+fn dispatch(pid: i32, message: &str) {
+    let all_units = ALL_UNITS.lock().unwrap();
+    for unit in all_units.iter() {
+        let mut service = unit.service.lock().unwrap();
+        if service.main_pid == Some(pid as u32) {
+            println!("Notification from {}: {:?}", unit.path, message);
+            service.handle_notify(message);
+            return;
+        }
+    }
+    println!("Ignoring notification from PID {} which is not a main process", pid);
+}
+
+/// Abort services which have not pinged their watchdog in time.
+fn check_watchdogs() {
+    let all_units = ALL_UNITS.lock().unwrap();
+    for unit in all_units.iter() {
+        unit.service.lock().unwrap().check_watchdog();
+    }
+}
@@ -0,0 +1,200 @@
+/// policy.rs decides who may do what through the API.
+///
+/// Clients on the API socket are identified by the credentials the kernel
+/// reports for the other end of the connection (`SO_PEERCRED`). Root may do
+/// anything, everybody else may only look at units and jobs, unless a rule in
+/// the policy file allows more. Reading the output of units is the `logs`
+/// action and following events the `events` one:
+///
+/// ```toml
+/// # Members of wheel may do anything.
+/// [[allow]]
+/// group = "wheel"
+///
+/// # deploy may restart the web server, but nothing else.
+/// [[allow]]
+/// user = "deploy"
+/// actions = ["restart"]
+/// units = ["web.service"]
+///
+/// # monitoring may read the output of the web server.
+/// [[allow]]
+/// user = "monitoring"
+/// actions = ["logs"]
+/// units = ["web.service"]
+/// ```
+///
+/// Clients over TCP have no credentials, they may only look.
+///
+/// The policy file is read when getupd starts and again when the unit files
+/// are reloaded.
+use config::{Config, File, FileFormat};
+use lazy_static::lazy_static;
+use nix::unistd::{Gid, Group, Uid, User};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// The process on the other end of an API connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// A rule granting a user or group some of the actions, on some of the units.
+/// Whatever is left out isn't restricted.
+#[derive(Debug, Default, Deserialize)]
+pub struct Rule {
+    /// User name or UID.
+    pub user: Option<String>,
+    /// Group name or GID, the user can be a member of it.
+    pub group: Option<String>,
+    /// Actions like start or poweroff.
+    pub actions: Option<Vec<String>>,
+    /// Units the actions may be taken on, which means the rule only covers
+    /// actions on units.
+    pub units: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<Rule>,
+}
+
+lazy_static! {
+    /// The policy in effect, see `reload`.
+    static ref POLICY: RwLock<Policy> = RwLock::new(Policy::default());
+}
+
+/// Read the policy file at path, which is in effect from then on.
+pub fn reload(path: &str) {
+    *POLICY.write().unwrap() = Policy::load(path);
+}
+
+/// The policy in effect.
+pub fn current() -> RwLockReadGuard<'static, Policy> {
+    POLICY.read().unwrap()
+}
+
+impl Policy {
+    /// Load the policy from path. A missing file is an empty policy, a broken
+    /// one is reported and doesn't allow anything either.
+    pub fn load(path: &str) -> Policy {
+        if !Path::new(path).exists() {
+            return Policy::default();
+        }
+        let mut config = Config::new();
+        let policy = config
+            .merge(File::new(path, FileFormat::Toml))
+            .and_then(|config| config.clone().try_into());
+        match policy {
+            Ok(policy) => policy,
+            Err(e) => {
+                println!("Failed to load the API policy from {}: {}", path, e);
+                Policy::default()
+            }
+        }
+    }
+
+    /// Whether peer may take action, on unit if the action is for one. Only
+    /// actions that change something need to be checked.
+    pub fn allows(&self, peer: Option<Peer>, unit: Option<&str>, action: &str) -> bool {
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return false,
+        };
+        if peer.uid == 0 {
+            return true;
+        }
+        self.allow.iter().any(|rule| rule.allows(peer, unit, action))
+    }
+}
+
+impl Rule {
+    fn allows(&self, peer: Peer, unit: Option<&str>, action: &str) -> bool {
+        let who = match (&self.user, &self.group) {
+            (None, None) => false,
+            (user, group) => {
+                user.as_ref().is_none_or(|user| is_user(peer, user))
+                    && group.as_ref().is_none_or(|group| in_group(peer, group))
+            }
+        };
+        let what = self
+            .actions
+            .as_ref()
+            .is_none_or(|actions| actions.iter().any(|a| a == action));
+        let which = match (&self.units, unit) {
+            (None, _) => true,
+            (Some(units), Some(unit)) => units.iter().any(|u| u == unit),
+            (Some(_), None) => false,
+        };
+        who && what && which
+    }
+}
+
+fn is_user(peer: Peer, user: &str) -> bool {
+    match user.parse::<u32>() {
+        Ok(uid) => uid == peer.uid,
+        Err(_) => {
+            matches!(User::from_name(user), Ok(Some(u)) if u.uid.as_raw() == peer.uid)
+        }
+    }
+}
+
+/// Whether the peer is in the group, either as its primary group or as a
+/// member listed in the group database.
+fn in_group(peer: Peer, group: &str) -> bool {
+    let group = match group.parse::<u32>() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(group),
+    };
+    let group = match group {
+        Ok(Some(group)) => group,
+        _ => return false,
+    };
+    if group.gid.as_raw() == peer.gid {
+        return true;
+    }
+    match User::from_uid(Uid::from_raw(peer.uid)) {
+        Ok(Some(user)) => group.mem.contains(&user.name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_reload() {
+        let path = env::temp_dir().join(format!("getup-policy-{}.toml", process::id()));
+        let path = path.to_str().unwrap();
+        let deploy = Some(Peer { uid: 4242, gid: 4242 });
+        let root = Some(Peer { uid: 0, gid: 0 });
+
+        fs::write(
+            path,
+            "[[allow]]\nuser = \"4242\"\nactions = [\"restart\"]\nunits = [\"web.service\"]\n",
+        )
+        .unwrap();
+        reload(path);
+        assert!(current().allows(deploy, Some("web.service"), "restart"));
+        assert!(!current().allows(deploy, Some("web.service"), "stop"));
+        assert!(!current().allows(deploy, Some("db.service"), "restart"));
+        assert!(!current().allows(deploy, None, "restart"));
+        assert!(!current().allows(None, Some("web.service"), "restart"));
+
+        // A broken file allows nobody but root.
+        fs::write(path, "[[allow]\n").unwrap();
+        reload(path);
+        assert!(!current().allows(deploy, Some("web.service"), "restart"));
+        assert!(current().allows(root, None, "poweroff"));
+
+        fs::remove_file(path).unwrap();
+        reload(path);
+        assert!(current().allow.is_empty());
+    }
+}
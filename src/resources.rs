@@ -0,0 +1,306 @@
+/// resources.rs maps the resource control directives of a service, like
+/// `MemoryMax=` or `CPUQuota=`, to the files of the cgroup v2 controllers that
+/// enforce them, see systemd.resource-control(5).
+///
+/// They are written to the cgroup of the service when it starts, and can be
+/// changed while it runs with `getupctl set-property`. Units only get the
+/// controllers `cgroup::init` could enable for them, directives for any other
+/// controller fail to apply.
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The period CPUQuota= is enforced over, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// What CPUWeight= and IOWeight= are when they aren't set.
+const DEFAULT_WEIGHT: u64 = 100;
+
+/// The directives, as they are called in unit files.
+pub const PROPERTIES: [&str; 8] = [
+    "MemoryMax",
+    "MemoryHigh",
+    "MemorySwapMax",
+    "CPUQuota",
+    "CPUWeight",
+    "IOWeight",
+    "TasksMax",
+    "AllowedCPUs",
+];
+
+/// A limit, which `infinity` lifts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Infinity,
+    Value(u64),
+}
+
+/// How the cgroup files want it.
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Infinity => write!(f, "max"),
+            Limit::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Serialize for Limit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Limit::Infinity => serializer.serialize_str("infinity"),
+            Limit::Value(value) => serializer.serialize_u64(*value),
+        }
+    }
+}
+
+/// The resource control directives of a service, None for those that aren't
+/// set, which leaves the kernel defaults alone.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Resources {
+    /// Bytes of memory the processes may use, the OOM killer steps in above.
+    pub memory_max: Option<Limit>,
+    /// Bytes of memory above which the processes are throttled.
+    pub memory_high: Option<Limit>,
+    /// Bytes of swap the processes may use.
+    pub memory_swap_max: Option<Limit>,
+    /// CPU time in percent of one CPU, more than 100 takes more than one.
+    pub cpu_quota: Option<u64>,
+    /// Share of CPU time relative to other units, from 1 to 10000.
+    pub cpu_weight: Option<u64>,
+    /// Share of IO relative to other units, from 1 to 10000.
+    pub io_weight: Option<u64>,
+    /// How many processes and threads there may be.
+    pub tasks_max: Option<Limit>,
+    /// CPUs the processes may run on, like `0-3,6`.
+    pub allowed_cpus: Option<String>,
+}
+
+impl Resources {
+    /// Set the directive key to value as it is written in unit files. An
+    /// empty value unsets it.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = Some(value.trim()).filter(|value| !value.is_empty());
+        match key {
+            "MemoryMax" => self.memory_max = value.map(parse_bytes).transpose()?,
+            "MemoryHigh" => self.memory_high = value.map(parse_bytes).transpose()?,
+            "MemorySwapMax" => {
+                self.memory_swap_max = value.map(parse_bytes).transpose()?
+            }
+            "CPUQuota" => self.cpu_quota = value.map(parse_quota).transpose()?,
+            "CPUWeight" => self.cpu_weight = value.map(parse_weight).transpose()?,
+            "IOWeight" => self.io_weight = value.map(parse_weight).transpose()?,
+            "TasksMax" => self.tasks_max = value.map(parse_tasks).transpose()?,
+            "AllowedCPUs" => self.allowed_cpus = value.map(parse_cpus).transpose()?,
+            _ => return Err(format!("unknown property {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Whether the directive key is set.
+    pub fn is_set(&self, key: &str) -> bool {
+        match key {
+            "MemoryMax" => self.memory_max.is_some(),
+            "MemoryHigh" => self.memory_high.is_some(),
+            "MemorySwapMax" => self.memory_swap_max.is_some(),
+            "CPUQuota" => self.cpu_quota.is_some(),
+            "CPUWeight" => self.cpu_weight.is_some(),
+            "IOWeight" => self.io_weight.is_some(),
+            "TasksMax" => self.tasks_max.is_some(),
+            "AllowedCPUs" => self.allowed_cpus.is_some(),
+            _ => false,
+        }
+    }
+
+    /// The cgroup file the directive key is enforced by and what goes in it,
+    /// which is the kernel default if it isn't set.
+    pub fn file(&self, key: &str) -> Option<(&'static str, String)> {
+        let limit = |limit: Option<Limit>| limit.unwrap_or(Limit::Infinity).to_string();
+        let file = match key {
+            "MemoryMax" => ("memory.max", limit(self.memory_max)),
+            "MemoryHigh" => ("memory.high", limit(self.memory_high)),
+            "MemorySwapMax" => ("memory.swap.max", limit(self.memory_swap_max)),
+            "CPUQuota" => match self.cpu_quota {
+                Some(quota) => {
+                    ("cpu.max", format!("{} {}", quota * CPU_PERIOD / 100, CPU_PERIOD))
+                }
+                None => ("cpu.max", format!("max {}", CPU_PERIOD)),
+            },
+            "CPUWeight" => {
+                ("cpu.weight", self.cpu_weight.unwrap_or(DEFAULT_WEIGHT).to_string())
+            }
+            "IOWeight" => (
+                "io.weight",
+                format!("default {}", self.io_weight.unwrap_or(DEFAULT_WEIGHT)),
+            ),
+            "TasksMax" => ("pids.max", limit(self.tasks_max)),
+            "AllowedCPUs" => {
+                ("cpuset.cpus", self.allowed_cpus.clone().unwrap_or_default())
+            }
+            _ => return None,
+        };
+        Some(file)
+    }
+
+    /// Write the directive key to the cgroup at path.
+    pub fn apply(&self, path: &Path, key: &str) -> Result<(), String> {
+        let (file, value) =
+            self.file(key).ok_or_else(|| format!("unknown property {}", key))?;
+        let path = path.join(file);
+        // The files of a controller are only there once it is enabled.
+        if !path.exists() {
+            let controller = file.split('.').next().unwrap_or(file);
+            return Err(format!("the {} controller is not available", controller));
+        }
+        fs::write(&path, value).map_err(|e| format!("failed to write {}: {}", file, e))
+    }
+
+    /// Write the directives that are set to the cgroup at path, returning what
+    /// failed.
+    pub fn apply_all(&self, path: &Path) -> Vec<String> {
+        PROPERTIES
+            .iter()
+            .filter(|key| self.is_set(key))
+            .filter_map(|key| {
+                self.apply(path, key).err().map(|e| format!("{}: {}", key, e))
+            })
+            .collect()
+    }
+}
+
+/// Bytes with an optional K, M, G or T suffix, which are powers of 1024, a
+/// percentage of the physical memory or `infinity`.
+fn parse_bytes(value: &str) -> Result<Limit, String> {
+    if value == "infinity" {
+        return Ok(Limit::Infinity);
+    }
+    if let Some(percent) = value.strip_suffix('%') {
+        let total = physical_memory().ok_or("failed to find the size of the memory")?;
+        return Ok(Limit::Value(total / 100 * parse_percent(percent)?));
+    }
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) => (&value[..i], 1 << 30),
+        Some((i, 'T')) => (&value[..i], 1u64 << 40),
+        _ => (value, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid size {:?}", value))?;
+    number
+        .checked_mul(multiplier)
+        .map(Limit::Value)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+/// A percentage, which CPUQuota= always is and which may be over 100.
+fn parse_quota(value: &str) -> Result<u64, String> {
+    let percent = value
+        .strip_suffix('%')
+        .ok_or_else(|| format!("CPU quota {:?} is not a percentage", value))?;
+    match percent.parse() {
+        Ok(percent) if percent > 0 => Ok(percent),
+        _ => Err(format!("invalid CPU quota {:?}", value)),
+    }
+}
+
+fn parse_weight(value: &str) -> Result<u64, String> {
+    match value.parse() {
+        Ok(weight) if (1..=10000).contains(&weight) => Ok(weight),
+        _ => Err(format!("weight {:?} is not between 1 and 10000", value)),
+    }
+}
+
+/// A number, a percentage of the most PIDs there can be or `infinity`.
+fn parse_tasks(value: &str) -> Result<Limit, String> {
+    if value == "infinity" {
+        return Ok(Limit::Infinity);
+    }
+    if let Some(percent) = value.strip_suffix('%') {
+        let pid_max: u64 = fs::read_to_string("/proc/sys/kernel/pid_max")
+            .ok()
+            .and_then(|max| max.trim().parse().ok())
+            .ok_or("failed to read kernel.pid_max")?;
+        return Ok(Limit::Value(pid_max * parse_percent(percent)? / 100));
+    }
+    value
+        .parse()
+        .map(Limit::Value)
+        .map_err(|_| format!("invalid number of tasks {:?}", value))
+}
+
+fn parse_percent(percent: &str) -> Result<u64, String> {
+    match percent.parse() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!("invalid percentage {:?}", percent)),
+    }
+}
+
+/// CPU numbers and ranges like `0-3`, separated by commas or spaces. They are
+/// written out the way cpuset.cpus takes them.
+fn parse_cpus(value: &str) -> Result<String, String> {
+    let invalid = || format!("invalid CPU list {:?}", value);
+    let mut cpus = vec![];
+    for part in value.split([',', ' ']).filter(|part| !part.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: u32 = first.parse().map_err(|_| invalid())?;
+        let last: u32 = last.parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        cpus.push(part.to_string());
+    }
+    Ok(cpus.join(","))
+}
+
+fn physical_memory() -> Option<u64> {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages < 0 || page_size < 0 {
+        return None;
+    }
+    Some(pages as u64 * page_size as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut resources = Resources::default();
+        resources.set("MemoryMax", "512M").unwrap();
+        resources.set("MemoryHigh", "infinity").unwrap();
+        resources.set("CPUQuota", "150%").unwrap();
+        resources.set("IOWeight", "500").unwrap();
+        resources.set("AllowedCPUs", "0-3 6").unwrap();
+        assert_eq!(resources.memory_max, Some(Limit::Value(512 << 20)));
+        assert_eq!(resources.file("MemoryHigh").unwrap(), ("memory.high", "max".into()));
+        assert_eq!(
+            resources.file("CPUQuota").unwrap(),
+            ("cpu.max", "150000 100000".into())
+        );
+        assert_eq!(
+            resources.file("IOWeight").unwrap(),
+            ("io.weight", "default 500".into())
+        );
+        assert_eq!(resources.allowed_cpus.as_deref(), Some("0-3,6"));
+        assert!(!resources.is_set("TasksMax"));
+
+        resources.set("MemoryMax", "").unwrap();
+        assert_eq!(resources.memory_max, None);
+        assert_eq!(resources.file("MemoryMax").unwrap(), ("memory.max", "max".into()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut resources = Resources::default();
+        assert!(resources.set("MemoryMax", "lots").is_err());
+        assert!(resources.set("CPUQuota", "20").is_err());
+        assert!(resources.set("CPUWeight", "0").is_err());
+        assert!(resources.set("TasksMax", "101%").is_err());
+        assert!(resources.set("AllowedCPUs", "3-1").is_err());
+        assert!(resources.set("Nice", "5").is_err());
+        assert_eq!(resources, Resources::default());
+    }
+}
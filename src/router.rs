@@ -0,0 +1,273 @@
+/// router.rs matches requests against a table of routes like
+/// `/units/:name/start`, where segments starting with `:` capture that part of
+/// the path as a parameter for the handler.
+///
+/// A path that doesn't match any route gets a 404, one that only matches
+/// routes for other methods gets a 405 along with the methods it allows.
+/// Errors are sent as JSON, like every other response of the API.
+use futures::future::{BoxFuture, FutureExt};
+use hyper::header::{ALLOW, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+
+/// Parameters captured from the path, by name.
+pub type Params = HashMap<String, String>;
+
+type Handler = Box<
+    dyn Fn(Request<Body>, Params) -> BoxFuture<'static, Response<Body>> + Send + Sync,
+>;
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    /// The parameters if the path matches this route.
+    fn matches(&self, path: &[String]) -> Option<Params> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Params::new();
+        for (segment, part) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), part.clone());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: vec![] }
+    }
+
+    /// Add a route for the method and pattern.
+    pub fn add<F, R>(mut self, method: Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request<Body>, Params) -> R + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(part),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(move |req, params| handler(req, params).boxed()),
+        });
+        self
+    }
+
+    pub fn get<F, R>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request<Body>, Params) -> R + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.add(Method::GET, pattern, handler)
+    }
+
+    pub fn post<F, R>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request<Body>, Params) -> R + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.add(Method::POST, pattern, handler)
+    }
+
+    /// Hand the request over to the handler of the route it matches.
+    pub async fn route(&self, req: Request<Body>) -> Response<Body> {
+        let path = split_path(req.uri().path());
+        let mut allowed: Vec<&str> = vec![];
+        for route in &self.routes {
+            if let Some(params) = route.matches(&path) {
+                if route.method == req.method() {
+                    return (route.handler)(req, params).await;
+                }
+                allowed.push(route.method.as_str());
+            }
+        }
+
+        if allowed.is_empty() {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                &format!("No such path {}", req.uri().path()),
+            );
+        }
+        let mut response = error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &format!("{} is not allowed on {}", req.method(), req.uri().path()),
+        );
+        response.headers_mut().insert(ALLOW, allowed.join(", ").parse().unwrap());
+        response
+    }
+}
+
+/// Split a path into its percent-decoded segments, ignoring empty ones so
+/// that trailing slashes don't matter.
+pub fn split_path(path: &str) -> Vec<String> {
+    path.split('/').filter(|part| !part.is_empty()).map(percent_decode).collect()
+}
+
+pub fn percent_decode(part: &str) -> String {
+    let bytes = part.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A response with value as its JSON body.
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(value).unwrap()))
+        .expect("Failed to construct the response")
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+/// An error response, with the message as JSON: `{"error": "..."}`.
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &ErrorBody { error: message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn router() -> Router {
+        let echo = |name: &'static str| {
+            move |_: Request<Body>, params: Params| async move {
+                let mut params: Vec<_> = params.into_iter().collect();
+                params.sort();
+                json_response(StatusCode::OK, &(name, params))
+            }
+        };
+        Router::new()
+            .get("/", echo("root"))
+            .get("/units", echo("units"))
+            .get("/units/:name", echo("unit"))
+            .post("/units/:name/:action", echo("action"))
+            .add(Method::DELETE, "/jobs/:id", echo("job"))
+            .post("/jobs/:id", echo("job"))
+    }
+
+    /// Send a request through the router, returning the status, the Allow
+    /// header and the JSON body.
+    fn send(
+        method: Method,
+        uri: &str,
+    ) -> (StatusCode, Option<String>, serde_json::Value) {
+        let req =
+            Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(router().route(req));
+        let status = response.status();
+        let allow =
+            response.headers().get(ALLOW).map(|v| v.to_str().unwrap().to_string());
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body =
+            runtime.block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        (status, allow, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_path_params() {
+        let (status, _, body) = send(Method::GET, "/");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!(["root", []]));
+
+        let (_, _, body) = send(Method::GET, "/units/");
+        assert_eq!(body, serde_json::json!(["units", []]));
+
+        let (_, _, body) = send(Method::GET, "/units/web.service");
+        assert_eq!(body, serde_json::json!(["unit", [["name", "web.service"]]]));
+
+        // Parameters are percent-decoded, the query isn't part of the path.
+        let (_, _, body) =
+            send(Method::POST, "/units/getty%40tty1.service/start?wait=true");
+        assert_eq!(
+            body,
+            serde_json::json!([
+                "action",
+                [["action", "start"], ["name", "getty@tty1.service"]]
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unknown_route() {
+        for uri in &["/nope", "/units/web.service/start/now", "/jobs"] {
+            let (status, allow, body) = send(Method::GET, uri);
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+            assert_eq!(allow, None);
+            assert_eq!(body["error"], format!("No such path {}", uri));
+        }
+    }
+
+    #[test]
+    fn test_method_mismatch() {
+        let (status, allow, body) = send(Method::POST, "/units");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("GET"));
+        assert_eq!(body["error"], "POST is not allowed on /units");
+
+        let (status, allow, _) = send(Method::GET, "/jobs/3");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("DELETE, POST"));
+
+        let (status, _, body) = send(Method::DELETE, "/jobs/3");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!(["job", [["id", "3"]]]));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("%2Fetc"), "/etc");
+        // Anything that isn't an escape is kept as it is.
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(split_path("//units//a%2Fb/"), vec!["units", "a/b"]);
+    }
+}
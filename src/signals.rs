@@ -1,27 +1,203 @@
 /// This module defines the signals that API server and the main daemon use to
-/// communicate.
+/// communicate, along with receiving Unix signals inside the tokio runtime.
+use crate::jobs::{Job, JobId, JobInfo};
+use futures::future::poll_fn;
 use lazy_static::lazy_static;
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use nix::sys::signal::Signal;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Mutex;
+use std::task::Poll;
+use tokio::io::PollEvented;
+use tokio::sync::oneshot;
 
 lazy_static! {
-    pub static ref CHANNEL: (Mutex<Sender<Message>>, Mutex<Receiver<Message>>) = {
+    pub static ref CHANNEL: (Mutex<Sender<Job>>, Mutex<Receiver<Job>>) = {
         let (r, w) = channel();
         (Mutex::new(r), Mutex::new(w))
     };
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Message {
     /// Signifies that the server should start shutdown sequence and stop.
     Shutdown,
+    /// Bring the whole system down. Immediate skips stopping the units.
+    SystemShutdown {
+        action: SystemAction,
+        immediate: bool,
+    },
     Start(String),
     Stop(String),
     Restart(String),
+    Reload(String),
+    /// Send the signal to the processes of the unit.
+    Kill(String, Signal),
+    /// Read the unit files again.
+    ReloadUnits,
 }
 
-/// Signal the Daemon process with the required signal.
-pub fn signal_daemon(msg: Message) {
-    let sender = &CHANNEL.0;
-    sender.lock().unwrap().send(msg).expect("Failed to send msg to daemon");
+impl Message {
+    /// The unit the message is about, if any, and what is to be done.
+    pub fn describe(&self) -> (Option<&str>, &'static str) {
+        match self {
+            Message::Shutdown => (None, "shutdown"),
+            Message::SystemShutdown { action: SystemAction::Halt, .. } => (None, "halt"),
+            Message::SystemShutdown { action: SystemAction::PowerOff, .. } => {
+                (None, "poweroff")
+            }
+            Message::SystemShutdown { action: SystemAction::Reboot, .. } => {
+                (None, "reboot")
+            }
+            Message::SystemShutdown { action: SystemAction::Kexec, .. } => {
+                (None, "kexec")
+            }
+            Message::Start(unit) => (Some(unit), "start"),
+            Message::Stop(unit) => (Some(unit), "stop"),
+            Message::Restart(unit) => (Some(unit), "restart"),
+            Message::Reload(unit) => (Some(unit), "reload"),
+            Message::Kill(unit, _) => (Some(unit), "kill"),
+            Message::ReloadUnits => (None, "daemon-reload"),
+        }
+    }
+}
+
+/// What happens to the system once it has been shut down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemAction {
+    Halt,
+    PowerOff,
+    Reboot,
+    Kexec,
+}
+
+/// Signal the Daemon process with the required signal. Returns the ID of the
+/// job for it, along with a receiver for the job once it finished.
+pub fn signal_daemon(msg: Message) -> (JobId, oneshot::Receiver<JobInfo>) {
+    let (job, finished) = Job::new(msg);
+    let id = job.id;
+    if let Some(job) = job.merge() {
+        let sender = &CHANNEL.0;
+        sender.lock().unwrap().send(job).expect("Failed to send msg to daemon");
+    }
+    (id, finished)
+}
+
+/// Reload when getupd gets SIGHUP, forever, like daemons do. Init handles it
+/// along with its other signals instead. This has to be spawned on a tokio
+/// runtime, after blocking SIGHUP with `block`.
+pub async fn reload_on_sighup() -> io::Result<()> {
+    let mut receiver = SignalReceiver::new(&[libc::SIGHUP])?;
+    loop {
+        receiver.recv().await?;
+        println!("Received SIGHUP, reloading");
+        signal_daemon(Message::ReloadUnits);
+    }
+}
+
+fn sigset(signals: &[libc::c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in signals {
+            libc::sigaddset(&mut set, *signal);
+        }
+        set
+    }
+}
+
+/// Block signals in the calling thread so that they are only delivered
+/// through a `SignalReceiver`. This has to be called before any other thread
+/// is started, as threads inherit the mask of the thread that creates them.
+pub fn block(signals: &[libc::c_int]) -> io::Result<()> {
+    let set = sigset(signals);
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// A signalfd, which the runtime can wait on. Unlike nix's SignalFd this takes
+/// raw signal numbers, which is the only way to get at the real-time signals.
+struct SignalFd(RawFd);
+
+impl Evented for SignalFd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Receives blocked signals from inside the tokio runtime.
+pub struct SignalReceiver {
+    fd: PollEvented<SignalFd>,
+}
+
+impl SignalReceiver {
+    /// Start receiving the signals, which have to be blocked with `block`.
+    /// This has to be called from within a tokio runtime.
+    pub fn new(signals: &[libc::c_int]) -> io::Result<SignalReceiver> {
+        let set = sigset(signals);
+        let fd =
+            unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SignalReceiver { fd: PollEvented::new(SignalFd(fd))? })
+    }
+
+    /// Wait for the next signal and return its number.
+    pub async fn recv(&mut self) -> io::Result<libc::c_int> {
+        loop {
+            poll_fn(|cx| self.fd.poll_read_ready(cx, Ready::readable())).await?;
+
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::signalfd_siginfo>();
+            let read = unsafe {
+                libc::read(
+                    self.fd.get_ref().0,
+                    &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                    size,
+                )
+            };
+            if read == size as isize {
+                return Ok(info.ssi_signo as libc::c_int);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            poll_fn(|cx| Poll::Ready(self.fd.clear_read_ready(cx, Ready::readable())))
+                .await?;
+        }
+    }
 }
@@ -0,0 +1,282 @@
+/// syslog.rs stores what services log through `syslog(3)`.
+///
+/// getupd can take the place of the syslog daemon by binding `/dev/log`. The
+/// messages sent to it are parsed as RFC 5424 or RFC 3164, whichever they
+/// look like, and stored in the log store of the unit the sender belongs to,
+/// which we find from the credentials the kernel attaches to each datagram.
+/// Messages from processes that don't belong to any unit are stored as those
+/// of `syslog`, so are those of processes that are already gone by the time
+/// we look for their parent, like `logger` run from a script.
+///
+/// Entries are timestamped when they are received, like journald does, the
+/// time in the message is left alone.
+use crate::logs::{self, LogEntry, LogStream};
+use crate::units::ALL_UNITS;
+use nix::sys::socket::{
+    recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, UnixCredentials,
+};
+use nix::sys::uio::IoVec;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::thread;
+
+/// Longer datagrams are cut off.
+const DATAGRAM_MAX: usize = 64 * 1024;
+
+/// What messages from processes outside of any unit are stored as.
+const OTHER: &str = "syslog";
+
+/// user.notice, for messages without a priority.
+const DEFAULT_PRI: u8 = 13;
+
+/// A syslog message, as far as we care about it.
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub facility: u8,
+    pub priority: u8,
+    /// The tag or APP-NAME, like `sshd`.
+    pub identifier: Option<String>,
+    /// The PID the sender put in the message.
+    pub pid: Option<u32>,
+    pub message: String,
+}
+
+/// Bind the syslog socket at path and store the messages sent to it in a
+/// background thread. A syslog daemon already listening on it is left alone.
+pub fn listen(path: &str) -> io::Result<thread::JoinHandle<()>> {
+    if UnixDatagram::unbound()?.connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "somebody else is listening on it",
+        ));
+    }
+    // Remove a stale socket left behind by a previous run.
+    let _ = fs::remove_file(path);
+    let socket = UnixDatagram::bind(path)?;
+    // Everybody gets to log.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    setsockopt(socket.as_raw_fd(), sockopt::PassCred, &true)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    thread::Builder::new().name("syslog".to_string()).spawn(move || {
+        let mut buf = vec![0u8; DATAGRAM_MAX];
+        loop {
+            if let Some((pid, bytes)) = receive(&socket, &mut buf) {
+                store(pid, &String::from_utf8_lossy(&buf[..bytes]));
+            }
+        }
+    })
+}
+
+/// Receive one datagram into buf, returning the PID of the sender and its
+/// length.
+fn receive(socket: &UnixDatagram, buf: &mut [u8]) -> Option<(u32, usize)> {
+    let mut cmsg = nix::cmsg_space!(UnixCredentials);
+    let iov = [IoVec::from_mut_slice(buf)];
+
+    let msg = match recvmsg(socket.as_raw_fd(), &iov, Some(&mut cmsg), MsgFlags::empty())
+    {
+        Ok(msg) => msg,
+        Err(e) => {
+            println!("Failed to receive syslog message: {}", e);
+            return None;
+        }
+    };
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmCredentials(creds) = cmsg {
+            return Some((creds.pid() as u32, msg.bytes));
+        }
+    }
+    println!("Ignoring syslog message without credentials");
+    None
+}
+
+/// Store the message in the log of the unit the sender belongs to.
+fn store(pid: u32, datagram: &str) {
+    let message = parse(datagram);
+    let unit = ALL_UNITS.lock().unwrap().unit_of_pid(pid).map(|unit| unit.name.clone());
+    let mut entry = LogEntry::new(
+        unit.as_deref().unwrap_or(OTHER),
+        Some(pid),
+        LogStream::Syslog,
+        &message.message,
+    );
+    entry.priority = message.priority;
+    entry.facility = Some(message.facility);
+    entry.identifier = message.identifier;
+    logs::append(&entry);
+}
+
+/// Parse a datagram as RFC 5424 if it has a version after the priority and
+/// as RFC 3164 otherwise, which is what `syslog(3)` sends. Anything that
+/// doesn't parse ends up in the message.
+pub fn parse(datagram: &str) -> Message {
+    let datagram = datagram.trim_end_matches(['\n', '\0']);
+    let (pri, rest) = parse_pri(datagram).unwrap_or((DEFAULT_PRI, datagram));
+    let (identifier, pid, message) = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest),
+        None => parse_rfc3164(rest),
+    };
+    Message {
+        facility: pri >> 3,
+        priority: pri & 7,
+        identifier,
+        pid,
+        message: message.to_string(),
+    }
+}
+
+/// `<PRI>`, which is the facility times 8 plus the priority.
+fn parse_pri(datagram: &str) -> Option<(u8, &str)> {
+    let rest = datagram.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    if end == 0 || end > 3 || !rest[..end].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let pri: u8 = rest[..end].parse().ok()?;
+    if pri > 191 {
+        return None;
+    }
+    Some((pri, &rest[end + 1..]))
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after
+/// the version. Fields that are left out are `-`.
+fn parse_rfc5424(rest: &str) -> (Option<String>, Option<u32>, &str) {
+    let fields: Vec<&str> = rest.splitn(6, ' ').collect();
+    if fields.len() < 6 {
+        return (None, None, rest);
+    }
+    let identifier = Some(fields[2]).filter(|app| *app != "-").map(str::to_string);
+    let pid = fields[3].parse().ok();
+    let message = skip_structured_data(fields[5]);
+    let message = message.strip_prefix(' ').unwrap_or(message);
+    (identifier, pid, message.strip_prefix('\u{feff}').unwrap_or(message))
+}
+
+/// What comes after the structured data, which is `-` or elements like
+/// `[id param="value"]`, where values may contain escaped quotes and `]`.
+fn skip_structured_data(data: &str) -> &str {
+    if let Some(rest) = data.strip_prefix('-') {
+        return rest;
+    }
+    let (mut in_value, mut escaped, mut in_element) = (false, false, false);
+    for (i, c) in data.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_value => escaped = true,
+            '"' if in_element => in_value = !in_value,
+            '[' if !in_element => in_element = true,
+            ']' if in_element && !in_value => in_element = false,
+            _ if in_element => {}
+            _ => return &data[i..],
+        }
+    }
+    ""
+}
+
+/// `[Mmm dd hh:mm:ss ][HOSTNAME ]TAG[[PID]]: MSG`, though pretty much
+/// anything goes.
+fn parse_rfc3164(rest: &str) -> (Option<String>, Option<u32>, &str) {
+    let rest = skip_timestamp(rest);
+    if let Some(tagged) = parse_tag(rest) {
+        return tagged;
+    }
+    // A hostname, which local senders usually leave out.
+    if let Some((_, after)) = rest.split_once(' ') {
+        if let Some(tagged) = parse_tag(after) {
+            return tagged;
+        }
+    }
+    (None, None, rest)
+}
+
+fn skip_timestamp(rest: &str) -> &str {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+        "Dec",
+    ];
+    let bytes = rest.as_bytes();
+    let is_timestamp = bytes.len() >= 16
+        && MONTHS.iter().any(|month| rest.starts_with(month))
+        && bytes[3] == b' '
+        && bytes[9] == b':'
+        && bytes[12] == b':'
+        && bytes[15] == b' ';
+    if is_timestamp {
+        &rest[16..]
+    } else {
+        rest
+    }
+}
+
+/// `TAG: MSG` or `TAG[PID]: MSG`.
+fn parse_tag(rest: &str) -> Option<(Option<String>, Option<u32>, &str)> {
+    let end = rest.find(['[', ':', ' '])?;
+    if end == 0 {
+        return None;
+    }
+    let tag = &rest[..end];
+    let (pid, after) = match rest[end..].strip_prefix('[') {
+        Some(after) => {
+            let close = after.find(']')?;
+            (Some(after[..close].parse().ok()?), &after[close + 1..])
+        }
+        None => (None, &rest[end..]),
+    };
+    let message = after.strip_prefix(':')?;
+    Some((Some(tag.to_string()), pid, message.strip_prefix(' ').unwrap_or(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(identifier: Option<&str>, pid: Option<u32>, message: &str) -> Message {
+        Message {
+            facility: 3,
+            priority: 6,
+            identifier: identifier.map(str::to_string),
+            pid,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rfc3164() {
+        assert_eq!(
+            parse("<30>Apr  1 13:05:00 sshd[42]: Accepted publickey\n"),
+            message(Some("sshd"), Some(42), "Accepted publickey")
+        );
+        assert_eq!(
+            parse("<30>Apr  1 13:05:00 myhost cron: job done"),
+            message(Some("cron"), None, "job done")
+        );
+        assert_eq!(parse("<30>just a message"), message(None, None, "just a message"));
+    }
+
+    #[test]
+    fn test_rfc5424() {
+        assert_eq!(
+            parse("<30>1 2020-04-01T13:05:00Z myhost app 42 ID47 - hello"),
+            message(Some("app"), Some(42), "hello")
+        );
+        assert_eq!(
+            parse(r#"<30>1 - - - - - [a b="c\"]"][d] hello"#),
+            message(None, None, "hello")
+        );
+        assert_eq!(parse("<30>1 - - app - - -"), message(Some("app"), None, ""));
+    }
+
+    #[test]
+    fn test_pri() {
+        let parsed = parse("no priority");
+        assert_eq!((parsed.facility, parsed.priority), (1, 5));
+        assert_eq!(parse("<999>x").message, "<999>x");
+        let parsed = parse("<0>kernel: panic");
+        assert_eq!((parsed.facility, parsed.priority), (0, 0));
+    }
+}
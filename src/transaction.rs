@@ -0,0 +1,597 @@
+/// transaction.rs turns a request like "start X" into the ordered list of jobs
+/// needed to carry it out.
+///
+/// Building a transaction first collects every unit pulled in by the
+/// requirement dependencies of the units asked for, then orders the jobs by
+/// After=/Before=. If the ordering has a cycle, a job that was only pulled in
+/// by Wants= is dropped to break it, the same way systemd does it. A cycle
+/// made up only of required jobs fails the whole transaction.
+///
+/// The units started at boot are only wanted, like default.target does, so a
+/// unit that can't be started is left out along with the units requiring it
+/// instead of holding up all the others.
+use crate::deps::DepGraph;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+/// What a job does to its unit.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum JobType {
+    Start,
+    Stop,
+    /// Check that a Requisite= unit is already active, without starting it.
+    VerifyActive,
+}
+
+/// A single job of a transaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    /// Name of the unit this job is for.
+    pub unit: String,
+    pub job_type: JobType,
+    /// Whether the transaction fails if this job fails. Jobs pulled in only by
+    /// Wants= are not required.
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum TransactionError {
+    /// A required unit does not exist.
+    NotFound(String),
+    /// A required unit has to be both started and stopped.
+    Conflict(String, String),
+    /// An ordering cycle between required jobs, which can't be broken.
+    OrderingCycle(Vec<String>),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::NotFound(name) => write!(f, "unit {} not found", name),
+            TransactionError::Conflict(a, b) => {
+                write!(f, "unit {} conflicts with {}, both are required", a, b)
+            }
+            TransactionError::OrderingCycle(cycle) => {
+                write!(f, "ordering cycle between {}", cycle.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// An ordered set of jobs.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Transaction {
+    /// Units the transaction was built for, as opposed to the ones pulled in.
+    pub anchors: Vec<String>,
+    /// The units are stopped to be started again, see RestartKillSignal=.
+    pub restart: bool,
+    /// Jobs in the order they have to run.
+    pub jobs: Vec<Job>,
+    /// Ordering cycles that were found, along with the unit whose job was
+    /// dropped to break each of them.
+    pub broken_cycles: Vec<(Vec<String>, String)>,
+    /// Units left out because something they require can't be started, along
+    /// with the reason why.
+    pub pruned: Vec<(String, String)>,
+}
+
+impl Transaction {
+    /// Build a transaction to start the units in anchors and everything they
+    /// need.
+    pub fn start(
+        graph: &DepGraph,
+        anchors: &[&str],
+    ) -> Result<Transaction, TransactionError> {
+        Transaction::build_start(graph, anchors, true)
+    }
+
+    /// Build a transaction to start the units in anchors as far as they can
+    /// be. Those missing a unit they require or stuck in an ordering cycle are
+    /// left out, as are the units requiring them.
+    pub fn start_wanted(
+        graph: &DepGraph,
+        anchors: &[&str],
+    ) -> Result<Transaction, TransactionError> {
+        Transaction::build_start(graph, anchors, false)
+    }
+
+    fn build_start(
+        graph: &DepGraph,
+        anchors: &[&str],
+        required: bool,
+    ) -> Result<Transaction, TransactionError> {
+        let mut transaction = Transaction {
+            anchors: anchors.iter().map(|name| name.to_string()).collect(),
+            ..Transaction::default()
+        };
+        let mut dropped = HashSet::new();
+
+        loop {
+            let collected = collect_start(graph, anchors, required, &dropped)?;
+            let order = match order_after(graph, &collected.starts) {
+                Ok(order) => order,
+                Err(cycle) => {
+                    println!("Found ordering cycle: {}", cycle.join(" -> "));
+                    let victim = cycle
+                        .iter()
+                        .find(|unit| !collected.required(unit))
+                        .cloned()
+                        .ok_or_else(|| TransactionError::OrderingCycle(cycle.clone()))?;
+                    println!(
+                        "Breaking ordering cycle by dropping the job for {}",
+                        victim
+                    );
+                    dropped.insert(victim.clone());
+                    transaction.broken_cycles.push((cycle, victim));
+                    continue;
+                }
+            };
+
+            // Stop conflicting units and check requisites before starting
+            // anything.
+            transaction.pruned = collected.pruned;
+            transaction.jobs = collected.stops;
+            transaction.jobs.extend(collected.verifies);
+            let mut starts = collected.starts;
+            for name in order {
+                let pos = starts.iter().position(|job| job.unit == name).unwrap();
+                transaction.jobs.push(starts.remove(pos));
+            }
+            return Ok(transaction);
+        }
+    }
+
+    /// Build a transaction to stop the units in anchors, along with all the
+    /// units that require them, in reverse start order.
+    pub fn stop(graph: &DepGraph, anchors: &[&str]) -> Transaction {
+        let mut stops: Vec<Job> = vec![];
+        let mut queue: Vec<String> =
+            anchors.iter().map(|name| name.to_string()).collect();
+        while let Some(name) = queue.pop() {
+            if !graph.contains(&name) || stops.iter().any(|job| job.unit == name) {
+                continue;
+            }
+            queue
+                .extend(graph.propagate_stop(&name).iter().map(|name| name.to_string()));
+            stops.push(Job { unit: name, job_type: JobType::Stop, required: true });
+        }
+
+        // A cycle doesn't matter much when stopping, we still stop everything
+        // and only lose the ordering between the units in the cycle.
+        let order = order_after(graph, &stops).unwrap_or_else(|cycle| {
+            println!("Ignoring ordering cycle while stopping: {}", cycle.join(" -> "));
+            stops.iter().map(|job| job.unit.clone()).collect()
+        });
+        let mut transaction = Transaction {
+            anchors: anchors.iter().map(|name| name.to_string()).collect(),
+            ..Transaction::default()
+        };
+        for name in order.into_iter().rev() {
+            let pos = stops.iter().position(|job| job.unit == name).unwrap();
+            transaction.jobs.push(stops.remove(pos));
+        }
+        transaction
+    }
+}
+
+/// Jobs collected for a start transaction, before ordering.
+struct Collected {
+    starts: Vec<Job>,
+    stops: Vec<Job>,
+    verifies: Vec<Job>,
+    /// Units left out by prune and why.
+    pruned: Vec<(String, String)>,
+}
+
+impl Collected {
+    fn required(&self, name: &str) -> bool {
+        self.starts.iter().any(|job| job.unit == name && job.required)
+    }
+}
+
+/// Collect the start jobs for anchors and every unit they pull in, skipping
+/// the units in dropped.
+fn collect_start(
+    graph: &DepGraph,
+    anchors: &[&str],
+    required: bool,
+    dropped: &HashSet<String>,
+) -> Result<Collected, TransactionError> {
+    let mut collected =
+        Collected { starts: vec![], stops: vec![], verifies: vec![], pruned: vec![] };
+    let mut queue: Vec<(String, bool)> =
+        anchors.iter().rev().map(|name| (name.to_string(), required)).collect();
+
+    while let Some((name, required)) = queue.pop() {
+        if dropped.contains(&name) {
+            continue;
+        }
+        if !graph.contains(&name) {
+            if required {
+                return Err(TransactionError::NotFound(name));
+            }
+            continue;
+        }
+
+        match collected.starts.iter_mut().find(|job| job.unit == name) {
+            // Only visit a unit again if it just became required, so that its
+            // own dependencies become required too.
+            Some(job) if job.required || !required => continue,
+            Some(job) => job.required = true,
+            None => collected.starts.push(Job {
+                unit: name.clone(),
+                job_type: JobType::Start,
+                required,
+            }),
+        }
+
+        let deps = graph.get(&name).unwrap();
+        for other in deps.wants.iter().rev() {
+            queue.push((other.clone(), false));
+        }
+        for other in deps.requires.iter().chain(deps.binds_to.iter()).rev() {
+            queue.push((other.clone(), required));
+        }
+        for other in &deps.requisite {
+            if !graph.contains(other) && required {
+                return Err(TransactionError::NotFound(other.clone()));
+            }
+        }
+    }
+    prune(graph, &mut collected);
+
+    // Between two units we start that conflict, the one that isn't required
+    // loses.
+    let conflicting = |collected: &Collected| -> Vec<String> {
+        let mut names: Vec<String> = collected
+            .starts
+            .iter()
+            .flat_map(|job| graph.get(&job.unit).unwrap().conflicts.iter().cloned())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    };
+    let conflicts_with = |collected: &Collected, other: &str| -> Option<String> {
+        collected
+            .starts
+            .iter()
+            .find(|job| {
+                graph.get(&job.unit).unwrap().conflicts.iter().any(|c| c == other)
+            })
+            .map(|job| job.unit.clone())
+    };
+    for other in conflicting(&collected) {
+        if !collected.starts.iter().any(|job| job.unit == other) {
+            continue;
+        }
+        let by = match conflicts_with(&collected, &other) {
+            Some(by) => by,
+            None => continue,
+        };
+        match (collected.required(&other), collected.required(&by)) {
+            (true, true) => return Err(TransactionError::Conflict(by, other)),
+            (true, false) => collected.starts.retain(|job| job.unit != by),
+            _ => collected.starts.retain(|job| job.unit != other),
+        }
+    }
+    prune(graph, &mut collected);
+
+    // The other units that conflict with a unit we start are stopped.
+    for other in conflicting(&collected) {
+        if graph.contains(&other)
+            && !collected.starts.iter().any(|job| job.unit == other)
+        {
+            collected.stops.push(Job {
+                unit: other,
+                job_type: JobType::Stop,
+                required: true,
+            });
+        }
+    }
+
+    // Requisites are checked for the units that are left.
+    for job in &collected.starts {
+        for other in &graph.get(&job.unit).unwrap().requisite {
+            match collected.verifies.iter_mut().find(|verify| &verify.unit == other) {
+                Some(verify) => verify.required |= job.required,
+                None => collected.verifies.push(Job {
+                    unit: other.clone(),
+                    job_type: JobType::VerifyActive,
+                    required: job.required,
+                }),
+            }
+        }
+    }
+
+    Ok(collected)
+}
+
+/// Leave out the units that aren't required and can't be started, because a
+/// unit they require or their requisite is missing or was left out itself.
+fn prune(graph: &DepGraph, collected: &mut Collected) {
+    loop {
+        let starting: HashSet<String> =
+            collected.starts.iter().map(|job| job.unit.clone()).collect();
+        let missing = collected.starts.iter().find_map(|job| {
+            let deps = graph.get(&job.unit).unwrap();
+            let missing = deps
+                .requires
+                .iter()
+                .chain(deps.binds_to.iter())
+                .find(|other| !starting.contains(*other))
+                .or_else(|| deps.requisite.iter().find(|other| !graph.contains(other)));
+            match missing {
+                Some(other) if !job.required => Some((job.unit.clone(), other.clone())),
+                _ => None,
+            }
+        });
+        match missing {
+            Some((name, other)) => {
+                let reason = if !graph.contains(&other) {
+                    format!("dependency {} not found", other)
+                } else {
+                    match collected.pruned.iter().find(|(unit, _)| *unit == other) {
+                        Some((_, why)) => {
+                            format!("dependency {} failed: {}", other, why)
+                        }
+                        None => format!("dependency {} can't be started", other),
+                    }
+                };
+                collected.starts.retain(|job| job.unit != name);
+                collected.pruned.push((name, reason));
+            }
+            None => return,
+        }
+    }
+}
+
+/// Order jobs so that every unit comes after the units it is ordered After=.
+/// Returns the units involved in a cycle if there is one.
+fn order_after(graph: &DepGraph, jobs: &[Job]) -> Result<Vec<String>, Vec<String>> {
+    let names: Vec<&str> = jobs.iter().map(|job| job.unit.as_str()).collect();
+    let in_transaction = |name: &&String| names.contains(&name.as_str());
+
+    let mut order: Vec<String> = vec![];
+    let mut remaining = names.clone();
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|name| {
+            graph
+                .after(name)
+                .iter()
+                .filter(in_transaction)
+                .all(|other| order.iter().any(|done| done == other))
+        });
+        match ready {
+            Some(pos) => order.push(remaining.remove(pos).to_string()),
+            None => return Err(find_cycle(graph, &remaining)),
+        }
+    }
+    Ok(order)
+}
+
+/// Each of the remaining units waits on another remaining unit, so following
+/// After= from any of them has to come back around.
+fn find_cycle(graph: &DepGraph, remaining: &[&str]) -> Vec<String> {
+    let mut path: Vec<&str> = vec![remaining[0]];
+    loop {
+        let current = path[path.len() - 1];
+        let next = graph
+            .after(current)
+            .iter()
+            .find(|other| remaining.contains(&other.as_str()))
+            .map(|other| other.as_str())
+            .unwrap();
+        if let Some(pos) = path.iter().position(|name| *name == next) {
+            return path[pos..].iter().map(|name| name.to_string()).collect();
+        }
+        path.push(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deps::Dependencies;
+    use crate::unitfile::UnitFile;
+
+    /// A graph of units, each with the dependencies of its [Unit] section.
+    fn graph(units: &[(&str, &str)]) -> DepGraph {
+        let mut graph = DepGraph::new();
+        for (name, unit) in units {
+            let unitfile =
+                UnitFile::parse(name, &format!("[Unit]\n{}\n", unit)).unwrap();
+            let deps = Dependencies::from_section(unitfile.section("Unit").unwrap());
+            graph.add(name, &deps);
+        }
+        graph
+    }
+
+    fn jobs(transaction: &Transaction) -> Vec<(&str, JobType, bool)> {
+        transaction
+            .jobs
+            .iter()
+            .map(|job| (job.unit.as_str(), job.job_type, job.required))
+            .collect()
+    }
+
+    #[test]
+    fn test_start_order() {
+        let graph = graph(&[
+            ("a", "Requires=b\nAfter=b"),
+            ("b", "Wants=c missing"),
+            ("c", "Before=b"),
+            ("d", ""),
+        ]);
+        let transaction = Transaction::start(&graph, &["a"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![
+                ("c", JobType::Start, false),
+                ("b", JobType::Start, true),
+                ("a", JobType::Start, true),
+            ]
+        );
+        assert!(transaction.broken_cycles.is_empty());
+
+        let graph = self::graph(&[("a", "Requires=missing")]);
+        assert_eq!(
+            Transaction::start(&graph, &["a"]).unwrap_err(),
+            TransactionError::NotFound("missing".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cycle_broken_through_wants() {
+        let graph = graph(&[
+            ("a", "Requires=b\nWants=c\nAfter=b"),
+            ("b", "After=c"),
+            ("c", "After=a"),
+        ]);
+        let transaction = Transaction::start(&graph, &["a"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("b", JobType::Start, true), ("a", JobType::Start, true)]
+        );
+        assert_eq!(transaction.broken_cycles.len(), 1);
+        let (cycle, victim) = &transaction.broken_cycles[0];
+        assert_eq!(victim, "c");
+        let mut cycle = cycle.clone();
+        cycle.sort();
+        assert_eq!(cycle, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_required_cycle() {
+        let graph = graph(&[
+            ("a", "Requires=b\nAfter=b"),
+            ("b", "Requires=c\nAfter=c"),
+            ("c", "After=a"),
+        ]);
+        match Transaction::start(&graph, &["a"]).unwrap_err() {
+            TransactionError::OrderingCycle(mut cycle) => {
+                cycle.sort();
+                assert_eq!(cycle, vec!["a", "b", "c"]);
+            }
+            e => panic!("expected an ordering cycle, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_conflicts() {
+        // A conflicting unit that isn't part of the transaction is stopped
+        // first.
+        let graph = graph(&[("a", "Conflicts=b"), ("b", "")]);
+        let transaction = Transaction::start(&graph, &["a"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("b", JobType::Stop, true), ("a", JobType::Start, true)]
+        );
+        // The conflict goes both ways.
+        let transaction = Transaction::start(&graph, &["b"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("a", JobType::Stop, true), ("b", JobType::Start, true)]
+        );
+
+        // Between two units to start, the one that's only wanted loses and is
+        // stopped instead.
+        let graph = self::graph(&[("a", "Wants=b\nConflicts=b"), ("b", "")]);
+        let transaction = Transaction::start(&graph, &["a"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("b", JobType::Stop, true), ("a", JobType::Start, true)]
+        );
+
+        let graph = self::graph(&[("a", "Requires=b"), ("b", "Conflicts=a")]);
+        assert_eq!(
+            Transaction::start(&graph, &["a"]).unwrap_err(),
+            TransactionError::Conflict("b".to_string(), "a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_requisite() {
+        // The requisite is only checked, before anything is started.
+        let graph = graph(&[("a", "Requisite=b\nAfter=b"), ("b", "")]);
+        let transaction = Transaction::start(&graph, &["a"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("b", JobType::VerifyActive, true), ("a", JobType::Start, true)]
+        );
+
+        let graph = self::graph(&[("a", "Requisite=missing")]);
+        assert_eq!(
+            Transaction::start(&graph, &["a"]).unwrap_err(),
+            TransactionError::NotFound("missing".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stop() {
+        let graph = graph(&[
+            ("a", "Requires=b\nAfter=b"),
+            ("b", "After=c"),
+            ("c", "PartOf=b"),
+            ("d", "Wants=b"),
+        ]);
+        let transaction = Transaction::stop(&graph, &["b"]);
+        assert_eq!(
+            jobs(&transaction),
+            vec![
+                ("a", JobType::Stop, true),
+                ("b", JobType::Stop, true),
+                ("c", JobType::Stop, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_start_wanted() {
+        // A unit missing what it requires is left out along with the units
+        // requiring it, the others still start.
+        let graph = graph(&[
+            ("a", "Requires=missing"),
+            ("b", "BindsTo=a"),
+            ("c", "Requisite=missing"),
+            ("d", "Wants=missing"),
+        ]);
+        let transaction =
+            Transaction::start_wanted(&graph, &["a", "b", "c", "d"]).unwrap();
+        assert_eq!(jobs(&transaction), vec![("d", JobType::Start, false)]);
+        let mut pruned = transaction.pruned.clone();
+        pruned.sort();
+        let expected = [
+            ("a", "dependency missing not found"),
+            ("b", "dependency a failed: dependency missing not found"),
+            ("c", "dependency missing not found"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(unit, reason)| (unit.to_string(), reason.to_string()))
+            .collect();
+        assert_eq!(pruned, expected);
+
+        // Breaking a cycle of required units drops one of them and with it
+        // the other.
+        let graph = self::graph(&[
+            ("a", "Requires=b\nAfter=b"),
+            ("b", "Requires=a\nAfter=a"),
+            ("c", "After=a"),
+        ]);
+        let transaction = Transaction::start_wanted(&graph, &["a", "b", "c"]).unwrap();
+        assert_eq!(jobs(&transaction), vec![("c", JobType::Start, false)]);
+        assert_eq!(transaction.broken_cycles.len(), 1);
+
+        // Of two conflicting units only one is started, the other is stopped.
+        let graph = self::graph(&[("a", "Conflicts=b"), ("b", "")]);
+        let transaction = Transaction::start_wanted(&graph, &["a", "b"]).unwrap();
+        assert_eq!(
+            jobs(&transaction),
+            vec![("a", JobType::Stop, true), ("b", JobType::Start, false)]
+        );
+    }
+}
@@ -0,0 +1,537 @@
+/// unitfile.rs includes a small parser for systemd style unit files.
+///
+/// Unit files look like ini files, but they have a few rules of their own: keys
+/// can be repeated to build up lists, a line ending with a backslash continues
+/// on the next line and comments are only allowed at the start of a line. We
+/// also want to remember where each value came from so that we can point the
+/// user to the exact line when something is wrong with it.
+use nix::sys::signal::Signal;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// An error encountered while loading a unit file.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitParseError {
+    /// Path to the unit file which failed to load.
+    pub path: String,
+    /// Line number (starting at 1) where the error was found, if known.
+    pub line: Option<usize>,
+    /// The section in which the error was found, if any.
+    pub section: Option<String>,
+    /// The key which failed to parse, if any.
+    pub key: Option<String>,
+    /// Human readable reason for the failure.
+    pub reason: String,
+}
+
+impl fmt::Display for UnitParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        write!(f, ":")?;
+        if let Some(section) = &self.section {
+            write!(f, " [{}]", section)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " {}=", key)?;
+        }
+        write!(f, " {}", self.reason)
+    }
+}
+
+impl Error for UnitParseError {}
+
+/// A single `Key=Value` assignment in a unit file.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    /// Line number where this assignment starts.
+    pub line: usize,
+}
+
+/// A `[Section]` in a unit file along with all of its assignments in the order
+/// they appeared.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    /// Line number of the section header.
+    pub line: usize,
+    entries: Vec<Entry>,
+}
+
+impl Section {
+    /// Return the last assignment for the key, which is the one that wins for
+    /// keys that take a single value.
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().rev().find(|entry| entry.key == key)
+    }
+
+    /// Return all the assignments for a key which takes a list of values. An
+    /// empty assignment resets the list, like it does in systemd.
+    pub fn get_all(&self, key: &str) -> Vec<&Entry> {
+        let mut all = vec![];
+        for entry in self.entries.iter().filter(|entry| entry.key == key) {
+            if entry.value.is_empty() {
+                all.clear();
+            } else {
+                all.push(entry);
+            }
+        }
+        all
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+/// A parsed unit file.
+#[derive(Debug, Clone)]
+pub struct UnitFile {
+    pub path: String,
+    sections: Vec<Section>,
+}
+
+impl UnitFile {
+    /// Read and parse the unit file at path.
+    pub fn load(path: &Path) -> Result<UnitFile, UnitParseError> {
+        let display = path.display().to_string();
+        match fs::read_to_string(path) {
+            Ok(contents) => UnitFile::parse(&display, &contents),
+            Err(e) => Err(UnitParseError {
+                path: display,
+                line: None,
+                section: None,
+                key: None,
+                reason: format!("failed to read unit file: {}", e),
+            }),
+        }
+    }
+
+    /// Parse the contents of a unit file, path is only used for reporting
+    /// errors.
+    pub fn parse(path: &str, contents: &str) -> Result<UnitFile, UnitParseError> {
+        let mut unitfile = UnitFile { path: path.to_string(), sections: vec![] };
+        let mut lines = contents.lines().enumerate();
+
+        while let Some((index, raw)) = lines.next() {
+            let lineno = index + 1;
+            let mut line = raw.trim().to_string();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            // A trailing backslash joins the next line into this one with a
+            // space. Comment lines in between are skipped.
+            while line.ends_with('\\') {
+                line.pop();
+                match lines.next() {
+                    Some((_, next)) => {
+                        let next = next.trim();
+                        if next.starts_with('#') || next.starts_with(';') {
+                            line.push('\\');
+                            continue;
+                        }
+                        line.push(' ');
+                        line.push_str(next);
+                    }
+                    None => break,
+                }
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') || line.len() < 3 {
+                    return Err(unitfile.error(
+                        Some(lineno),
+                        None,
+                        None,
+                        &format!("invalid section header {:?}", line),
+                    ));
+                }
+                unitfile.sections.push(Section {
+                    name: line[1..line.len() - 1].to_string(),
+                    line: lineno,
+                    entries: vec![],
+                });
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => {
+                    return Err(unitfile.error(
+                        Some(lineno),
+                        unitfile.sections.last().map(|s| s.name.as_str()),
+                        None,
+                        &format!("expected Key=Value assignment, got {:?}", line),
+                    ))
+                }
+            };
+            if key.is_empty() {
+                return Err(unitfile.error(
+                    Some(lineno),
+                    unitfile.sections.last().map(|s| s.name.as_str()),
+                    None,
+                    "assignment without a key",
+                ));
+            }
+
+            let entry =
+                Entry { key: key.to_string(), value: value.to_string(), line: lineno };
+            match unitfile.sections.last_mut() {
+                Some(section) => section.entries.push(entry),
+                None => {
+                    return Err(unitfile.error(
+                        Some(lineno),
+                        None,
+                        Some(key),
+                        "assignment outside of any section",
+                    ))
+                }
+            }
+        }
+
+        Ok(unitfile)
+    }
+
+    /// Return the section with name, if it exists.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// Return the section with name, or an error saying it is missing.
+    pub fn required_section(&self, name: &str) -> Result<&Section, UnitParseError> {
+        self.section(name).ok_or_else(|| {
+            self.error(None, Some(name), None, "required section is missing")
+        })
+    }
+
+    /// Return the value of key in section, or an error pointing at the section
+    /// if it is missing.
+    pub fn required<'a>(
+        &self,
+        section: &'a Section,
+        key: &str,
+    ) -> Result<&'a Entry, UnitParseError> {
+        section.get(key).ok_or_else(|| {
+            self.error(
+                Some(section.line),
+                Some(&section.name),
+                Some(key),
+                "required key is missing",
+            )
+        })
+    }
+
+    /// Return the boolean value of key in section, or default if it is not set.
+    pub fn get_bool(
+        &self,
+        section: &Section,
+        key: &str,
+        default: bool,
+    ) -> Result<bool, UnitParseError> {
+        match section.get(key) {
+            Some(entry) => parse_bool(&entry.value)
+                .ok_or_else(|| self.invalid(section, entry, "expected a boolean value")),
+            None => Ok(default),
+        }
+    }
+
+    /// Return the signal named by key in section, or default if it is not set.
+    pub fn get_signal(
+        &self,
+        section: &Section,
+        key: &str,
+        default: Signal,
+    ) -> Result<Signal, UnitParseError> {
+        match section.get(key) {
+            Some(entry) => parse_signal(&entry.value)
+                .ok_or_else(|| self.invalid(section, entry, "unknown signal")),
+            None => Ok(default),
+        }
+    }
+
+    /// Return the time span value of key in section, or default if it is not
+    /// set. `infinity` is returned as None.
+    pub fn get_timespan(
+        &self,
+        section: &Section,
+        key: &str,
+        default: Option<Duration>,
+    ) -> Result<Option<Duration>, UnitParseError> {
+        match section.get(key) {
+            Some(entry) => parse_timespan(&entry.value)
+                .map_err(|e| self.invalid(section, entry, &e)),
+            None => Ok(default),
+        }
+    }
+
+    /// Return the value of key in section parsed with `FromStr`, or default if
+    /// it is not set.
+    pub fn get_parsed<T>(
+        &self,
+        section: &Section,
+        key: &str,
+        default: T,
+    ) -> Result<T, UnitParseError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match section.get(key) {
+            Some(entry) => entry
+                .value
+                .parse()
+                .map_err(|e: T::Err| self.invalid(section, entry, &e.to_string())),
+            None => Ok(default),
+        }
+    }
+
+    /// Build an error for a value that was found but could not be understood.
+    pub fn invalid(
+        &self,
+        section: &Section,
+        entry: &Entry,
+        reason: &str,
+    ) -> UnitParseError {
+        self.error(Some(entry.line), Some(&section.name), Some(&entry.key), reason)
+    }
+
+    pub fn error(
+        &self,
+        line: Option<usize>,
+        section: Option<&str>,
+        key: Option<&str>,
+        reason: &str,
+    ) -> UnitParseError {
+        UnitParseError {
+            path: self.path.clone(),
+            line,
+            section: section.map(|s| s.to_string()),
+            key: key.map(|k| k.to_string()),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Parse a boolean the way systemd does, accepting yes/no, true/false, on/off
+/// and 1/0.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Some(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// A signal by name, with or without the SIG prefix, or by number.
+pub fn parse_signal(signal: &str) -> Option<Signal> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = signal.to_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    Signal::from_str(&name).ok()
+}
+
+/// Parse a time span like `90`, `1min 30s` or `500ms` the way systemd does. A
+/// number without a unit is in seconds and `infinity` is returned as None.
+pub fn parse_timespan(value: &str) -> Result<Option<Duration>, String> {
+    let value = value.trim();
+    if value == "infinity" {
+        return Ok(None);
+    }
+
+    let mut total = Duration::from_secs(0);
+    let mut rest = value;
+    if rest.is_empty() {
+        return Err("empty time span".to_string());
+    }
+    while !rest.is_empty() {
+        let digits =
+            rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let number: f64 = rest[..digits]
+            .parse()
+            .map_err(|_| format!("invalid time span {:?}", value))?;
+        rest = rest[digits..].trim_start();
+        let unit_len =
+            rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_len] {
+            "us" | "usec" => 0.000_001,
+            "ms" | "msec" => 0.001,
+            "" | "s" | "sec" | "second" | "seconds" => 1.0,
+            "m" | "min" | "minute" | "minutes" => 60.0,
+            "h" | "hr" | "hour" | "hours" => 3600.0,
+            "d" | "day" | "days" => 86400.0,
+            "w" | "week" | "weeks" => 604_800.0,
+            unit => return Err(format!("unknown time unit {:?}", unit)),
+        };
+        total += Duration::from_secs_f64(number * multiplier);
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> UnitFile {
+        UnitFile::parse("test.service", contents).unwrap()
+    }
+
+    fn values(section: &Section, key: &str) -> Vec<String> {
+        section.get_all(key).iter().map(|entry| entry.value.clone()).collect()
+    }
+
+    #[test]
+    fn test_sections_and_entries() {
+        let unitfile = parse(
+            "[Unit]\nDescription = A test unit \n\n[Service]\nExecStart=/bin/true\n",
+        );
+        let unit = unitfile.section("Unit").unwrap();
+        assert_eq!(unit.line, 1);
+        let description = unit.get("Description").unwrap();
+        assert_eq!(description.value, "A test unit");
+        assert_eq!(description.line, 2);
+        let service = unitfile.section("Service").unwrap();
+        assert_eq!(service.get("ExecStart").unwrap().value, "/bin/true");
+        assert!(unitfile.section("Install").is_none());
+        // Only the first = splits the key from the value.
+        let unitfile = parse("[Service]\nEnvironment=A=1 B=2\n");
+        let service = unitfile.section("Service").unwrap();
+        assert_eq!(service.get("Environment").unwrap().value, "A=1 B=2");
+    }
+
+    #[test]
+    fn test_comments() {
+        let unitfile = parse(
+            "# leading comment\n; another one\n[Service]\n  # indented\nType=simple\n",
+        );
+        let service = unitfile.section("Service").unwrap();
+        assert_eq!(service.entries().len(), 1);
+        assert_eq!(service.get("Type").unwrap().line, 5);
+    }
+
+    #[test]
+    fn test_continuations() {
+        let unitfile = parse(
+            "[Service]\nExecStart=/bin/echo \\\n    one \\\n# skipped\n    two\nType=oneshot\n",
+        );
+        let service = unitfile.section("Service").unwrap();
+        let exec_start = service.get("ExecStart").unwrap();
+        assert_eq!(exec_start.value, "/bin/echo  one  two");
+        assert_eq!(exec_start.line, 2);
+        assert_eq!(service.get("Type").unwrap().value, "oneshot");
+
+        // A continuation at the end of the file is just dropped.
+        let unitfile = parse("[Service]\nExecStart=/bin/true \\");
+        let service = unitfile.section("Service").unwrap();
+        assert_eq!(service.get("ExecStart").unwrap().value, "/bin/true");
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let unitfile =
+            parse("[Service]\nType=simple\nType=forking\nWants=a\nWants=b c\n");
+        let service = unitfile.section("Service").unwrap();
+        // The last assignment wins for single values, all of them are kept for
+        // lists.
+        assert_eq!(service.get("Type").unwrap().value, "forking");
+        assert_eq!(service.get("Type").unwrap().line, 3);
+        assert_eq!(values(service, "Type"), vec!["simple", "forking"]);
+        assert_eq!(values(service, "Wants"), vec!["a", "b c"]);
+    }
+
+    #[test]
+    fn test_empty_value_resets() {
+        let unitfile = parse(
+            "[Service]\nExecStartPre=/bin/a\nExecStartPre=\nExecStartPre=/bin/b\n",
+        );
+        let service = unitfile.section("Service").unwrap();
+        assert_eq!(values(service, "ExecStartPre"), vec!["/bin/b"]);
+
+        let unitfile = parse("[Service]\nExecStartPre=/bin/a\nExecStartPre=\n");
+        let service = unitfile.section("Service").unwrap();
+        assert!(values(service, "ExecStartPre").is_empty());
+        assert_eq!(service.get("ExecStartPre").unwrap().value, "");
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let error =
+            UnitFile::parse("test.service", "[Service\nType=simple\n").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        assert!(error.reason.contains("invalid section header"));
+
+        let error = UnitFile::parse("test.service", "[]\n").unwrap_err();
+        assert!(error.reason.contains("invalid section header"));
+
+        let error = UnitFile::parse("test.service", "[Service]\nType=simple\njunk\n")
+            .unwrap_err();
+        assert_eq!(error.path, "test.service");
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.section.as_deref(), Some("Service"));
+        assert!(error.reason.contains("expected Key=Value"));
+
+        let error = UnitFile::parse("test.service", "[Service]\n=value\n").unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.reason, "assignment without a key");
+
+        let error = UnitFile::parse("test.service", "Type=simple\n").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        assert_eq!(error.key.as_deref(), Some("Type"));
+        assert_eq!(error.reason, "assignment outside of any section");
+    }
+
+    #[test]
+    fn test_required() {
+        let unitfile = parse("[Service]\nType=simple\n");
+        let error = unitfile.required_section("Unit").unwrap_err();
+        assert_eq!(error.section.as_deref(), Some("Unit"));
+        let service = unitfile.required_section("Service").unwrap();
+        let error = unitfile.required(service, "ExecStart").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        assert_eq!(error.key.as_deref(), Some("ExecStart"));
+        assert_eq!(unitfile.required(service, "Type").unwrap().value, "simple");
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        for value in &["1", "yes", "Y", "true", "t", "On"] {
+            assert_eq!(parse_bool(value), Some(true), "{}", value);
+        }
+        for value in &["0", "no", "N", "false", "f", "OFF"] {
+            assert_eq!(parse_bool(value), Some(false), "{}", value);
+        }
+        assert_eq!(parse_bool("maybe"), None);
+        assert_eq!(parse_bool(""), None);
+    }
+
+    #[test]
+    fn test_parse_timespan() {
+        let secs = |secs: f64| Ok(Some(Duration::from_secs_f64(secs)));
+        assert_eq!(parse_timespan("90"), secs(90.0));
+        assert_eq!(parse_timespan("1.5"), secs(1.5));
+        assert_eq!(parse_timespan("500ms"), secs(0.5));
+        assert_eq!(parse_timespan("20us"), secs(0.000_02));
+        assert_eq!(parse_timespan("1min 30s"), secs(90.0));
+        assert_eq!(parse_timespan(" 2h5min "), secs(7500.0));
+        assert_eq!(parse_timespan("1d 1w"), secs(691_200.0));
+        assert_eq!(parse_timespan("infinity"), Ok(None));
+        assert!(parse_timespan("").is_err());
+        assert!(parse_timespan("5 fortnights").is_err());
+        assert!(parse_timespan("min").is_err());
+        assert!(parse_timespan("1..2s").is_err());
+    }
+}
@@ -1,18 +1,38 @@
-use ini::Ini;
+use crate::capabilities::{self, Capabilities, CapabilitySet};
+use crate::cgroup;
+use crate::cmdline::{parse_command_line, ExecCommand};
+use crate::credentials::{self, Credentials};
+use crate::deps::{DepGraph, Dependencies};
+use crate::environment;
+use crate::events::{self, EventKind};
+use crate::execute::{self, ExecContext};
+use crate::logs::{self, LogStream};
+use crate::monitor;
+use crate::namespace::{self, Sandbox};
+use crate::notify;
+use crate::resources::{self, Resources};
+use crate::unitfile::{UnitFile, UnitParseError};
 use lazy_static::lazy_static;
-use nix::errno::Errno::{EINVAL, EPERM, ESRCH};
+use nix::errno::Errno::ESRCH;
 use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use nix::Error::Sys;
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io;
-use std::path::Path;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
-use std::string::ToString;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::{thread, time};
+use std::time;
+use std::time::Duration;
 
 #[derive(Debug, Serialize)]
 pub struct Install {
@@ -20,20 +40,202 @@ pub struct Install {
     alias: Option<String>,
 }
 
-#[derive(Debug, Copy, Clone, Serialize)]
+/// When a service is started again after its main process exited, from
+/// `Restart=`, see systemd.service(5).
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum RestartMethod {
-    OnFailure,
+    No,
     Always,
-    Never,
+    /// After a clean exit, with exit code 0 or one of the clean signals.
+    OnSuccess,
+    /// After an unclean exit or a watchdog timeout.
+    OnFailure,
+    /// After an unclean signal or a watchdog timeout.
+    OnAbnormal,
+    /// After an unclean signal.
+    OnAbort,
+    /// After a watchdog timeout.
+    OnWatchdog,
+}
+
+impl FromStr for RestartMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no" => Ok(RestartMethod::No),
+            "always" => Ok(RestartMethod::Always),
+            "on-success" => Ok(RestartMethod::OnSuccess),
+            "on-failure" => Ok(RestartMethod::OnFailure),
+            "on-abnormal" => Ok(RestartMethod::OnAbnormal),
+            "on-abort" => Ok(RestartMethod::OnAbort),
+            "on-watchdog" => Ok(RestartMethod::OnWatchdog),
+            _ => Err(format!("unknown restart policy {:?}", s)),
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, Serialize)]
+/// Signals a process may be terminated by and still count as exiting cleanly.
+const CLEAN_SIGNALS: [Signal; 4] =
+    [Signal::SIGHUP, Signal::SIGINT, Signal::SIGTERM, Signal::SIGPIPE];
+
+/// Which processes of a service are signaled to stop it, from `KillMode=`,
+/// see systemd.kill(5).
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum KillModeEnum {
+    /// All of them.
+    ControlGroup,
+    /// The main process gets KillSignal=, all of them FinalKillSignal=.
+    Mixed,
+    /// Only the main process.
     Process,
-    All,
+    /// None of them, only ExecStop= is run.
+    None,
+}
+
+impl FromStr for KillModeEnum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "control-group" => Ok(KillModeEnum::ControlGroup),
+            "mixed" => Ok(KillModeEnum::Mixed),
+            "process" => Ok(KillModeEnum::Process),
+            "none" => Ok(KillModeEnum::None),
+            _ => Err(format!("unknown kill mode {:?}", s)),
+        }
+    }
+}
+
+fn serialize_signal<S: Serializer>(signal: &Signal, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(signal.as_str())
+}
+
+/// How a service starts up and when it is considered started, from `Type=`.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum ServiceType {
+    /// Started as soon as the main process has been spawned.
+    Simple,
+    /// Started as soon as the main process has executed the binary.
+    Exec,
+    /// The main process forks off the daemon and exits, the service is
+    /// started once it has exited.
+    Forking,
+    /// The process runs to completion, the service is started once it exits.
+    Oneshot,
+    /// Started once the main process sends READY=1 over sd_notify.
+    Notify,
+    /// Like Simple, but meant to run once everything else is started.
+    Idle,
+}
+
+impl FromStr for ServiceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(ServiceType::Simple),
+            "exec" => Ok(ServiceType::Exec),
+            "forking" => Ok(ServiceType::Forking),
+            "oneshot" => Ok(ServiceType::Oneshot),
+            "notify" => Ok(ServiceType::Notify),
+            "idle" => Ok(ServiceType::Idle),
+            _ => Err(format!("unknown service type {:?}", s)),
+        }
+    }
+}
+
+/// Where the output of a service goes, see StandardOutput= in systemd.exec(5).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Output {
+    /// Wherever getupd's output goes, for stderr the same as stdout.
+    Inherit,
+    Null,
+    /// Written to the file, from the start without truncating it.
+    File(PathBuf),
+    /// Appended to the file.
+    Append(PathBuf),
+    /// Stored in the log store.
+    Journal,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            return Ok(Output::File(absolute_path(path)?));
+        }
+        if let Some(path) = s.strip_prefix("append:") {
+            return Ok(Output::Append(absolute_path(path)?));
+        }
+        match s {
+            "inherit" => Ok(Output::Inherit),
+            "null" => Ok(Output::Null),
+            "journal" => Ok(Output::Journal),
+            _ => Err(format!("unknown output {:?}", s)),
+        }
+    }
+}
+
+fn absolute_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(format!("{} is not an absolute path", path.display()))
+    }
+}
+
+/// Where the output of a process actually goes.
+enum Target {
+    Inherit,
+    Null,
+    Pipe,
+    File(fs::File),
+}
+
+impl Target {
+    fn open(output: &Output) -> io::Result<Target> {
+        Ok(match output {
+            Output::Inherit => Target::Inherit,
+            Output::Null => Target::Null,
+            Output::File(path) => Target::File(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?,
+            ),
+            Output::Append(path) => {
+                Target::File(OpenOptions::new().append(true).create(true).open(path)?)
+            }
+            Output::Journal if logs::is_open() => Target::Pipe,
+            Output::Journal => Target::Inherit,
+        })
+    }
+
+    /// The same target again, a pipe is another pipe to tell the streams apart.
+    fn try_clone(&self) -> io::Result<Target> {
+        Ok(match self {
+            Target::Inherit => Target::Inherit,
+            Target::Null => Target::Null,
+            Target::Pipe => Target::Pipe,
+            Target::File(file) => Target::File(file.try_clone()?),
+        })
+    }
+
+    fn stdio(self) -> Stdio {
+        match self {
+            Target::Inherit => Stdio::inherit(),
+            Target::Null => Stdio::null(),
+            Target::Pipe => Stdio::piped(),
+            Target::File(file) => Stdio::from(file),
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
 pub enum CurrState {
     Stopping,
     Stopped,
@@ -41,30 +243,182 @@ pub enum CurrState {
     Running,
     Failed,
     Restarting,
+    Reloading,
 }
 
 /// A collection of all the unit files in a system.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct AllUnits {
     units: Vec<Unit>,
+    /// Unit files which could not be loaded, along with the reason why.
+    failed_to_load: Vec<UnitParseError>,
 }
 
 impl AllUnits {
     pub fn new() -> AllUnits {
-        AllUnits { units: vec![] }
+        AllUnits { units: vec![], failed_to_load: vec![] }
     }
 
     pub fn add_unit(&mut self, u: Unit) {
         self.units.push(u)
     }
 
+    /// Record a unit file that failed to load so that it can be reported.
+    pub fn add_failed(&mut self, e: UnitParseError) {
+        self.failed_to_load.push(e)
+    }
+
+    /// Take in the units read again from their files. Units that are active
+    /// keep running as they are, the others are replaced, new units are
+    /// loaded and the ones whose file is gone are unloaded.
+    pub fn reload(&mut self, units: Vec<Unit>, failed: Vec<UnitParseError>) {
+        let mut previous = std::mem::take(&mut self.units);
+        for unit in units {
+            match previous.iter().position(|old| old.name == unit.name) {
+                Some(pos) if previous[pos].is_active() => {
+                    println!("Not reloading {} while it is active", unit.name);
+                    self.units.push(previous.remove(pos));
+                }
+                Some(pos) => {
+                    previous.remove(pos);
+                    self.units.push(unit);
+                }
+                None => {
+                    events::publish(EventKind::UnitLoaded { unit: unit.name.clone() });
+                    self.units.push(unit);
+                }
+            }
+        }
+        for unit in previous {
+            if unit.is_active() {
+                println!("Keeping {} loaded, it is still active", unit.name);
+                self.units.push(unit);
+            } else {
+                events::publish(EventKind::UnitUnloaded { unit: unit.name.clone() });
+            }
+        }
+        self.failed_to_load = failed;
+    }
+
+    pub fn failed_to_load(&self) -> &[UnitParseError] {
+        &self.failed_to_load
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Unit> {
+        self.units.iter()
+    }
+
     pub fn get_by_name(&self, name: &str) -> Option<&Unit> {
         // Given the name of a service, return if it exists
-        self.units.iter().find(|&x| x.path.ends_with(name))
+        self.units.iter().find(|&x| x.name == name)
+    }
+
+    /// The unit the process belongs to, which is the unit that spawned it or
+    /// one of its ancestors.
+    pub fn unit_of_pid(&self, pid: u32) -> Option<&Unit> {
+        if let Some(name) = cgroup::unit_of_pid(pid) {
+            return self.units.iter().find(|u| u.name == name);
+        }
+        let mut pid = pid;
+        // PID 1 and 0 are as far up as it goes.
+        while pid > 1 {
+            let owner = self.units.iter().find(|u| u.service.lock().unwrap().owns(pid));
+            if owner.is_some() {
+                return owner;
+            }
+            pid = parent_pid(pid)?;
+        }
+        None
+    }
+
+    /// Build the dependency graph between all the loaded units.
+    pub fn dep_graph(&self) -> DepGraph {
+        let mut graph = DepGraph::new();
+        for unit in &self.units {
+            graph.add(&unit.name, &unit.dependencies);
+        }
+        graph
+    }
+}
+
+/// Send signal to the processes, which may be gone already.
+fn signal_all(pids: &[u32], signal: Signal) {
+    for pid in pids {
+        match kill(Pid::from_raw(*pid as i32), signal) {
+            Ok(()) | Err(Sys(ESRCH)) => {}
+            Err(e) => println!("Failed to send {} to {}: {}", signal, pid, e),
+        }
+    }
+}
+
+/// Whether /proc is that of our PID namespace. Under `unshare --pid` without
+/// remounting it, it has the processes of the host, whose PIDs mean something
+/// else here.
+fn proc_is_ours() -> bool {
+    let pid = fs::read_link("/proc/self")
+        .ok()
+        .and_then(|link| link.to_str()?.parse::<u32>().ok());
+    pid == Some(std::process::id())
+}
+
+/// The processes along with all their descendants that are still around.
+/// Without a /proc of our own, only the processes themselves are known.
+fn descendants(roots: &[u32]) -> Vec<u32> {
+    if !proc_is_ours() {
+        return roots.to_vec();
+    }
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = match entry.file_name().to_string_lossy().parse() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            if let Some(parent) = parent_pid(pid) {
+                children.entry(parent).or_default().push(pid);
+            }
+        }
+    }
+    let mut found: Vec<u32> = vec![];
+    let mut queue = roots.to_vec();
+    while let Some(pid) = queue.pop() {
+        if !found.contains(&pid) {
+            found.push(pid);
+            queue.extend(children.get(&pid).into_iter().flatten());
+        }
+    }
+    found
+}
+
+/// Whether the process exists and isn't a zombie, which is as good as gone
+/// even if whoever has to reap it doesn't get to it.
+fn is_running(pid: u32) -> bool {
+    if !proc_is_ours() {
+        return kill(Pid::from_raw(pid as i32), None).is_ok();
+    }
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().next())
+            .is_some_and(|state| state != "Z"),
+        Err(_) => false,
+    }
+}
+
+/// The parent of the process, from the fourth field of /proc/PID/stat. The
+/// second one is the command in parentheses, which may contain spaces.
+fn parent_pid(pid: u32) -> Option<u32> {
+    if !proc_is_ours() {
+        return None;
     }
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
 
-    pub fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+impl fmt::Display for AllUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(&self).map_err(|_| fmt::Error)?)
     }
 }
 
@@ -73,6 +427,8 @@ impl AllUnits {
 /// system.
 #[derive(Debug, Serialize)]
 pub struct Unit {
+    /// Name of the unit, which is the file name of the unit file.
+    pub name: String,
     /// Path to the systemd config file on the host from where it was read.
     pub path: String,
     /// Description of the Unit.
@@ -86,106 +442,432 @@ pub struct Unit {
     /// How to install this Unit.
     pub install: Install,
 
-    // We use Option and Arc here because these values can be None and we need
-    // to Read these values through multiple threads and might need sharing,
-    // because of which, we need some sort of reference counting structure.
-    /// Start this unit file after the service file for after is started.
-    after: Option<Arc<Unit>>,
-
-    before: Option<Arc<Unit>>,
-
-    wants: Option<Arc<Unit>>,
+    /// Requirement and ordering dependencies on other units, by name. They are
+    /// resolved through `AllUnits::dep_graph` when the units are started.
+    pub dependencies: Dependencies,
 }
 
 impl Unit {
-    pub fn from_unitfile(inifile: &Path) -> Unit {
-        let conf = Ini::load_from_file(inifile.to_str().unwrap()).unwrap();
-        let unit =
-            conf.section(Some("Unit".to_owned())).expect("failed to get section: Unit");
-        let service = conf
-            .section(Some("Service".to_owned()))
-            .expect("failed to get section: Service");
-        let install = conf
-            .section(Some("Install".to_owned()))
-            .expect("failed to get section: Install");
-
-        let _documentation = None;
-        if let Some(desc) = unit.get("Documentation") {
-            let _documentation = Some(desc.to_string());
+    /// Whether the unit is doing anything, as opposed to stopped or failed.
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self.service.lock().unwrap().status(),
+            CurrState::Stopped | CurrState::Failed
+        )
+    }
+
+    pub fn from_unitfile(inifile: &Path) -> Result<Unit, UnitParseError> {
+        let conf = UnitFile::load(inifile)?;
+        let unit = conf.section("Unit");
+        let service = conf.required_section("Service")?;
+        let install = conf.section("Install");
+
+        let description = unit
+            .and_then(|unit| unit.get("Description"))
+            .map(|entry| entry.value.clone())
+            .unwrap_or_default();
+
+        let documentation = unit
+            .and_then(|unit| unit.get("Documentation"))
+            .map(|entry| entry.value.clone());
+
+        let exec_reload = service.get("ExecReload").map(|entry| entry.value.clone());
+
+        let service_type = match service.get("Type") {
+            Some(entry) => entry
+                .value
+                .parse()
+                .map_err(|e: String| conf.invalid(service, entry, &e))?,
+            None => ServiceType::Simple,
         };
 
-        let _exec_reload = None;
-        if let Some(exec) = service.get("ExecReload") {
-            let _exec_reload = Some(exec.to_string());
+        // Variables are only known when the service starts, so here we only
+        // check that the command lines themselves are well formed.
+        conf.required(service, "ExecStart")?;
+        let exec_start = service.get_all("ExecStart");
+        let mut commands = 0;
+        for entry in &exec_start {
+            match parse_command_line(&entry.value, &HashMap::new()) {
+                Ok(parsed) => commands += parsed.len(),
+                Err(e) => return Err(conf.invalid(service, entry, &e)),
+            }
+        }
+        if commands == 0 {
+            return Err(conf.error(
+                Some(service.line),
+                Some(&service.name),
+                Some("ExecStart"),
+                "no command to start the service",
+            ));
+        }
+        if commands > 1 && service_type != ServiceType::Oneshot {
+            return Err(conf.invalid(
+                service,
+                exec_start[exec_start.len() - 1],
+                "more than one command is only allowed for Type=oneshot",
+            ));
         }
 
-        let atype = None;
-        if let Some(atp) = service.get("Type") {
-            let _atype = Some(atp.to_string());
+        let remain_after_exit = conf.get_bool(service, "RemainAfterExit", false)?;
+        let watchdog = conf
+            .get_timespan(service, "WatchdogSec", None)?
+            .filter(|timeout| *timeout > Duration::from_secs(0));
+        let timeout_start =
+            conf.get_timespan(service, "TimeoutStartSec", Some(DEFAULT_TIMEOUT_START))?;
+        let timeout_stop =
+            conf.get_timespan(service, "TimeoutStopSec", Some(DEFAULT_TIMEOUT_STOP))?;
+        for key in &["ExecStop", "ExecStopPost"] {
+            for entry in service.get_all(key) {
+                if let Err(e) = parse_command_line(&entry.value, &HashMap::new()) {
+                    return Err(conf.invalid(service, entry, &e));
+                }
+            }
         }
+        let exec_stop = service.get_all("ExecStop");
+        let exec_stop_post = service.get_all("ExecStopPost");
+        let kill_mode =
+            conf.get_parsed(service, "KillMode", KillModeEnum::ControlGroup)?;
+        let kill_signal = conf.get_signal(service, "KillSignal", Signal::SIGTERM)?;
+        let restart_kill_signal =
+            conf.get_signal(service, "RestartKillSignal", kill_signal)?;
+        let final_kill_signal =
+            conf.get_signal(service, "FinalKillSignal", Signal::SIGKILL)?;
+        let send_sighup = conf.get_bool(service, "SendSIGHUP", false)?;
+        let send_sigkill = conf.get_bool(service, "SendSIGKILL", true)?;
+        let dynamic_user = conf.get_bool(service, "DynamicUser", false)?;
+        let mut environment = vec![];
+        for entry in service.get_all("Environment") {
+            let assignments = environment::parse_assignments(&entry.value)
+                .map_err(|e| conf.invalid(service, entry, &e))?;
+            environment.extend(assignments);
+        }
+        // Lists of words, which can be spread over more than one line.
+        let words = |key: &str| -> Vec<String> {
+            service
+                .get_all(key)
+                .iter()
+                .flat_map(|entry| entry.value.split_whitespace().map(str::to_string))
+                .collect()
+        };
+        let pass_environment = words("PassEnvironment");
+        let unset_environment = words("UnsetEnvironment");
+        let supplementary_groups = words("SupplementaryGroups");
+        let mut exec_context = ExecContext::default();
+        let limits = execute::LIMITS.iter().map(|(key, _)| key);
+        for key in execute::DIRECTIVES.iter().chain(limits) {
+            if let Some(entry) = service.get(key) {
+                exec_context
+                    .set(key, &entry.value)
+                    .map_err(|e| conf.invalid(service, entry, &e))?;
+            }
+        }
+        let mut capabilities = Capabilities::default();
+        for key in &capabilities::DIRECTIVES {
+            for entry in service.get_all(key) {
+                capabilities
+                    .set(key, &entry.value)
+                    .map_err(|e| conf.invalid(service, entry, &e))?;
+            }
+        }
+        if let Some(entry) = service.get("AmbientCapabilities") {
+            capabilities.validate().map_err(|e| conf.invalid(service, entry, &e))?;
+        }
+        let mut sandbox = Sandbox::default();
+        for key in &namespace::DIRECTIVES {
+            for entry in service.get_all(key) {
+                sandbox
+                    .set(key, &entry.value)
+                    .map_err(|e| conf.invalid(service, entry, &e))?;
+            }
+        }
+        let mut resources = Resources::default();
+        for key in &resources::PROPERTIES {
+            if let Some(entry) = service.get(key) {
+                resources
+                    .set(key, &entry.value)
+                    .map_err(|e| conf.invalid(service, entry, &e))?;
+            }
+        }
+        let standard_output =
+            conf.get_parsed(service, "StandardOutput", Output::Journal)?;
+        let standard_error =
+            conf.get_parsed(service, "StandardError", Output::Inherit)?;
+
+        let restart_policy = conf.get_parsed(service, "Restart", RestartMethod::No)?;
+        if service_type == ServiceType::Oneshot
+            && matches!(restart_policy, RestartMethod::Always | RestartMethod::OnSuccess)
+        {
+            return Err(conf.invalid(
+                service,
+                service.get("Restart").unwrap(),
+                "always and on-success are not allowed for Type=oneshot",
+            ));
+        }
+        let restart_sec = conf
+            .get_timespan(service, "RestartSec", Some(DEFAULT_RESTART_SEC))?
+            .unwrap_or(DEFAULT_RESTART_SEC);
+        let restart_steps = conf.get_parsed(service, "RestartSteps", 0)?;
+        let restart_max_delay =
+            conf.get_timespan(service, "RestartMaxDelaySec", None)?;
+        // Rate limiting starts is up to the unit, not only its service.
+        let (start_limit_interval, start_limit_burst) = match unit {
+            Some(unit) => (
+                conf.get_timespan(
+                    unit,
+                    "StartLimitIntervalSec",
+                    Some(DEFAULT_START_LIMIT_INTERVAL),
+                )?,
+                conf.get_parsed(unit, "StartLimitBurst", DEFAULT_START_LIMIT_BURST)?,
+            ),
+            None => (Some(DEFAULT_START_LIMIT_INTERVAL), DEFAULT_START_LIMIT_BURST),
+        };
 
-        Unit {
-            path: inifile.to_str().unwrap().to_string(),
-            description: unit
-                .get("Description")
-                .expect("failed to get Description from Unit")
-                .to_string(),
-            documentation: _documentation,
+        let name = inifile
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| conf.path.clone());
+        Ok(Unit {
+            name: name.clone(),
+            path: conf.path.clone(),
+            description,
+            documentation,
             service: Arc::new(Mutex::new(Service {
-                service_type: atype,
-                exec_start: service
-                    .get("ExecStart")
-                    .expect("failed to get ExecStart from Service")
-                    .to_string(),
-                exec_reload: _exec_reload,
-                restart: None,
-                no_new_privs: None,
-                capability_bounding_set: None,
+                name,
+                service_type,
+                exec_start: exec_start.iter().map(|entry| entry.value.clone()).collect(),
+                exec_reload,
+                pid_file: service.get("PIDFile").map(|entry| entry.value.clone()),
+                remain_after_exit,
+                user: service.get("User").map(|entry| entry.value.clone()),
+                group: service.get("Group").map(|entry| entry.value.clone()),
+                supplementary_groups,
+                dynamic_user,
+                credentials: None,
+                exec_context,
+                capabilities,
+                sandbox,
+                environment,
+                environment_files: service
+                    .get_all("EnvironmentFile")
+                    .iter()
+                    .map(|entry| entry.value.clone())
+                    .collect(),
+                pass_environment,
+                unset_environment,
+                env: HashMap::new(),
+                main_pid: None,
+                cgroup: None,
+                resources,
+                pending: VecDeque::new(),
+                ignore_failure: false,
+                status_text: None,
+                status_errno: None,
+                failure_reason: None,
+                watchdog,
+                timeout_start,
+                timeout_stop,
+                exec_stop: exec_stop.iter().map(|entry| entry.value.clone()).collect(),
+                exec_stop_post: exec_stop_post
+                    .iter()
+                    .map(|entry| entry.value.clone())
+                    .collect(),
+                kill_mode,
+                kill_signal,
+                restart_kill_signal,
+                final_kill_signal,
+                send_sighup,
+                send_sigkill,
+                stop_job: false,
+                stop_post_pending: false,
+                stopping_pids: vec![],
+                standard_output,
+                standard_error,
+                last_watchdog: None,
+                extend_timeout: None,
+                restart_policy,
+                restart_sec,
+                restart_steps,
+                restart_max_delay,
+                start_limit_interval: start_limit_interval
+                    .filter(|interval| *interval > Duration::from_secs(0)),
+                start_limit_burst,
+                restarts: 0,
+                starts: VecDeque::new(),
                 current_state: CurrState::Stopped,
                 child: None,
                 exit_status: None,
-                restart_policy: RestartMethod::OnFailure,
             })),
             install: Install {
                 wanted_by: None,
-                alias: match install.get("Alias") {
-                    Some(value) => Some(value.to_string()),
-                    None => None,
-                },
+                alias: install
+                    .and_then(|install| install.get("Alias"))
+                    .map(|entry| entry.value.clone()),
             },
-            after: None,
-            before: None,
-            wants: None,
-        }
+            dependencies: unit.map(Dependencies::from_section).unwrap_or_default(),
+        })
     }
 }
 
+/// How long services get to start up if they don't set TimeoutStartSec=.
+const DEFAULT_TIMEOUT_START: Duration = Duration::from_secs(90);
+
+/// How long services get to stop if they don't set TimeoutStopSec=.
+const DEFAULT_TIMEOUT_STOP: Duration = Duration::from_secs(10);
+
+/// How long to wait before restarting services that don't set RestartSec=.
+const DEFAULT_RESTART_SEC: Duration = Duration::from_millis(100);
+
+/// Units may be started 5 times within 10 seconds, like in systemd.
+const DEFAULT_START_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_START_LIMIT_BURST: u32 = 5;
+
 /// Service file which includes information on how to start, stop, kill or
 /// reload a daemon service.
 #[derive(Debug, Serialize)]
 pub struct Service {
-    /// There are different types of Services, for now, all I know is that they
-    /// are different kinds of them.
-    pub service_type: Option<String>,
-    /// Command to start a daemon, can be a command with arguments, delimited
-    /// by empty whitespace.
-    pub exec_start: String,
+    /// Name of the unit the service belongs to.
+    #[serde(skip_serializing)]
+    name: String,
+    /// How the service starts up and when it is considered started.
+    pub service_type: ServiceType,
+    /// Commands to start a daemon, can be a command with arguments. They are
+    /// parsed following the systemd command line rules, see `cmdline`. Only
+    /// oneshot services can have more than one, which are run in order.
+    pub exec_start: Vec<String>,
     /// Command to reload the configuration for the daemon.
     pub exec_reload: Option<String>,
-    /// Command to restart the service.
-    pub restart: Option<RestartMethod>,
-    /// Limit the capabilities of the child spawned process.
-    pub capability_bounding_set: Option<String>,
-    /// Disable the daemon process from gaining any new privileges.
-    pub no_new_privs: Option<bool>,
-    /// What is the current state of this service.
-    pub current_state: CurrState,
+    /// File the daemon of a forking service writes its PID to.
+    pub pid_file: Option<String>,
+    /// Consider the service running even after its processes have exited.
+    pub remain_after_exit: bool,
+    /// User the processes run as, by name or UID.
+    pub user: Option<String>,
+    /// Group the processes run as, the one of the user by default.
+    pub group: Option<String>,
+    /// Groups the processes are in besides those of the user.
+    pub supplementary_groups: Vec<String>,
+    /// Run as a user that only exists while the service does.
+    pub dynamic_user: bool,
+    /// Who the processes run as, looked up when the service starts.
+    pub credentials: Option<Credentials>,
+    /// Working directory, limits and the like of the processes.
+    pub exec_context: ExecContext,
+    /// What the processes may do as far as capabilities go.
+    pub capabilities: Capabilities,
+    /// What the processes see of the file system.
+    pub sandbox: Sandbox,
+    /// Variables from Environment=, later ones win.
+    pub environment: Vec<(String, String)>,
+    /// Files to read variables from on every start, optional with `-`.
+    pub environment_files: Vec<String>,
+    /// Variables getupd passes on from its own environment.
+    pub pass_environment: Vec<String>,
+    /// Variables, or assignments, to leave out of the environment.
+    pub unset_environment: Vec<String>,
+    /// The environment built when the service last started.
+    #[serde(skip_serializing)]
+    env: HashMap<String, String>,
+    /// PID of the main process of the service, if it is known.
+    pub main_pid: Option<u32>,
+    /// The cgroup the processes of the service are in while it is around,
+    /// none if units don't get one.
+    pub cgroup: Option<PathBuf>,
+    /// Limits and weights enforced through the cgroup of the service.
+    pub resources: Resources,
+    /// Free-form status the service sent with STATUS=.
+    pub status_text: Option<String>,
+    /// Error number the service sent with ERRNO=.
+    pub status_errno: Option<i32>,
+    /// Why the service failed, the last time it did.
+    pub failure_reason: Option<String>,
+    /// The service is aborted if it doesn't send WATCHDOG=1 this often.
+    pub watchdog: Option<Duration>,
+    /// How long to wait for the service to start up before failing it, no
+    /// limit if None.
+    pub timeout_start: Option<Duration>,
+    /// How long to wait for the service to stop before killing it, no limit
+    /// if None.
+    pub timeout_stop: Option<Duration>,
+    /// Commands to stop the service with, before it is sent KillSignal=.
+    pub exec_stop: Vec<String>,
+    /// Commands to run once the service stopped, however it did.
+    pub exec_stop_post: Vec<String>,
+    /// Which processes are signaled to stop the service.
+    pub kill_mode: KillModeEnum,
+    /// Signal to stop the service with.
+    #[serde(serialize_with = "serialize_signal")]
+    pub kill_signal: Signal,
+    /// Signal to stop the service with when it is restarted.
+    #[serde(serialize_with = "serialize_signal")]
+    pub restart_kill_signal: Signal,
+    /// Signal for whatever is left once the stop timeout passed.
+    #[serde(serialize_with = "serialize_signal")]
+    pub final_kill_signal: Signal,
+    /// Follow KillSignal= with SIGHUP, for shells and the like.
+    pub send_sighup: bool,
+    /// Whether FinalKillSignal= is sent at all.
+    pub send_sigkill: bool,
+    /// Where stdout of the service goes.
+    pub standard_output: Output,
+    /// Where stderr of the service goes.
+    pub standard_error: Output,
+    /// When to start the service again after its main process exited.
+    pub restart_policy: RestartMethod,
+    /// How long to wait before starting the service again.
+    pub restart_sec: Duration,
+    /// How many restarts it takes for the delay to grow from RestartSec= to
+    /// RestartMaxDelaySec=, the delay stays the same if zero.
+    pub restart_steps: u32,
+    pub restart_max_delay: Option<Duration>,
+    /// The service may only be started `start_limit_burst` times within this
+    /// interval, no limit if None.
+    pub start_limit_interval: Option<Duration>,
+    pub start_limit_burst: u32,
+    /// Restarts since the service was last started other than by restarting
+    /// it, which is what the delay grows with.
+    pub restarts: u32,
+    /// What is the current state of this service, only ever changed through
+    /// `set_state` so that the change is published.
+    current_state: CurrState,
 
     /// The handle to the child process.
     #[serde(skip_serializing)]
     child: Option<Child>,
 
-    pub restart_policy: RestartMethod,
+    /// Commands of a oneshot service that are still left to run.
+    #[serde(skip_serializing)]
+    pending: VecDeque<ExecCommand>,
+
+    /// Whether the running command was prefixed with `-`.
+    #[serde(skip_serializing)]
+    ignore_failure: bool,
+
+    /// When the service last pinged its watchdog.
+    #[serde(skip_serializing)]
+    last_watchdog: Option<time::Instant>,
+
+    /// Until when the service asked for more time to start with
+    /// EXTEND_TIMEOUT_USEC=.
+    #[serde(skip_serializing)]
+    extend_timeout: Option<time::Instant>,
+
+    /// Whether a stop job is taking care of the service, which moves it on to
+    /// Stopped once everything is gone.
+    #[serde(skip_serializing)]
+    stop_job: bool,
+
+    /// Whether ExecStopPost= is still to be run after the service exited on
+    /// its own.
+    #[serde(skip_serializing)]
+    stop_post_pending: bool,
+
+    /// Processes a stop job waits to be gone.
+    #[serde(skip_serializing)]
+    stopping_pids: Vec<u32>,
+
+    /// When the service was started within the start limit interval.
+    #[serde(skip_serializing)]
+    starts: VecDeque<time::Instant>,
 
     #[serde(skip_serializing)]
     pub exit_status: Option<ExitStatus>,
@@ -196,81 +878,717 @@ impl Service {
         self.current_state
     }
 
-    pub fn child_id(&self) -> u32 {
-        self.child.as_ref().unwrap().id()
+    /// Move the service to state, letting everybody following along know.
+    pub fn set_state(&mut self, state: CurrState) {
+        if state == self.current_state {
+            return;
+        }
+        events::publish(EventKind::StateChanged {
+            unit: self.name.clone(),
+            from: self.current_state,
+            to: state,
+        });
+        self.current_state = state;
+    }
+
+    /// PID of the process we spawned and are waiting on, if any.
+    pub fn child_pid(&self) -> Option<u32> {
+        self.child.as_ref().map(|child| child.id())
     }
 
-    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
-        self.child.as_mut().unwrap().try_wait()
+    /// Whether the process is one we spawned or the main process of the
+    /// service.
+    pub fn owns(&self, pid: u32) -> bool {
+        self.child_pid() == Some(pid) || self.main_pid == Some(pid)
     }
 
     pub fn start(&mut self) {
-        let exec_args: Vec<&str> = self.exec_start.split_whitespace().collect();
-        let mut cmd = Command::new(exec_args[0]);
-        self.current_state = CurrState::Starting;
-
-        // We need to set the args if there are any.
-        if exec_args.len() > 1 {
-            cmd.args(&exec_args[1..]);
-        }
-        cmd.stdout(Stdio::piped());
-
-        self.child =
-            Some(cmd.spawn().expect(&format!(
-                "failed to spawn child process for {:?}",
-                exec_args[0]
-            )));
-        self.current_state = CurrState::Running;
-    }
-
-    /// Send SIGTERM to the process and if it does not exit after a timeout,
-    /// send a SIGKILL.
-    pub fn stop(&mut self) {
-        // Reference for this implementation:
-        // https://gist.github.com/spwitt/2f8f116fffeb0f3135df963d4bdf0637
-
-        self.current_state = CurrState::Stopping;
-        // Graceful exit timeout is 10 seconds by default./
-        // TODO: Make this configurable per-service, like systemd.
-        let wait_duration = time::Duration::new(10, 0);
-        let pid = Pid::from_raw(self.child.as_ref().unwrap().id() as i32);
-        match kill(pid, Signal::SIGINT) {
-            Ok(()) => {
-                let expire = time::Instant::now() + wait_duration;
-                while let Ok(None) = self.try_wait() {
-                    if time::Instant::now() > expire {
-                        break;
+        if self.start_limit_hit() {
+            println!("Start of {} repeated too quickly, refusing to start", self.name);
+            self.failure_reason = None;
+            self.fail("start-limit-hit");
+            return;
+        }
+        // Starting it by hand rather than restarting starts the backoff over.
+        if self.current_state != CurrState::Restarting {
+            self.restarts = 0;
+        }
+
+        // Environment files are read again every time.
+        let env = environment::resolve(
+            &self.pass_environment,
+            &self.environment,
+            &self.environment_files,
+        );
+        self.env = match env {
+            Ok(env) => env,
+            Err(e) => {
+                println!("Failed to start {}: {}", self.name, e);
+                self.fail("resources");
+                return;
+            }
+        };
+        let resolved = credentials::resolve(
+            &self.name,
+            self.user.as_deref(),
+            self.group.as_deref(),
+            &self.supplementary_groups,
+            self.dynamic_user,
+        );
+        self.credentials = match resolved {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                println!("Failed to start {}: {}", self.name, e);
+                self.fail("user");
+                return;
+            }
+        };
+        self.main_pid = None;
+        let env = self.exec_env();
+        let mut commands = VecDeque::new();
+        for line in &self.exec_start {
+            match parse_command_line(line, &env) {
+                Ok(parsed) => commands.extend(parsed),
+                Err(e) => {
+                    println!("Invalid ExecStart {:?}: {}", line, e);
+                    credentials::release(&self.name);
+                    self.fail("invalid-exec");
+                    return;
+                }
+            }
+        }
+        self.cgroup = match cgroup::create(&self.name) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                println!("Failed to create the cgroup of {}: {}", self.name, e);
+                credentials::release(&self.name);
+                self.fail("resources");
+                return;
+            }
+        };
+        if let Some(path) = &self.cgroup {
+            for e in self.resources.apply_all(path) {
+                println!("Failed to apply {} to {}", e, self.name);
+            }
+        }
+        if let Err(e) = self.sandbox.prepare(&self.name) {
+            println!("Failed to set up the sandbox of {}: {}", self.name, e);
+            credentials::release(&self.name);
+            self.fail("resources");
+            return;
+        }
+        self.pending = commands;
+        self.exit_status = None;
+        self.status_text = None;
+        self.status_errno = None;
+        self.failure_reason = None;
+        self.last_watchdog = Some(time::Instant::now());
+        self.extend_timeout = None;
+        self.set_state(CurrState::Starting);
+        if !self.spawn_next() {
+            credentials::release(&self.name);
+            self.sandbox.cleanup(&self.name);
+            return;
+        }
+
+        // Forking and oneshot services are started once the process exits and
+        // notify services once they tell us so, until then they stay in
+        // Starting. Spawning only returns once the binary has been executed,
+        // which is what exec needs.
+        match self.service_type {
+            ServiceType::Simple | ServiceType::Exec | ServiceType::Idle => {
+                self.set_state(CurrState::Running)
+            }
+            ServiceType::Forking | ServiceType::Oneshot | ServiceType::Notify => {}
+        }
+    }
+
+    /// Build the command to run one of the service's command lines, which
+    /// fails if its output can't be opened.
+    pub fn command(&self, exec: &ExecCommand) -> io::Result<Command> {
+        let mut cmd = Command::new(&exec.path);
+        cmd.arg0(&exec.argv[0]);
+        cmd.args(exec.args());
+        let stdout = Target::open(&self.standard_output)?;
+        let stderr = match self.standard_error {
+            Output::Inherit => stdout.try_clone()?,
+            ref output => Target::open(output)?,
+        };
+        cmd.stdout(stdout.stdio());
+        cmd.stderr(stderr.stdio());
+        cmd.env_clear();
+        cmd.envs(self.exec_env());
+        // Signals ignored by getupd stay ignored across exec, like SIGINT when
+        // it was started in the background by a shell, and so do the ones it
+        // blocks to read them from a signalfd, like SIGCHLD and as PID 1
+        // SIGTERM.
+        unsafe {
+            cmd.pre_exec(|| {
+                // The standard signals, real-time ones start at 32.
+                for signal in 1..32 {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigprocmask(libc::SIG_SETMASK, &set, std::ptr::null_mut());
+                Ok(())
+            });
+        }
+        // Everything the service runs goes in its cgroup, including
+        // ExecStop= and the like.
+        if let Some(path) = &self.cgroup {
+            cgroup::attach(&mut cmd, path);
+        }
+        // `+` runs the command with all of getupd's privileges, outside of
+        // the sandbox.
+        if exec.full_privileges {
+            return Ok(cmd);
+        }
+        // Paths of the sandbox are those of the host, not of RootDirectory=.
+        self.sandbox.apply(&mut cmd, &self.name)?;
+        let home = self.credentials.as_ref().and_then(|c| c.home.as_deref());
+        self.exec_context.apply(&mut cmd, home)?;
+        // `!` and `!!` run the command as getupd's user, `!!` without the
+        // ambient capabilities.
+        let credentials = self
+            .credentials
+            .as_ref()
+            .filter(|_| !exec.elevated && !exec.elevated_no_ambient);
+        let mut capabilities = self.capabilities.clone();
+        if exec.elevated_no_ambient {
+            capabilities.ambient_set = CapabilitySet::default();
+        }
+        // Switching users comes last, joining the cgroup takes root. Only
+        // the ambient capabilities make it past the switch.
+        let switching_user = credentials.is_some_and(|c| c.uid != 0);
+        capabilities.prepare(&mut cmd, switching_user);
+        if let Some(credentials) = credentials {
+            credentials::switch(&mut cmd, credentials);
+        }
+        capabilities.apply(&mut cmd);
+        Ok(cmd)
+    }
+
+    /// The environment the commands of the service run with, which `$VAR` in
+    /// them is expanded from too.
+    fn exec_env(&self) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        if self.service_type == ServiceType::Notify || self.watchdog.is_some() {
+            if let Some(path) = notify::socket_path() {
+                env.insert("NOTIFY_SOCKET".to_string(), path);
+            }
+        }
+        if let Some(watchdog) = self.watchdog {
+            env.insert("WATCHDOG_USEC".to_string(), watchdog.as_micros().to_string());
+        }
+        // Commands that run alongside the main process, like ExecReload=, get
+        // to know about it.
+        if let Some(pid) = self.main_pid {
+            env.insert("MAINPID".to_string(), pid.to_string());
+        }
+        if let Some(credentials) = &self.credentials {
+            let user = credentials.user.as_ref();
+            for (key, value) in [
+                ("USER", user),
+                ("LOGNAME", user),
+                ("HOME", credentials.home.as_ref()),
+                ("SHELL", credentials.shell.as_ref()),
+            ] {
+                if let Some(value) = value {
+                    env.insert(key.to_string(), value.clone());
+                }
+            }
+        }
+        environment::unset(&mut env, &self.unset_environment);
+        env
+    }
+
+    /// Have the log store read whatever the child writes to its pipes.
+    pub fn capture_output(&self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            logs::capture(&self.name, child.id(), LogStream::Stdout, stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            logs::capture(&self.name, child.id(), LogStream::Stderr, stderr);
+        }
+    }
+
+    /// Spawn the next pending command, returns false and marks the service
+    /// failed if it could not be spawned.
+    fn spawn_next(&mut self) -> bool {
+        let exec = match self.pending.pop_front() {
+            Some(exec) => exec,
+            None => return false,
+        };
+        match self.command(&exec).and_then(|mut cmd| cmd.spawn()) {
+            Ok(mut child) => {
+                self.capture_output(&mut child);
+                // The process we spawn for a forking service only starts the
+                // real daemon, whose PID we find out once it exits.
+                if self.service_type != ServiceType::Forking {
+                    self.main_pid = Some(child.id());
+                }
+                self.ignore_failure = exec.ignore_failure;
+                self.child = Some(child);
+                true
+            }
+            Err(e) => {
+                println!("Failed to spawn child process for {:?}: {}", exec.path, e);
+                self.fail("resources");
+                false
+            }
+        }
+    }
+
+    /// Move to the next state after the process we spawned has exited.
+    pub fn on_exit(&mut self, status: ExitStatus) {
+        self.child = None;
+        self.exit_status = Some(status);
+        let success = status.success() || self.ignore_failure;
+
+        if let CurrState::Stopping | CurrState::Stopped = self.current_state {
+            self.main_pid = None;
+            // The stop job moves it on once everything is gone.
+            if !self.stop_job {
+                self.set_state(CurrState::Stopped);
+            }
+            return;
+        }
+
+        let starting = self.current_state == CurrState::Starting;
+        let state = match self.service_type {
+            _ if !success => {
+                self.fail(if status.code().is_some() { "exit-code" } else { "signal" });
+                CurrState::Failed
+            }
+            ServiceType::Forking if starting => self.read_pid_file(),
+            ServiceType::Oneshot if starting && !self.pending.is_empty() => {
+                if self.spawn_next() {
+                    CurrState::Starting
+                } else {
+                    CurrState::Failed
+                }
+            }
+            // Exiting before being ready is a failure.
+            ServiceType::Notify if starting => {
+                self.fail("protocol");
+                CurrState::Failed
+            }
+            _ if self.remain_after_exit => CurrState::Running,
+            _ => CurrState::Stopped,
+        };
+        self.set_state(state);
+        // The main process is gone unless a forking daemon just took over or
+        // the next oneshot command was started.
+        match (self.current_state, self.service_type) {
+            (CurrState::Running, ServiceType::Forking) | (CurrState::Starting, _) => {}
+            _ => self.main_pid = None,
+        }
+        // Whoever follows exits takes it from here, see `core::handle_exits`.
+        if let CurrState::Stopped | CurrState::Failed = self.current_state {
+            self.stop_post_pending = !self.exec_stop_post.is_empty();
+            if self.should_restart(status) {
+                self.set_state(CurrState::Restarting);
+            } else if !self.stop_post_pending {
+                // Otherwise ExecStopPost= still runs as the user and in the
+                // sandbox, see `core::handle_exits`.
+                credentials::release(&self.name);
+                self.sandbox.cleanup(&self.name);
+            }
+        }
+    }
+
+    /// Whether the service should be started again after its main process
+    /// exited with status, going by Restart=.
+    fn should_restart(&self, status: ExitStatus) -> bool {
+        let watchdog = self.failure_reason.as_deref() == Some("watchdog");
+        let signal = status.signal().and_then(|signal| Signal::try_from(signal).ok());
+        let clean = status.success()
+            || self.ignore_failure
+            || signal.is_some_and(|signal| CLEAN_SIGNALS.contains(&signal));
+        let unclean_signal = status.signal().is_some() && !clean;
+        match self.restart_policy {
+            RestartMethod::No => false,
+            RestartMethod::Always => true,
+            RestartMethod::OnSuccess => clean && !watchdog,
+            RestartMethod::OnFailure => !clean || watchdog,
+            RestartMethod::OnAbnormal => unclean_signal || watchdog,
+            RestartMethod::OnAbort => unclean_signal,
+            RestartMethod::OnWatchdog => watchdog,
+        }
+    }
+
+    /// How long to wait before restarting the service this time. The delay
+    /// grows exponentially from RestartSec= to RestartMaxDelaySec= over
+    /// RestartSteps= restarts.
+    pub fn next_restart_delay(&mut self) -> Duration {
+        let restarts = self.restarts;
+        self.restarts += 1;
+        let max = match self.restart_max_delay {
+            Some(max) if self.restart_steps > 0 && max > self.restart_sec => max,
+            _ => return self.restart_sec,
+        };
+        if restarts >= self.restart_steps {
+            return max;
+        }
+        // Growing from nothing takes a starting point.
+        let base = self.restart_sec.as_secs_f64().max(0.001);
+        let step = f64::from(restarts) / f64::from(self.restart_steps);
+        Duration::from_secs_f64(base * (max.as_secs_f64() / base).powf(step))
+    }
+
+    /// Whether starting the service now would exceed StartLimitBurst= starts
+    /// within StartLimitIntervalSec=, if not the start is counted.
+    fn start_limit_hit(&mut self) -> bool {
+        let interval = match self.start_limit_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let now = time::Instant::now();
+        while self.starts.front().is_some_and(|start| now - *start > interval) {
+            self.starts.pop_front();
+        }
+        if self.starts.len() >= self.start_limit_burst as usize {
+            return true;
+        }
+        self.starts.push_back(now);
+        false
+    }
+
+    /// Mark the service failed, keeping the first reason if there are several.
+    pub fn fail(&mut self, reason: &str) {
+        self.set_state(CurrState::Failed);
+        if self.failure_reason.is_none() {
+            self.failure_reason = Some(reason.to_string());
+        }
+    }
+
+    /// Handle a message the service sent over the notification socket, see
+    /// sd_notify(3).
+    pub fn handle_notify(&mut self, message: &str) {
+        for line in message.lines() {
+            let (key, value) = match line.find('=') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => continue,
+            };
+            match (key, value) {
+                ("READY", "1") => {
+                    if let CurrState::Starting | CurrState::Reloading =
+                        self.current_state
+                    {
+                        self.set_state(CurrState::Running);
                     }
-                    thread::sleep(wait_duration / 10);
                 }
-                if let Ok(None) = self.try_wait() {
-                    self.kill()
+                ("RELOADING", "1") => self.set_state(CurrState::Reloading),
+                ("STOPPING", "1") => self.set_state(CurrState::Stopping),
+                ("STATUS", text) => self.status_text = Some(text.to_string()),
+                ("MAINPID", pid) => match pid.parse() {
+                    Ok(pid) => self.main_pid = Some(pid),
+                    Err(_) => println!("Ignoring invalid MAINPID={}", pid),
+                },
+                ("ERRNO", errno) => match errno.parse() {
+                    Ok(errno) => self.status_errno = Some(errno),
+                    Err(_) => println!("Ignoring invalid ERRNO={}", errno),
+                },
+                ("WATCHDOG", "1") => self.last_watchdog = Some(time::Instant::now()),
+                ("EXTEND_TIMEOUT_USEC", usec) => match usec.parse() {
+                    Ok(usec) => {
+                        self.extend_timeout =
+                            Some(time::Instant::now() + Duration::from_micros(usec))
+                    }
+                    Err(_) => println!("Ignoring invalid EXTEND_TIMEOUT_USEC={}", usec),
+                },
+                // Unknown assignments are ignored, like systemd does.
+                _ => {}
+            }
+        }
+    }
+
+    /// How much time a start that times out at expire has left, which the
+    /// service may have asked for with EXTEND_TIMEOUT_USEC=. None once the
+    /// time is up.
+    pub fn time_left(&self, expire: time::Instant) -> Option<Duration> {
+        let until = match self.extend_timeout {
+            Some(until) => until.max(expire),
+            None => expire,
+        };
+        until.checked_duration_since(time::Instant::now()).filter(|left| !left.is_zero())
+    }
+
+    /// Abort the service with SIGABRT if its watchdog timeout has passed
+    /// without a WATCHDOG=1 ping.
+    pub fn check_watchdog(&mut self) {
+        let (timeout, last) = match (self.watchdog, self.last_watchdog) {
+            (Some(timeout), Some(last)) => (timeout, last),
+            _ => return,
+        };
+        if self.current_state != CurrState::Running || last.elapsed() < timeout {
+            return;
+        }
+        self.failure_reason = Some("watchdog".to_string());
+        if let Some(pid) = self.main_pid {
+            println!("Watchdog timeout for {:?}, aborting PID {}", self.exec_start, pid);
+            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGABRT);
+        }
+        // Only fire once, the process exiting takes it from here.
+        self.last_watchdog = None;
+    }
+
+    /// Find the main PID of a forking service after the parent process has
+    /// exited.
+    fn read_pid_file(&mut self) -> CurrState {
+        let path = match &self.pid_file {
+            Some(path) => path,
+            // Without a PIDFile= we have no way to track the daemon.
+            None => return CurrState::Running,
+        };
+        match fs::read_to_string(path).ok().and_then(|pid| pid.trim().parse().ok()) {
+            Some(pid) => {
+                self.main_pid = Some(pid);
+                CurrState::Running
+            }
+            None => {
+                println!("Failed to read a PID from PIDFile {}", path);
+                self.fail("resources");
+                CurrState::Failed
+            }
+        }
+    }
+
+    /// Whether the main process is still around.
+    fn is_alive(&mut self) -> bool {
+        let main_pid = match self.main_pid {
+            Some(pid) => pid,
+            None => return false,
+        };
+        match self.child_pid() {
+            Some(pid) if pid == main_pid => {
+                match waitpid(Pid::from_raw(pid as i32), Some(WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::StillAlive) => true,
+                    Ok(status) => {
+                        if let Some((_, status)) = monitor::exit_status(status) {
+                            self.on_exit(status);
+                        }
+                        false
+                    }
+                    // The reaper got to it first, it hands us the status
+                    // once we let go of the service.
+                    Err(_) => false,
                 }
             }
-            Err(Sys(EINVAL)) => {
-                println!("Invalid signal. Killing process");
-                self.kill()
+            // Not our child, so we can only check if it exists.
+            _ => kill(Pid::from_raw(main_pid as i32), None).is_ok(),
+        }
+    }
+
+    /// Change resource control directives, like MemoryMax=, and apply them to
+    /// the cgroup right away if the service is running. Nothing changes if any
+    /// of them is invalid.
+    pub fn set_properties(
+        &mut self,
+        properties: &[(String, String)],
+    ) -> Result<(), String> {
+        let mut resources = self.resources.clone();
+        for (key, value) in properties {
+            resources
+                .set(key, value)
+                .map_err(|e| format!("{}={}: {}", key, value, e))?;
+        }
+        if let Some(path) = &self.cgroup {
+            for (key, value) in properties {
+                resources
+                    .apply(path, key)
+                    .map_err(|e| format!("{}={}: {}", key, value, e))?;
             }
-            Err(Sys(EPERM)) => {
-                println!("Insufficient permissions to signal process {}", pid);
+        }
+        self.resources = resources;
+        Ok(())
+    }
+
+    /// The cgroup of the service ran empty. If it was up, its processes are
+    /// gone even if none of them was our child, like the daemon of a forking
+    /// service. Returns the status it exited with if that's news, our own
+    /// children are taken care of by the reaper.
+    pub fn on_cgroup_empty(&mut self) -> Option<ExitStatus> {
+        if self.child.is_some() || self.stop_job {
+            return None;
+        }
+        let up = matches!(self.current_state, CurrState::Running | CurrState::Reloading);
+        // RemainAfterExit= services without a main process have nothing
+        // running to begin with.
+        let exited = up
+            && !(self.remain_after_exit && self.main_pid.is_none())
+            && !self.is_alive();
+        // We never get to know how it exited.
+        let status = Some(ExitStatus::from_raw(0)).filter(|_| exited);
+        if let Some(status) = status {
+            self.on_exit(status);
+        }
+        // Nothing is left to keep the cgroup of a service that is down around.
+        if let CurrState::Stopped | CurrState::Failed = self.current_state {
+            if self.cgroup.is_some() && !self.stop_post_pending {
+                cgroup::remove(&self.name);
+                self.cgroup = None;
             }
-            Err(Sys(ESRCH)) => {
-                println!("Process identified by {} does not exist", pid);
+        }
+        status
+    }
+
+    /// Take the service into Stopping for a stop job. Returns whether there
+    /// is anything to stop, otherwise the service is stopped already.
+    pub fn begin_stop(&mut self) -> bool {
+        if let CurrState::Stopped | CurrState::Failed | CurrState::Restarting =
+            self.current_state
+        {
+            self.set_state(CurrState::Stopped);
+            return false;
+        }
+        self.stop_job = true;
+        // The stop job runs ExecStopPost= itself.
+        self.stop_post_pending = false;
+        self.set_state(CurrState::Stopping);
+        true
+    }
+
+    /// Send KillSignal=, or RestartKillSignal= when restarting, to the
+    /// processes KillMode= says, followed by SIGHUP if SendSIGHUP= is set.
+    pub fn send_kill_signal(&mut self, restarting: bool) {
+        let signal =
+            if restarting { self.restart_kill_signal } else { self.kill_signal };
+        let main: Vec<u32> =
+            self.main_pid.or_else(|| self.child_pid()).into_iter().collect();
+        let (targets, waiting) = match self.kill_mode {
+            KillModeEnum::ControlGroup => (self.processes(), self.processes()),
+            KillModeEnum::Mixed => (main, self.processes()),
+            KillModeEnum::Process => (main.clone(), main),
+            // Whatever ExecStop= left running is left alone.
+            KillModeEnum::None => (vec![], vec![]),
+        };
+        signal_all(&targets, signal);
+        if self.send_sighup {
+            signal_all(&targets, Signal::SIGHUP);
+        }
+        self.stopping_pids = waiting;
+    }
+
+    /// Send FinalKillSignal= to whatever is left, unless SendSIGKILL= is off.
+    /// Returns whether it was sent.
+    pub fn send_final_signal(&mut self) -> bool {
+        if !self.send_sigkill || self.kill_mode == KillModeEnum::None {
+            return false;
+        }
+        let mut targets = self.stopping_pids.clone();
+        if self.kill_mode != KillModeEnum::Process {
+            // The whole cgroup goes at once, processes forking away included.
+            if self.cgroup.is_some() && self.final_kill_signal == Signal::SIGKILL {
+                match cgroup::kill(&self.name) {
+                    Ok(true) => println!("Killed the cgroup of {}", self.name),
+                    Ok(false) => {}
+                    Err(e) => {
+                        println!("Failed to kill the cgroup of {}: {}", self.name, e)
+                    }
+                }
+            }
+            targets.extend(self.processes());
+            targets.sort_unstable();
+            targets.dedup();
+        }
+        println!("Sending {} to {:?}", self.final_kill_signal, targets);
+        signal_all(&targets, self.final_kill_signal);
+        self.stopping_pids = targets;
+        true
+    }
+
+    /// Whether the processes the stop job is waiting for are gone.
+    pub fn is_stopped(&mut self) -> bool {
+        let main_alive = self.is_alive();
+        if main_alive || self.stopping_pids.iter().any(|pid| is_running(*pid)) {
+            return false;
+        }
+        // Only an empty cgroup means everything is really gone.
+        match self.kill_mode {
+            KillModeEnum::ControlGroup | KillModeEnum::Mixed
+                if self.cgroup.is_some() =>
+            {
+                !cgroup::is_populated(&self.name)
             }
-            Err(e) => println!("Unexpected error {}", e),
+            _ => true,
+        }
+    }
+
+    /// The stop job is done, whatever is left of the service is forgotten.
+    pub fn finish_stop(&mut self) {
+        self.stop_job = false;
+        self.stopping_pids.clear();
+        self.main_pid = None;
+        credentials::release(&self.name);
+        self.sandbox.cleanup(&self.name);
+        // Whatever KillMode= left behind keeps the cgroup around.
+        if self.cgroup.is_some() && !cgroup::is_populated(&self.name) {
+            cgroup::remove(&self.name);
+            self.cgroup = None;
         }
-        self.current_state = CurrState::Stopped;
+        self.set_state(CurrState::Stopped);
     }
 
-    pub fn kill(&mut self) {
-        println!("Trying to kill service started by: {:?}", self.exec_start);
-        self.child.as_mut().unwrap().kill().expect("Failed to kill chill process");
-        println!("Killed child service started by: {:?}", self.exec_start);
+    /// Whether ExecStopPost= is to be run now that the service exited on its
+    /// own, which only the first one asking gets to do.
+    pub fn take_stop_post(&mut self) -> bool {
+        std::mem::take(&mut self.stop_post_pending)
     }
 
-    pub fn reload() {}
+    /// The processes in the cgroup of the service, or without one, the main
+    /// process, the one we spawned and whatever they spawned that is still
+    /// around.
+    pub fn processes(&self) -> Vec<u32> {
+        if self.cgroup.is_some() {
+            return cgroup::procs(&self.name);
+        }
+        let roots: Vec<u32> =
+            self.main_pid.into_iter().chain(self.child_pid()).collect();
+        descendants(&roots)
+    }
+
+    /// Send a signal to the processes of the service.
+    pub fn signal(&self, signal: Signal) -> Result<(), String> {
+        let mut pids: Vec<u32> = self.main_pid.into_iter().collect();
+        pids.extend(self.child_pid().filter(|pid| Some(*pid) != self.main_pid));
+        if pids.is_empty() {
+            return Err("no process to signal".to_string());
+        }
+        for pid in pids {
+            kill(Pid::from_raw(pid as i32), signal)
+                .map_err(|e| format!("failed to signal PID {}: {}", pid, e))?;
+        }
+        Ok(())
+    }
+
+    /// The ExecReload= commands, with $MAINPID available to them.
+    pub fn reload_commands(&self) -> Result<Vec<ExecCommand>, String> {
+        match &self.exec_reload {
+            Some(line) => self.commands(std::slice::from_ref(line)),
+            None => Err("the unit has no ExecReload=".to_string()),
+        }
+    }
+
+    /// The ExecStop= commands, with $MAINPID available to them.
+    pub fn stop_commands(&self) -> Result<Vec<ExecCommand>, String> {
+        self.commands(&self.exec_stop)
+    }
+
+    /// The ExecStopPost= commands.
+    pub fn stop_post_commands(&self) -> Result<Vec<ExecCommand>, String> {
+        self.commands(&self.exec_stop_post)
+    }
 
-    pub fn restart() {}
+    fn commands(&self, lines: &[String]) -> Result<Vec<ExecCommand>, String> {
+        let env = self.exec_env();
+        let mut commands = vec![];
+        for line in lines {
+            commands.extend(parse_command_line(line, &env)?);
+        }
+        Ok(commands)
+    }
 }
 
 // A global instance of AllUnits to store the loaded values at runtime.
@@ -280,5 +1598,466 @@ lazy_static! {
     // pub static ref BASE_PATH: &str = "".to_string();
 }
 
-// Reload the server looking for any new services.
-pub fn reload_server() {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Load a unit with the given [Service] section, under a name of its own.
+    fn load(service: &str) -> Unit {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "getup-test-{}-{}.service",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, format!("[Service]\n{}\n", service)).unwrap();
+        let unit = Unit::from_unitfile(&path);
+        fs::remove_file(&path).unwrap();
+        unit.unwrap()
+    }
+
+    /// Wait for the process the service spawned and hand it the status.
+    fn exit(service: &mut Service) -> ExitStatus {
+        let status = service.child.as_mut().unwrap().wait().unwrap();
+        service.on_exit(status);
+        status
+    }
+
+    #[test]
+    fn test_simple() {
+        let unit = load("ExecStart=/bin/sh -c 'exit 0'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert_eq!(service.status(), CurrState::Running);
+        assert_eq!(service.main_pid, service.child_pid());
+        assert!(exit(&mut service).success());
+        assert_eq!(service.status(), CurrState::Stopped);
+        assert_eq!(service.main_pid, None);
+        assert_eq!(service.failure_reason, None);
+
+        let unit = load("ExecStart=/bin/sh -c 'exit 3'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+        assert_eq!(service.failure_reason.as_deref(), Some("exit-code"));
+
+        let unit = load("ExecStart=/bin/sh -c 'kill -KILL $$$$'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+        assert_eq!(service.failure_reason.as_deref(), Some("signal"));
+
+        // A failure the unit file says to ignore isn't one.
+        let unit = load("ExecStart=-/bin/sh -c 'exit 3'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Stopped);
+    }
+
+    #[test]
+    fn test_exec() {
+        let unit = load("Type=exec\nExecStart=/bin/sh -c 'exit 0'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert_eq!(service.status(), CurrState::Running);
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Stopped);
+
+        // The binary not being there fails the start right away.
+        let unit = load("Type=exec\nExecStart=/nonexistent/getup-test");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert_eq!(service.status(), CurrState::Failed);
+        assert_eq!(service.failure_reason.as_deref(), Some("resources"));
+        assert!(service.child.is_none());
+    }
+
+    #[test]
+    fn test_forking() {
+        let pid_file = std::env::temp_dir()
+            .join(format!("getup-test-{}-forking.pid", std::process::id()));
+        let unit = load(&format!(
+            "Type=forking\nPIDFile={0}\nExecStart=/bin/sh -c 'sleep 30 & echo $! > {0}'",
+            pid_file.display()
+        ));
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        // The parent exiting is what makes it started.
+        assert_eq!(service.status(), CurrState::Starting);
+        assert_eq!(service.main_pid, None);
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Running);
+        let daemon: u32 = fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        assert_eq!(service.main_pid, Some(daemon));
+        kill(Pid::from_raw(daemon as i32), Signal::SIGKILL).unwrap();
+        fs::remove_file(&pid_file).unwrap();
+
+        // A PIDFile= that never shows up fails it.
+        let unit = load(&format!(
+            "Type=forking\nPIDFile={}\nExecStart=/bin/sh -c 'exit 0'",
+            pid_file.display()
+        ));
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+        assert_eq!(service.failure_reason.as_deref(), Some("resources"));
+        assert_eq!(service.main_pid, None);
+
+        // Without one there is nothing to track but it is up.
+        let unit = load("Type=forking\nExecStart=/bin/sh -c 'exit 0'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Running);
+    }
+
+    #[test]
+    fn test_oneshot() {
+        let unit = load(
+            "Type=oneshot\nExecStart=/bin/sh -c 'exit 0'\nExecStart=/bin/sh -c 'exit 0'",
+        );
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert_eq!(service.status(), CurrState::Starting);
+        let first = service.child_pid();
+        exit(&mut service);
+        // On to the next command.
+        assert_eq!(service.status(), CurrState::Starting);
+        assert!(service.child_pid().is_some());
+        assert_ne!(service.child_pid(), first);
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Stopped);
+
+        let unit =
+            load("Type=oneshot\nRemainAfterExit=yes\nExecStart=/bin/sh -c 'exit 0'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Running);
+        assert_eq!(service.main_pid, None);
+
+        // A failing command stops the rest from running.
+        let unit = load(
+            "Type=oneshot\nRemainAfterExit=yes\nExecStart=/bin/sh -c 'exit 1'\n\
+             ExecStart=/bin/sh -c 'exit 0'",
+        );
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+        assert!(service.child.is_none());
+    }
+
+    #[test]
+    fn test_notify() {
+        let unit = load("Type=notify\nExecStart=/bin/sleep 30");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert_eq!(service.status(), CurrState::Starting);
+        service.handle_notify("READY=1");
+        assert_eq!(service.status(), CurrState::Running);
+        service.signal(Signal::SIGKILL).unwrap();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+
+        // Exiting before being ready is a failure, even a clean exit.
+        let unit = load("Type=notify\nExecStart=/bin/sh -c 'exit 0'");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        exit(&mut service);
+        assert_eq!(service.status(), CurrState::Failed);
+        assert_eq!(service.failure_reason.as_deref(), Some("protocol"));
+    }
+
+    #[test]
+    fn test_handle_notify() {
+        let unit = load("Type=notify\nExecStart=/bin/sleep 30");
+        let mut service = unit.service.lock().unwrap();
+        service.set_state(CurrState::Starting);
+        // Several assignments to a message, the ones we don't know are skipped.
+        service.handle_notify("STATUS=Loading\nX_CUSTOM=1\nnonsense\nMAINPID=4242");
+        assert_eq!(service.status(), CurrState::Starting);
+        assert_eq!(service.status_text.as_deref(), Some("Loading"));
+        assert_eq!(service.main_pid, Some(4242));
+        service.handle_notify("MAINPID=nope\nERRNO=nope");
+        assert_eq!(service.main_pid, Some(4242));
+        assert_eq!(service.status_errno, None);
+
+        let expire = time::Instant::now();
+        assert_eq!(service.time_left(expire), None);
+        service.handle_notify("EXTEND_TIMEOUT_USEC=60000000");
+        assert!(service.time_left(expire).unwrap() > Duration::from_secs(59));
+        service.handle_notify("EXTEND_TIMEOUT_USEC=0");
+        assert_eq!(service.time_left(expire), None);
+
+        service.handle_notify("READY=1\nSTATUS=Serving");
+        assert_eq!(service.status(), CurrState::Running);
+        assert_eq!(service.status_text.as_deref(), Some("Serving"));
+        // Only a starting or reloading service gets ready.
+        service.handle_notify("RELOADING=1");
+        assert_eq!(service.status(), CurrState::Reloading);
+        service.handle_notify("READY=1");
+        assert_eq!(service.status(), CurrState::Running);
+
+        service.handle_notify("ERRNO=2\nSTOPPING=1");
+        assert_eq!(service.status_errno, Some(2));
+        assert_eq!(service.status(), CurrState::Stopping);
+        service.handle_notify("READY=1");
+        assert_eq!(service.status(), CurrState::Stopping);
+    }
+
+    #[test]
+    fn test_signals_unblocked() {
+        // Like getupd does to read them from a signalfd.
+        let blocked = [libc::SIGTERM, libc::SIGCHLD];
+        crate::signals::block(&blocked).unwrap();
+        let unit = load("ExecStart=/bin/sleep 30");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in &blocked {
+                libc::sigaddset(&mut set, *signal);
+            }
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+        }
+        service.signal(Signal::SIGTERM).unwrap();
+        assert_eq!(exit(&mut service).signal(), Some(libc::SIGTERM));
+    }
+
+    /// Exit statuses of the main process, by name.
+    fn status(name: &str) -> ExitStatus {
+        match name {
+            "exit 0" => ExitStatus::from_raw(0),
+            "exit 1" => ExitStatus::from_raw(1 << 8),
+            "SIGTERM" => ExitStatus::from_raw(Signal::SIGTERM as i32),
+            "SIGPIPE" => ExitStatus::from_raw(Signal::SIGPIPE as i32),
+            "SIGKILL" => ExitStatus::from_raw(Signal::SIGKILL as i32),
+            _ => ExitStatus::from_raw(Signal::SIGABRT as i32),
+        }
+    }
+
+    #[test]
+    fn test_should_restart() {
+        // Whether to restart after a clean exit, an exit with a failure code,
+        // a clean signal, an unclean signal and a watchdog timeout.
+        let cases = [
+            ("no", [false, false, false, false, false]),
+            ("always", [true, true, true, true, true]),
+            ("on-success", [true, false, true, false, false]),
+            ("on-failure", [false, true, false, true, true]),
+            ("on-abnormal", [false, false, false, true, true]),
+            ("on-abort", [false, false, false, true, true]),
+            ("on-watchdog", [false, false, false, false, true]),
+        ];
+        let exits = ["exit 0", "exit 1", "SIGTERM", "SIGKILL", "watchdog"];
+        for (policy, expected) in cases.iter() {
+            let unit = load(&format!("ExecStart=/bin/true\nRestart={}", policy));
+            let mut service = unit.service.lock().unwrap();
+            for (exit, restart) in exits.iter().zip(expected.iter()) {
+                service.failure_reason =
+                    if *exit == "watchdog" { Some("watchdog".into()) } else { None };
+                assert_eq!(
+                    service.should_restart(status(exit)),
+                    *restart,
+                    "Restart={} after {}",
+                    policy,
+                    exit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_should_restart_clean() {
+        // What else counts as a clean exit.
+        let cases = [
+            ("ExecStart=/bin/true", "SIGPIPE", true),
+            ("ExecStart=/bin/true", "SIGABRT", false),
+            ("ExecStart=-/bin/true", "exit 1", true),
+            ("ExecStart=-/bin/true", "SIGKILL", true),
+        ];
+        for (exec, exit, clean) in cases.iter() {
+            for (policy, restart) in
+                [("on-success", *clean), ("on-failure", !*clean)].iter()
+            {
+                let unit = load(&format!("{}\nRestart={}", exec, policy));
+                let mut service = unit.service.lock().unwrap();
+                service.ignore_failure = exec.starts_with("ExecStart=-");
+                assert_eq!(
+                    service.should_restart(status(exit)),
+                    *restart,
+                    "{} with Restart={} after {}",
+                    exec,
+                    policy,
+                    exit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_next_restart_delay() {
+        let cases: [(&str, &[f64]); 6] = [
+            ("", &[0.1, 0.1, 0.1]),
+            ("RestartSec=2", &[2.0, 2.0, 2.0]),
+            // Without steps the maximum doesn't come into it.
+            ("RestartSec=1\nRestartMaxDelaySec=16", &[1.0, 1.0]),
+            (
+                "RestartSec=1\nRestartMaxDelaySec=16\nRestartSteps=4",
+                &[1.0, 2.0, 4.0, 8.0, 16.0, 16.0],
+            ),
+            (
+                "RestartSec=0\nRestartMaxDelaySec=1\nRestartSteps=3",
+                &[0.001, 0.01, 0.1, 1.0],
+            ),
+            // A maximum below RestartSec= is ignored.
+            ("RestartSec=5\nRestartMaxDelaySec=1\nRestartSteps=2", &[5.0, 5.0, 5.0]),
+        ];
+        for (config, expected) in cases.iter() {
+            let unit = load(&format!("ExecStart=/bin/true\n{}", config));
+            let mut service = unit.service.lock().unwrap();
+            let delays: Vec<f64> = expected
+                .iter()
+                .map(|_| service.next_restart_delay().as_secs_f64())
+                .collect();
+            for (delay, expected) in delays.iter().zip(expected.iter()) {
+                assert!((delay - expected).abs() < 1e-6, "{:?}: {:?}", config, delays);
+            }
+            assert_eq!(service.restarts as usize, expected.len());
+        }
+    }
+
+    #[test]
+    fn test_start_limit_hit() {
+        // Whether each of the starts hits the limit.
+        let cases: [(&str, &[bool]); 4] = [
+            ("", &[false, false, false, false, false, true]),
+            ("StartLimitBurst=2", &[false, false, true, true]),
+            ("StartLimitIntervalSec=0\nStartLimitBurst=1", &[false, false, false]),
+            (
+                "StartLimitIntervalSec=infinity\nStartLimitBurst=1",
+                &[false, false, false],
+            ),
+        ];
+        for (config, expected) in cases.iter() {
+            let unit = load(&format!("ExecStart=/bin/true\n[Unit]\n{}", config));
+            let mut service = unit.service.lock().unwrap();
+            let hits: Vec<bool> =
+                expected.iter().map(|_| service.start_limit_hit()).collect();
+            assert_eq!(&hits, expected, "{:?}", config);
+        }
+
+        // Starts that were long enough ago no longer count.
+        let unit = load("ExecStart=/bin/true\n[Unit]\nStartLimitIntervalSec=200ms\nStartLimitBurst=2");
+        let mut service = unit.service.lock().unwrap();
+        assert!(!service.start_limit_hit());
+        assert!(!service.start_limit_hit());
+        assert!(service.start_limit_hit());
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(!service.start_limit_hit());
+        assert!(!service.start_limit_hit());
+        assert!(service.start_limit_hit());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_dynamic_user_left_behind() {
+        let unit = load("ExecStart=/bin/sh -c 'sleep 30 &'\nDynamicUser=yes");
+        let mut service = unit.service.lock().unwrap();
+        service.start();
+        assert!(exit(&mut service).success());
+        assert_eq!(service.status(), CurrState::Stopped);
+        let uid = service.credentials.as_ref().unwrap().uid;
+        // The sleep the shell left behind.
+        let left: Vec<u32> = fs::read_dir("/proc")
+            .unwrap()
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .filter(|pid: &u32| {
+                let status = fs::read_to_string(format!("/proc/{}/status", pid));
+                status.is_ok_and(|status| {
+                    status
+                        .lines()
+                        .any(|line| line.starts_with(&format!("Uid:\t{}\t", uid)))
+                })
+            })
+            .collect();
+        assert_eq!(left.len(), 1);
+
+        // The sleep still runs as the UID, so it isn't handed out again.
+        let other = "left-behind-other.service";
+        let credentials = credentials::resolve(other, None, None, &[], true);
+        assert_ne!(credentials.unwrap().unwrap().uid, uid);
+        credentials::release(other);
+        signal_all(&left, Signal::SIGKILL);
+    }
+
+    /// Run the command line with the prefixes as one of the service's
+    /// commands, returning what it printed.
+    fn run_prefixed(service: &Service, line: &str) -> String {
+        let exec = parse_command_line(line, &HashMap::new()).unwrap().remove(0);
+        let mut cmd = service.command(&exec).unwrap();
+        let output = cmd.stdout(Stdio::piped()).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn test_exec_prefixes() {
+        let unit = load("ExecStart=/bin/true\nNoNewPrivileges=yes\nUMask=0077");
+        let service = unit.service.lock().unwrap();
+        let script = "/bin/sh -c 'umask; grep NoNewPrivs /proc/self/status | cut -f2'";
+        // Whether UMask= and NoNewPrivileges= apply.
+        let cases = [("", true), ("+", false), ("!", true), ("!!", true), ("-+", false)];
+        for (prefix, restricted) in cases.iter() {
+            let output = run_prefixed(&service, &format!("{}{}", prefix, script));
+            let lines: Vec<&str> = output.lines().collect();
+            assert_eq!(lines[0] == "0077", *restricted, "{:?}: {:?}", prefix, lines);
+            assert_eq!(lines[1] == "1", *restricted, "{:?}: {:?}", prefix, lines);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_exec_prefixes_privileged() {
+        let unit = load(
+            "ExecStart=/bin/true\nUser=nobody\nPrivateTmp=yes\n\
+             AmbientCapabilities=CAP_NET_BIND_SERVICE",
+        );
+        let mut service = unit.service.lock().unwrap();
+        service.credentials = credentials::resolve(
+            &service.name,
+            service.user.as_deref(),
+            None,
+            &[],
+            false,
+        )
+        .unwrap();
+        service.sandbox.prepare(&service.name).unwrap();
+        let marker = format!("/tmp/getup-prefix-{}", std::process::id());
+        fs::write(&marker, "").unwrap();
+        // The UID, whether the host's /tmp is there and the ambient set.
+        let script = format!(
+            "/bin/sh -c 'id -u; test -e {} && echo host; grep CapAmb /proc/self/status | cut -f2'",
+            marker
+        );
+        let cases = [
+            ("", "65534\n0000000000000400"),
+            ("+", "0\nhost\n0000000000000000"),
+            ("!", "0\n0000000000000400"),
+            ("!!", "0\n0000000000000000"),
+        ];
+        for (prefix, expected) in cases.iter() {
+            let output = run_prefixed(&service, &format!("{}{}", prefix, script));
+            assert_eq!(&output, expected, "{:?}", prefix);
+        }
+        service.sandbox.cleanup(&service.name);
+        fs::remove_file(marker).unwrap();
+    }
+}